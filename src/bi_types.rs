@@ -1,10 +1,12 @@
 #![allow(dead_code)]
 
 use crate::expr::{
-    Case, DataConstructor, Declaration, Dtor, Expr, Literal, ParserExpr, TypeDeclaration,
+    Case, DataConstructor, Declaration, Dtor, Expr, Literal, ParserExpr, Pattern, TypeDeclaration,
     TypedExpr, ValueDeclaration, Var,
 };
 use crate::pretty::render_doc;
+use crate::primitives::{self, PRIMITIVES};
+use crate::span::Span;
 use pretty::{BoxDoc, Doc};
 use std::collections::{HashMap, HashSet};
 use std::fmt;
@@ -602,6 +604,8 @@ pub enum TypeError {
     OccursCheck(String, Type),
     Unification(Type, Type),
     CantInferMatch,
+    UnsupportedPattern(Pattern<String>),
+    Located(Span, Box<TypeError>),
 }
 
 impl fmt::Display for TypeError {
@@ -637,6 +641,27 @@ impl TypeError {
             TypeError::CantInferMatch => {
                 "Can't infer type for a match, please provide an annotation".to_string()
             }
+            TypeError::UnsupportedPattern(pattern) => format!(
+                "Only flat constructor patterns are supported here: {}",
+                render_doc(pattern.to_doc())
+            ),
+            TypeError::Located(_, err) => err.print(),
+        }
+    }
+
+    /// Attaches a source location to an error, unless it already has a more
+    /// precise one.
+    pub fn at(self, span: Span) -> TypeError {
+        match self {
+            TypeError::Located(..) => self,
+            err => TypeError::Located(span, Box::new(err)),
+        }
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            TypeError::Located(span, _) => Some(*span),
+            _ => None,
         }
     }
 }
@@ -1006,7 +1031,21 @@ impl TypeChecker {
         ty_match: &Type,
         ty_body: &Type,
     ) -> Result<(Context, Case<Var>), TypeError> {
-        let (data_constructor, ty_args) = self.find_data_constructor(&case.data_constructor)?;
+        // This checker only supports a single constructor with variable binders
+        let (dtor, field_binders) = match &case.pattern {
+            Pattern::Constructor { dtor, fields, .. } => {
+                let mut field_binders = vec![];
+                for field in fields {
+                    match field {
+                        Pattern::Var(binder, span) => field_binders.push((binder.clone(), *span)),
+                        _ => return Err(TypeError::UnsupportedPattern(case.pattern.clone())),
+                    }
+                }
+                (dtor, field_binders)
+            }
+            _ => return Err(TypeError::UnsupportedPattern(case.pattern.clone())),
+        };
+        let (data_constructor, ty_args) = self.find_data_constructor(dtor)?;
 
         let mut ctx = ctx;
         let fresh_vars: Vec<(String, Type)> = ty_args
@@ -1020,15 +1059,14 @@ impl TypeChecker {
 
         // Make sure the type of the case constructor matches the type of the matched expression
         let ty_dtor: Type = Type::Constructor {
-            name: case.data_constructor.ty.clone(),
+            name: dtor.ty.clone(),
             arguments: fresh_vars.iter().map(|(_, ty)| ty.clone()).collect(),
         };
         let ctx = self.unify(ctx, &ty_dtor, ty_match)?;
 
-        let binders: Vec<(String, Type)> = case
-            .binders
-            .clone()
-            .into_iter()
+        let binders: Vec<(String, Type)> = field_binders
+            .iter()
+            .map(|(binder, _)| binder.clone())
             .zip(data_constructor.fields)
             .map(|(binder, ty)| (binder, ctx.apply_(ty.subst_many(&fresh_vars))))
             .collect();
@@ -1040,9 +1078,17 @@ impl TypeChecker {
         Ok((
             ctx,
             Case {
-                data_constructor: case.data_constructor.clone(),
-                binders: typed_binders,
+                pattern: Pattern::Constructor {
+                    dtor: dtor.clone(),
+                    fields: typed_binders
+                        .into_iter()
+                        .zip(field_binders)
+                        .map(|(binder, (_, span))| Pattern::Var(binder, span))
+                        .collect(),
+                    span: case.pattern.span(),
+                },
                 expr: applied_body,
+                span: case.span,
             },
         ))
     }
@@ -1070,10 +1116,10 @@ impl TypeChecker {
             .map(|(name, ty)| (name, ty, self.name_gen.fresh_var()))
             .collect();
         // Insert fresh names
-        let expr = expr.subst_many(
+        let expr = expr.clone().subst_var_many_(
             binder_mapping
                 .iter()
-                .map(|(b, _, fresh)| (b.clone(), Expr::Var(fresh.clone())))
+                .map(|(b, _, fresh)| (b.clone(), fresh.as_str()))
                 .collect(),
         );
         let mut binder_iter = binder_mapping.iter();
@@ -1114,18 +1160,10 @@ impl TypeChecker {
         };
 
         // Reversing the renaming
-        let typed_expr = typed_expr.subst_many(
+        let typed_expr = typed_expr.subst_var_many_(
             binder_mapping
-                .into_iter()
-                .map(|(binder, ty, fresh_binder)| {
-                    (
-                        fresh_binder,
-                        Expr::Var(Var {
-                            name: binder,
-                            ty: ty.clone(),
-                        }),
-                    )
-                })
+                .iter()
+                .map(|(binder, _, fresh_binder)| (fresh_binder.clone(), binder.as_str()))
                 .collect(),
         );
 
@@ -1137,14 +1175,26 @@ impl TypeChecker {
         ctx: Context,
         expr: &ParserExpr,
         ty: &Type,
+    ) -> Result<(Context, TypedExpr), TypeError> {
+        self.check_inner(ctx, expr, ty)
+            .map_err(|err| err.at(expr.span()))
+    }
+
+    fn check_inner(
+        &mut self,
+        ctx: Context,
+        expr: &ParserExpr,
+        ty: &Type,
     ) -> Result<(Context, TypedExpr), TypeError> {
         debug!("[checking] {} |- {} : {}", ctx, expr, ty);
         match (expr, ty) {
-            (Expr::Literal(Literal::Int(i)), ty) if ty == &Type::int() => Ok((ctx, Expr::int(*i))),
-            (Expr::Literal(Literal::Bool(b)), ty) if ty == &Type::bool() => {
-                Ok((ctx, Expr::bool(*b)))
+            (Expr::Literal(Literal::Int(i), span), ty) if ty == &Type::int() => {
+                Ok((ctx, Expr::int(*i, *span)))
+            }
+            (Expr::Literal(Literal::Bool(b), span), ty) if ty == &Type::bool() => {
+                Ok((ctx, Expr::bool(*b, *span)))
             }
-            (Expr::Lambda { binder, body }, Type::Fun { arg, result }) => {
+            (Expr::Lambda { binder, body, span }, Type::Fun { arg, result }) => {
                 let (res_ctx, typed_body, typed_binder) =
                     self.check_renamed(ctx, (binder.clone(), *arg.clone()), body, result)?;
                 Ok((
@@ -1152,16 +1202,25 @@ impl TypeChecker {
                     Expr::Lambda {
                         binder: typed_binder,
                         body: Box::new(typed_body),
+                        span: *span,
                     },
                 ))
             }
-            (Expr::Tuple(fst, snd), Type::Tuple(ty_fst, ty_snd)) => {
+            (Expr::Tuple(fst, snd, span), Type::Tuple(ty_fst, ty_snd)) => {
                 let (ctx, typed_fst) = self.check(ctx, fst, ty_fst)?;
                 let (ctx, typed_snd) = self.check(ctx, snd, ty_snd)?;
                 let typed_fst = ctx.apply_expr(typed_fst);
-                Ok((ctx, Expr::tuple(typed_fst, typed_snd)))
+                Ok((ctx, Expr::tuple(typed_fst, typed_snd, *span)))
             }
-            (Expr::Let { binder, expr, body }, ty) => {
+            (
+                Expr::Let {
+                    binder,
+                    expr,
+                    body,
+                    span,
+                },
+                ty,
+            ) => {
                 let (ctx, ty_binder, typed_expr) = self.infer(ctx, expr)?;
                 let (ctx, typed_body, typed_binder) =
                     self.check_renamed(ctx, (binder.clone(), ty_binder.clone()), body, ty)?;
@@ -1171,15 +1230,18 @@ impl TypeChecker {
                         binder: typed_binder,
                         expr: Box::new(typed_expr),
                         body: Box::new(typed_body),
+                        span: *span,
                     },
                 ))
             }
-            (Expr::Match { expr, cases }, ty) => {
+            (Expr::Match { expr, cases, span }, ty) => {
                 let (mut ctx, ty_match, typed_expr) = self.infer(ctx, expr)?;
                 let mut typed_cases = vec![];
                 for case in cases.iter() {
                     let ty_expr = &ctx.apply(&ty_match);
-                    let (new_ctx, typed_case) = self.check_case(ctx, case, &ty_expr, ty)?;
+                    let (new_ctx, typed_case) = self
+                        .check_case(ctx, case, &ty_expr, ty)
+                        .map_err(|err| err.at(case.span))?;
                     ctx = new_ctx;
                     typed_cases.push(typed_case);
                 }
@@ -1188,6 +1250,32 @@ impl TypeChecker {
                     Expr::Match {
                         expr: Box::new(typed_expr),
                         cases: typed_cases,
+                        span: *span,
+                    },
+                ))
+            }
+            (
+                Expr::If {
+                    condition,
+                    then_branch,
+                    else_branch,
+                    span,
+                },
+                ty,
+            ) => {
+                let (ctx, typed_condition) = self.check(ctx, condition, &Type::bool())?;
+                let (ctx, typed_then) = self.check(ctx, then_branch, ty)?;
+                let ty = ctx.apply(ty);
+                let (ctx, typed_else) = self.check(ctx, else_branch, &ty)?;
+                let typed_condition = ctx.apply_expr(typed_condition);
+                let typed_then = ctx.apply_expr(typed_then);
+                Ok((
+                    ctx,
+                    Expr::If {
+                        condition: Box::new(typed_condition),
+                        then_branch: Box::new(typed_then),
+                        else_branch: Box::new(typed_else),
+                        span: *span,
                     },
                 ))
             }
@@ -1219,11 +1307,20 @@ impl TypeChecker {
         &mut self,
         ctx: Context,
         expr: &ParserExpr,
+    ) -> Result<(Context, Type, TypedExpr), TypeError> {
+        self.infer_inner(ctx, expr)
+            .map_err(|err| err.at(expr.span()))
+    }
+
+    fn infer_inner(
+        &mut self,
+        ctx: Context,
+        expr: &ParserExpr,
     ) -> Result<(Context, Type, TypedExpr), TypeError> {
         match expr {
-            Expr::Literal(Literal::Int(i)) => Ok((ctx, Type::int(), Expr::int(*i))),
-            Expr::Literal(Literal::Bool(b)) => Ok((ctx, Type::bool(), Expr::bool(*b))),
-            Expr::Var(var) => {
+            Expr::Literal(Literal::Int(i), span) => Ok((ctx, Type::int(), Expr::int(*i, *span))),
+            Expr::Literal(Literal::Bool(b), span) => Ok((ctx, Type::bool(), Expr::bool(*b, *span))),
+            Expr::Var(var, span) => {
                 // Var
                 let res = match ctx.find_var(var) {
                     Some(ty) => Ok(ty.clone()),
@@ -1233,14 +1330,17 @@ impl TypeChecker {
                     (
                         ctx,
                         ty.clone(),
-                        Expr::Var(Var {
-                            name: var.to_string(),
-                            ty,
-                        }),
+                        Expr::Var(
+                            Var {
+                                name: var.to_string(),
+                                ty,
+                            },
+                            *span,
+                        ),
                     )
                 })
             }
-            Expr::Ann { expr, ty } => {
+            Expr::Ann { expr, ty, .. } => {
                 // Anno
                 if ctx.wf_type(ty) {
                     let (new_ctx, typed_expr) = self.check(ctx, expr, ty)?;
@@ -1250,13 +1350,18 @@ impl TypeChecker {
                     Err(TypeError::InvalidAnnotation(ty.clone()))
                 }
             }
-            Expr::Let { binder, expr, body } => {
+            Expr::Let {
+                binder,
+                expr,
+                body,
+                span,
+            } => {
                 let (ctx, ty_binder, typed_expr) = self.infer(ctx, expr)?;
                 let binder_fresh = self.name_gen.fresh_var();
                 let mut tmp_ctx = ctx;
                 let marker = ContextElem::Anno(binder_fresh.clone(), ty_binder.clone());
                 tmp_ctx.push(marker.clone());
-                let body = body.subst(binder, &Expr::Var(binder_fresh.clone()));
+                let body = (**body).clone().subst_var(binder, &binder_fresh);
                 let (mut res_ctx, ty_body, typed_body) = self.infer(tmp_ctx, &body)?;
                 let ty_binder = res_ctx.apply(&ty_binder);
                 res_ctx.drop_marker(marker);
@@ -1271,11 +1376,12 @@ impl TypeChecker {
                         },
                         expr: Box::new(typed_expr),
                         body: Box::new(typed_body),
+                        span: *span,
                     },
                 ))
             }
-            Expr::LetRec { binder, expr, body } => unreachable!("recursive"),
-            Expr::Lambda { binder, body } => {
+            Expr::LetRec { .. } => unreachable!("recursive"),
+            Expr::Lambda { binder, body, span } => {
                 // ->l=>
                 let mut tmp_ctx = ctx;
                 let binder_fresh = self.name_gen.fresh_var();
@@ -1290,7 +1396,7 @@ impl TypeChecker {
 
                 let (mut res_ctx, typed_body) = self.check(
                     tmp_ctx,
-                    &body.subst(binder, &Expr::Var(binder_fresh.clone())),
+                    &(**body).clone().subst_var(binder, &binder_fresh),
                     &Type::Existential(b.clone()),
                 )?;
                 res_ctx.drop_marker(marker);
@@ -1304,10 +1410,11 @@ impl TypeChecker {
                             ty: Type::ex(&a),
                         },
                         body: Box::new(typed_body),
+                        span: *span,
                     },
                 ))
             }
-            Expr::App { func, arg } => {
+            Expr::App { func, arg, span } => {
                 let (ctx, func_ty, typed_func) = self.infer(ctx, func)?;
                 let applied_func_ty = ctx.apply(&func_ty);
                 let (ctx, app_ty, typed_arg) =
@@ -1318,10 +1425,11 @@ impl TypeChecker {
                     Expr::App {
                         func: Box::new(typed_func),
                         arg: Box::new(typed_arg),
+                        span: *span,
                     },
                 ))
             }
-            Expr::Construction { dtor, args } => {
+            Expr::Construction { dtor, args, span } => {
                 let (DataConstructor { fields, .. }, ty_args) =
                     self.find_data_constructor(&dtor)?;
                 if args.len() != fields.len() {
@@ -1363,20 +1471,47 @@ impl TypeChecker {
                     Expr::Construction {
                         dtor: dtor.clone(),
                         args: typed_fields,
+                        span: *span,
                     },
                 ))
             }
 
-            Expr::Tuple(fst, snd) => {
+            Expr::Tuple(fst, snd, span) => {
                 let (ctx, fst_ty, typed_fst) = self.infer(ctx, fst)?;
                 let (ctx, snd_ty, typed_snd) = self.infer(ctx, snd)?;
                 Ok((
                     ctx,
                     Type::tuple(fst_ty, snd_ty),
-                    Expr::tuple(typed_fst, typed_snd),
+                    Expr::tuple(typed_fst, typed_snd, *span),
                 ))
             }
             Expr::Match { .. } => Err(TypeError::CantInferMatch),
+            Expr::If {
+                condition,
+                then_branch,
+                else_branch,
+                span,
+            } => {
+                // The else branch has to agree with whatever we find out
+                // about the then branch
+                let (ctx, typed_condition) = self.check(ctx, condition, &Type::bool())?;
+                let (ctx, ty, typed_then) = self.infer(ctx, then_branch)?;
+                let ty = ctx.apply(&ty);
+                let (ctx, typed_else) = self.check(ctx, else_branch, &ty)?;
+                let typed_condition = ctx.apply_expr(typed_condition);
+                let typed_then = ctx.apply_expr(typed_then);
+                let ty = ctx.apply(&ty);
+                Ok((
+                    ctx,
+                    ty,
+                    Expr::If {
+                        condition: Box::new(typed_condition),
+                        then_branch: Box::new(typed_then),
+                        else_branch: Box::new(typed_else),
+                        span: *span,
+                    },
+                ))
+            }
         }
     }
 
@@ -1429,10 +1564,8 @@ impl TypeChecker {
     }
 
     pub fn synth(&mut self, expr: &ParserExpr) -> Result<Type, TypeError> {
-        let initial_ctx = Context::new(vec![
-            ContextElem::Anno("primadd".to_string(), Type::int()),
-            ContextElem::Anno("pi".to_string(), Type::int()),
-        ]);
+        let mut initial_ctx = primitives_context();
+        initial_ctx.push(ContextElem::Anno("pi".to_string(), Type::int()));
         self.infer(initial_ctx, expr).map(|x| {
             debug!("synth_ctx: {:?}", x.0);
             x.0.apply(&x.1)
@@ -1442,22 +1575,7 @@ impl TypeChecker {
         &mut self,
         prog: Vec<Declaration<String>>,
     ) -> Result<Vec<(Declaration<Var>, Type)>, TypeError> {
-        let mk_prim = |name: &str| {
-            ContextElem::Anno(
-                name.to_string(),
-                Type::Poly {
-                    vars: vec!["a".to_string()],
-                    ty: Box::new(Type::var("a")),
-                },
-            )
-        };
-
-        let mut ctx = Context::new(vec![
-            mk_prim("primadd"),
-            mk_prim("primtuple"),
-            mk_prim("primfst"),
-            mk_prim("primsnd"),
-        ]);
+        let mut ctx = primitives_context();
         let mut result = vec![];
 
         for decl in prog {
@@ -1466,7 +1584,13 @@ impl TypeChecker {
                     self.add_type_declaration(type_decl.clone());
                     result.push((Declaration::Type(type_decl), Type::int()))
                 }
-                Declaration::Value(ValueDeclaration { name, expr }) => {
+                Declaration::Error(_) => {}
+                Declaration::Value(ValueDeclaration {
+                    name,
+                    expr,
+                    span,
+                    doc,
+                }) => {
                     debug!(
                         "Inferring declaration {}: \n=============================",
                         name
@@ -1479,6 +1603,8 @@ impl TypeChecker {
                         Declaration::Value(ValueDeclaration {
                             name: name.clone(),
                             expr,
+                            span,
+                            doc,
                         }),
                         ty,
                     ));
@@ -1490,9 +1616,26 @@ impl TypeChecker {
     }
 }
 
+fn primitives_context() -> Context {
+    Context::new(
+        PRIMITIVES
+            .iter()
+            .flat_map(|prim| {
+                vec![
+                    ContextElem::Anno(primitives::qualified(prim.name), prim.ty()),
+                    ContextElem::Anno(prim.name.to_string(), prim.ty()),
+                ]
+            })
+            .collect(),
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::grammar::ExprParser;
+    use crate::span::FileId;
+    use crate::token::Lexer;
 
     #[test]
     fn subst_mut() {
@@ -1534,4 +1677,22 @@ mod tests {
         let res = tc.subtype(ctx, &a, &b);
         assert_eq!(res, Ok(Context::new(vec![])));
     }
+
+    fn synth_source(source: &str) -> Result<Type, TypeError> {
+        let expr = ExprParser::new()
+            .parse(FileId::default(), &mut vec![], Lexer::new(source))
+            .unwrap();
+        TypeChecker::new().synth(&expr)
+    }
+
+    #[test]
+    fn synth_if() {
+        let res = synth_source("if 1 < 2 then \\x. x else \\y. y + 1");
+        assert_eq!(res, Ok(Type::fun(Type::int(), Type::int())));
+    }
+
+    #[test]
+    fn synth_if_non_bool_condition() {
+        assert!(synth_source("if 1 then 2 else 3").is_err());
+    }
 }
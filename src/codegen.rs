@@ -1,61 +1,26 @@
 use crate::bi_types::Type;
 use crate::expr::{
-    Case, DataConstructor, Declaration, Dtor, Expr, HasIdent, Literal, TypeDeclaration,
-    ValueDeclaration,
+    DataConstructor, Declaration, Dtor, Expr, HasIdent, Literal, TypeDeclaration, ValueDeclaration,
 };
-use std::collections::HashMap;
+use crate::ir::{IRCase, IRDeclaration, IRExpression, IRPattern, IR};
+use crate::patterns::{self, Decision, SimplePattern};
+use crate::primitives::{self, Primitive, PRIMITIVES};
+use crate::span::Span;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 
-#[derive(Debug, PartialEq, Eq, Clone)]
-pub struct IR {
-    pub globals: Vec<IRDeclaration>,
-    pub entry_point: String,
-}
-
-#[derive(Debug, PartialEq, Eq, Clone)]
-pub struct IRDeclaration {
-    pub name: String,
-    pub arguments: Vec<String>,
-    pub locals: Vec<String>,
-    pub expr: IRExpression,
-}
-
-#[derive(Debug, PartialEq, Eq, Clone)]
-pub enum IRExpression {
-    App {
-        func: Box<IRExpression>,
-        args: Vec<IRExpression>,
-    },
-    Let {
-        binder: String,
-        expr: Box<IRExpression>,
-        body: Box<IRExpression>,
-    },
-    // Is there an opportunity to differentiate locals from globals here?
-    Var(String),
-    Literal(Literal),
-    Pack {
-        tag: u32,
-        args: Vec<IRExpression>,
-    },
-    Match {
-        expr_local: String,
-        expr: Box<IRExpression>,
-        cases: Vec<IRCase>,
-    },
-}
-
-#[derive(Debug, PartialEq, Eq, Clone)]
-pub struct IRCase {
-    tag: u32,
-    binders: Vec<String>,
-    expr: IRExpression,
-}
+/// Tuples are packs with two fields. They're only ever matched by tuple
+/// patterns, so any tag would do.
+const TUPLE_TAG: u32 = 1;
 
 #[derive(Debug, Default)]
 pub struct Lowering {
     supply: u32,
     types: HashMap<String, Vec<DataConstructor>>,
+    /// The names of all top-level values, they shadow primitives
+    globals: HashSet<String>,
+    /// The lambda binders in scope at the expression we're lowering
+    scope: Vec<String>,
 }
 
 impl Lowering {
@@ -76,6 +41,26 @@ impl Lowering {
         self.fresh_name("")
     }
 
+    /// Gives lambda binders that shadow a global a fresh name, so closure
+    /// conversion and codegen can't mistake them for the global
+    fn rename_shadowing<B: HasIdent + Clone>(
+        &mut self,
+        binders: Vec<B>,
+        body: &mut Expr<B>,
+    ) -> Vec<String> {
+        let mut arguments: Vec<String> = binders.into_iter().map(|v| v.ident()).collect();
+        // Later binders shadow earlier ones with the same name, so they get
+        // renamed first
+        for argument in arguments.iter_mut().rev() {
+            if self.globals.contains(argument) {
+                let fresh = self.fresh_name(argument);
+                body.subst_var_mut(argument, &fresh);
+                *argument = fresh;
+            }
+        }
+        arguments
+    }
+
     fn find_data_constructor(&self, dtor: &Dtor) -> Result<(usize, usize), CodegenError> {
         let dtors = self
             .types
@@ -98,6 +83,12 @@ impl Lowering {
         &mut self,
         prog: Vec<(Declaration<B>, T)>,
     ) -> Result<IR, CodegenError> {
+        for (decl, _) in prog.iter() {
+            if let Declaration::Value(vd) = decl {
+                self.globals.insert(vd.name.clone());
+            }
+        }
+
         let mut globals = vec![];
         for (decl, _) in prog {
             match decl {
@@ -107,6 +98,7 @@ impl Lowering {
                     globals.push(g);
                 }
                 Declaration::Type(td) => self.add_type_declaration(td),
+                Declaration::Error(_) => {}
             }
         }
 
@@ -120,12 +112,14 @@ impl Lowering {
         &mut self,
         decl: ValueDeclaration<B>,
     ) -> Result<(IRDeclaration, Vec<IRDeclaration>), CodegenError> {
-        let (arguments, expr) = decl.expr.collapse_lambdas();
+        let (arguments, mut expr) = decl.expr.collapse_lambdas();
+        let arguments = self.rename_shadowing(arguments, &mut expr);
+        self.scope = arguments.clone();
         let (lowered_expr, locals, globals) = self.lower_expr(expr)?;
         Ok((
             IRDeclaration {
                 name: decl.name,
-                arguments: arguments.into_iter().map(|v| v.ident()).collect(),
+                arguments,
                 expr: lowered_expr,
                 locals,
             },
@@ -143,10 +137,15 @@ impl Lowering {
         if let Some(recursive_binder) = is_recursive {
             body.subst_var_mut(recursive_binder, &fresh_name);
         }
-        let (lowered_body, locals, mut gs) = self.lower_expr(body)?;
+        let arguments = self.rename_shadowing(binders, &mut body);
+        let scope_len = self.scope.len();
+        self.scope.extend(arguments.iter().cloned());
+        let lowered = self.lower_expr(body);
+        self.scope.truncate(scope_len);
+        let (lowered_body, locals, mut gs) = lowered?;
         let ir_decl = IRDeclaration {
             name: fresh_name.clone(),
-            arguments: binders.into_iter().map(|v| v.ident()).collect(),
+            arguments,
             locals,
             expr: lowered_body,
         };
@@ -158,14 +157,41 @@ impl Lowering {
     fn lower_expr<B: HasIdent + Clone>(
         &mut self,
         expr: Expr<B>,
+    ) -> Result<(IRExpression, Vec<String>, Vec<IRDeclaration>), CodegenError> {
+        let span = expr.span();
+        self.lower_expr_inner(expr).map_err(|err| err.at(span))
+    }
+
+    fn lower_expr_inner<B: HasIdent + Clone>(
+        &mut self,
+        expr: Expr<B>,
     ) -> Result<(IRExpression, Vec<String>, Vec<IRDeclaration>), CodegenError> {
         match expr {
             Expr::Ann { expr, .. } => self.lower_expr(*expr),
-            Expr::Literal(lit) => Ok((IRExpression::Literal(lit), vec![], vec![])),
-            Expr::Var(v) => Ok((IRExpression::Var(v.ident()), vec![], vec![])),
-            Expr::Tuple { .. } => Err(CodegenError::NotImplemented(
-                "Can't lower tuples".to_string(),
-            )),
+            Expr::Literal(lit, _) => Ok((IRExpression::Literal(lit), vec![], vec![])),
+            Expr::Var(v, _) => {
+                let name = v.ident();
+                let shadowed = self.scope.contains(&name) || self.globals.contains(&name);
+                let lowered = match primitives::lookup(&name) {
+                    Some(prim) if !shadowed => IRExpression::Primitive(prim.name.to_string()),
+                    _ => IRExpression::Var(name),
+                };
+                Ok((lowered, vec![], vec![]))
+            }
+            Expr::Tuple(fst, snd, _) => {
+                let (lowered_fst, mut ls, mut gs) = self.lower_expr(*fst)?;
+                let (lowered_snd, ls_snd, gs_snd) = self.lower_expr(*snd)?;
+                ls.extend(ls_snd);
+                gs.extend(gs_snd);
+                Ok((
+                    IRExpression::Pack {
+                        tag: TUPLE_TAG,
+                        args: vec![lowered_fst, lowered_snd],
+                    },
+                    ls,
+                    gs,
+                ))
+            }
             Expr::App { .. } => {
                 let mut args = expr.unfold_applications().into_iter();
                 let func = args.next().unwrap();
@@ -187,7 +213,7 @@ impl Lowering {
                     gs,
                 ))
             }
-            Expr::Construction { dtor, args } => {
+            Expr::Construction { dtor, args, .. } => {
                 let mut lowered_args = vec![];
                 let mut ls = vec![];
                 let mut gs = vec![];
@@ -210,7 +236,9 @@ impl Lowering {
                 ))
             }
             Expr::Lambda { .. } => self.lower_lambda(expr, None),
-            Expr::Let { binder, expr, body } => {
+            Expr::Let {
+                binder, expr, body, ..
+            } => {
                 let fresh_local = self.fresh_name(&binder.ident());
                 let renamed_body = body.subst_var(&binder.ident(), &fresh_local);
                 let mut locals = vec![fresh_local.clone()];
@@ -229,7 +257,9 @@ impl Lowering {
                     gs,
                 ))
             }
-            Expr::LetRec { binder, expr, body } => {
+            Expr::LetRec {
+                binder, expr, body, ..
+            } => {
                 let fresh_local = self.fresh_name(&binder.ident());
                 let mut locals = vec![fresh_local.clone()];
                 let (lowered_expr, ls, mut gs) = if let Expr::Lambda { .. } = *expr {
@@ -253,51 +283,116 @@ impl Lowering {
                     gs,
                 ))
             }
-            Expr::Match { expr, cases } => {
-                let expr_local = self.fresh_name("match");
+            Expr::Match { expr, cases, .. } => {
+                let scrutinee = self.fresh_name("match");
                 let (lowered_expr, mut ls, mut gs) = self.lower_expr(*expr)?;
-                ls.push(expr_local.clone());
-                let max_binders = cases
-                    .iter()
-                    .fold(0, |acc, case| usize::max(acc, case.binders.len()));
-                let mut fresh_binders = Vec::with_capacity(max_binders);
-                for _ in 1..=max_binders {
-                    fresh_binders.push(self.fresh_name("case"))
-                }
-                ls.extend(fresh_binders.clone());
+                ls.push(scrutinee.clone());
+
+                let mut fresh_locals = vec![];
+                let supply = &mut self.supply;
+                let decision =
+                    patterns::compile_match(&self.types, &scrutinee, cases, &mut |name: &str| {
+                        *supply += 1;
+                        let fresh = format!("${}{}", name, supply);
+                        fresh_locals.push(fresh.clone());
+                        fresh
+                    });
+                ls.extend(fresh_locals);
+
+                let (lowered_decision, ls_decision, gs_decision) = self.lower_decision(decision)?;
+                ls.extend(ls_decision);
+                gs.extend(gs_decision);
+                Ok((
+                    IRExpression::Let {
+                        binder: scrutinee,
+                        expr: Box::new(lowered_expr),
+                        body: Box::new(lowered_decision),
+                    },
+                    ls,
+                    gs,
+                ))
+            }
+            Expr::If {
+                condition,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                let (lowered_condition, mut ls, mut gs) = self.lower_expr(*condition)?;
+                let (lowered_then, ls_then, gs_then) = self.lower_expr(*then_branch)?;
+                let (lowered_else, ls_else, gs_else) = self.lower_expr(*else_branch)?;
+                ls.extend(ls_then);
+                ls.extend(ls_else);
+                gs.extend(gs_then);
+                gs.extend(gs_else);
+                Ok((
+                    IRExpression::If {
+                        condition: Box::new(lowered_condition),
+                        then_branch: Box::new(lowered_then),
+                        else_branch: Box::new(lowered_else),
+                    },
+                    ls,
+                    gs,
+                ))
+            }
+        }
+    }
 
+    fn lower_decision<B: HasIdent + Clone>(
+        &mut self,
+        decision: Decision<B>,
+    ) -> Result<(IRExpression, Vec<String>, Vec<IRDeclaration>), CodegenError> {
+        match decision {
+            Decision::Leaf(expr) => self.lower_expr(expr),
+            Decision::Switch {
+                scrutinee,
+                cases,
+                default,
+            } => {
+                let expr_local = self.fresh_name("switch");
+                let mut ls = vec![expr_local.clone()];
+                let mut gs = vec![];
                 let mut lowered_cases = vec![];
-                for Case {
-                    data_constructor,
-                    binders,
-                    expr,
-                } in cases
-                {
-                    let case_binders: Vec<(String, &str)> = binders
-                        .iter()
-                        .zip(fresh_binders.iter())
-                        .map(|(v, fresh)| (v.ident(), fresh.as_str()))
-                        .collect();
-                    let renamed_expr = expr.subst_var_many_(case_binders.clone());
-                    let (tag, arity) = self.find_data_constructor(&data_constructor)?;
-                    assert_eq!(arity, case_binders.len());
-                    let (lowered_expr, ls_case, gs_case) = self.lower_expr(renamed_expr)?;
+                for (pattern, decision) in cases {
+                    let pattern = match pattern {
+                        SimplePattern::Constructor { dtor, binders } => {
+                            let (tag, arity) = self.find_data_constructor(&dtor)?;
+                            assert_eq!(arity, binders.len());
+                            IRPattern::Pack {
+                                tag: tag as u32,
+                                binders,
+                            }
+                        }
+                        SimplePattern::Literal(lit) => IRPattern::Literal(lit),
+                        SimplePattern::Tuple(fst, snd) => IRPattern::Pack {
+                            tag: TUPLE_TAG,
+                            binders: vec![fst, snd],
+                        },
+                    };
+                    let (lowered_expr, ls_case, gs_case) = self.lower_decision(decision)?;
                     ls.extend(ls_case);
                     gs.extend(gs_case);
                     lowered_cases.push(IRCase {
-                        tag: tag as u32,
-                        binders: case_binders
-                            .into_iter()
-                            .map(|(_, new)| new.to_string())
-                            .collect(),
+                        pattern,
                         expr: lowered_expr,
                     });
                 }
+                let default = match default {
+                    None => None,
+                    Some(decision) => {
+                        let (lowered_default, ls_default, gs_default) =
+                            self.lower_decision(*decision)?;
+                        ls.extend(ls_default);
+                        gs.extend(gs_default);
+                        Some(Box::new(lowered_default))
+                    }
+                };
                 Ok((
                     IRExpression::Match {
                         expr_local,
-                        expr: Box::new(lowered_expr),
+                        expr: Box::new(IRExpression::Var(scrutinee)),
                         cases: lowered_cases,
+                        default,
                     },
                     ls,
                     gs,
@@ -312,6 +407,25 @@ pub enum CodegenError {
     NotImplemented(String),
     UnknownType(String),
     UnknownDataConstructor(Dtor),
+    Located(Span, Box<CodegenError>),
+}
+
+impl CodegenError {
+    /// Attaches a source location to an error, unless it already has a more
+    /// precise one.
+    pub fn at(self, span: Span) -> CodegenError {
+        match self {
+            CodegenError::Located(..) => self,
+            err => CodegenError::Located(span, Box::new(err)),
+        }
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            CodegenError::Located(span, _) => Some(*span),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for CodegenError {
@@ -327,6 +441,7 @@ impl fmt::Display for CodegenError {
                 CodegenError::UnknownDataConstructor(dtor) => {
                     format!("Couldn't lower unknown dataconstructor: {}", dtor)
                 }
+                CodegenError::Located(_, err) => format!("{}", err),
             }
         )
     }
@@ -336,6 +451,9 @@ impl fmt::Display for CodegenError {
 pub struct Codegen {
     /// A mapping from names to their index in the function table
     global_names: HashMap<String, u32>,
+    /// The frame offsets of the arguments and locals of the function we're
+    /// generating
+    slots: HashMap<String, u32>,
     out: String,
 }
 
@@ -343,6 +461,7 @@ impl Codegen {
     pub fn new() -> Codegen {
         Codegen {
             global_names: HashMap::new(),
+            slots: HashMap::new(),
             out: String::new(),
         }
     }
@@ -352,15 +471,40 @@ impl Codegen {
         for (index, global) in ir.globals.iter().enumerate() {
             global_names.insert(global.name.clone(), index as u32);
         }
+        for (index, prim) in PRIMITIVES.iter().enumerate() {
+            global_names.insert(primitive_function(prim), (ir.globals.len() + index) as u32);
+        }
         self.global_names = global_names;
     }
 
+    /// Every primitive also gets a function, so it can be passed around and
+    /// partially applied like any other global
+    fn primitives(&mut self) {
+        for prim in PRIMITIVES {
+            let arguments: Vec<String> = (0..prim.arity).map(|ix| format!("arg{}", ix)).collect();
+            let expr = IRExpression::App {
+                func: Box::new(IRExpression::Primitive(prim.name.to_string())),
+                args: arguments.iter().cloned().map(IRExpression::Var).collect(),
+            };
+            self.gen_decl(IRDeclaration {
+                name: primitive_function(prim),
+                arguments,
+                locals: vec![],
+                expr,
+            })
+        }
+    }
+
     pub fn codegen(mut self, ir: IR) -> String {
         self.populate_global_names(&ir);
 
         self.out += "(module\n";
         self.function_table();
         self.rts();
+        for arity in ir.pack_arities() {
+            self.out += &construct_pack(arity);
+        }
+        self.primitives();
         for ir_decl in ir.globals {
             self.gen_decl(ir_decl);
         }
@@ -378,22 +522,32 @@ impl Codegen {
         }
         self.out += " (result i32)\n";
 
-        for binder in decl.arguments.iter() {
-            self.out += &format!("(local ${} i32)\n", binder)
-        }
-        for local in decl.locals {
-            self.out += &format!("(local ${} i32)\n", local)
-        }
-        for (ix, binder) in decl.arguments.iter().enumerate() {
+        // Arguments and locals live in a frame on the shadow stack rather
+        // than in wasm locals, so the garbage collector can find and update
+        // them
+        let frame_size = arg_count + decl.locals.len();
+        self.slots = decl
+            .arguments
+            .iter()
+            .chain(decl.locals.iter())
+            .enumerate()
+            .map(|(ix, name)| (name.clone(), ix as u32 * 4))
+            .collect();
+        self.out += "(local $fp i32)\n";
+        self.out += &format!(
+            "(local.set $fp (call $enter_frame (i32.const {})))\n",
+            frame_size
+        );
+        for ix in 0..arg_count {
             self.out += &format!(
-                "(set_local ${} (i32.load (i32.add (get_local $args) (i32.const {}))))\n",
-                binder,
+                "(i32.store offset={} (local.get $fp) (i32.load offset={} (local.get $args)))\n",
+                ix * 4,
                 ix * 4
             )
         }
 
         self.gen_expr(decl.expr);
-        self.out += ")\n";
+        self.out += "\n(global.set $sp (local.get $fp)))\n";
 
         if arg_count == 0 {
             self.out += &format!("(func ${}_c (result i32) (call ${}))", decl.name, decl.name)
@@ -408,142 +562,484 @@ impl Codegen {
         }
     }
 
-    fn gen_expr(&mut self, expr: IRExpression) {
+    /// The offset of a local's slot in the current frame
+    fn slot(&self, local: &str) -> u32 {
+        *self
+            .slots
+            .get(local)
+            .unwrap_or_else(|| panic!("Unknown local {}", local))
+    }
+
+    /// Stores the value on top of the stack in a local's slot
+    fn set_slot(&mut self, local: &str, value: IRExpression) {
+        let offset = self.slot(local);
+        self.out += "(local.get $fp)\n";
+        self.gen_expr(value);
+        self.out += &format!("\ni32.store offset={}\n", offset);
+    }
+
+    fn get_slot(&self, local: &str) -> String {
+        format!("(i32.load offset={} (local.get $fp))", self.slot(local))
+    }
+
+    /// Whether evaluating the expression could allocate, and so trigger a
+    /// garbage collection
+    fn may_allocate(&self, expr: &IRExpression) -> bool {
         match expr {
-            IRExpression::Literal(Literal::Int(i)) => {
-                self.out += &format!("(i32.const {})", i);
+            IRExpression::Literal(_) => false,
+            IRExpression::Var(v) => self.global_names.contains_key(v),
+            _ => true,
+        }
+    }
+
+    fn gen_to_string(&mut self, expr: IRExpression) -> String {
+        let out = std::mem::take(&mut self.out);
+        self.gen_expr(expr);
+        std::mem::replace(&mut self.out, out)
+    }
+
+    /// Evaluates the operands of a call, and returns the code for loading
+    /// each of them, in order. A collection moves the objects they point to,
+    /// so the values of operands that allocate are kept on the shadow stack.
+    /// Operands that don't allocate are evaluated once they're loaded. The
+    /// caller needs to pop the returned number of values once it's done.
+    fn gen_operands(&mut self, operands: Vec<IRExpression>) -> (Vec<String>, usize) {
+        let to_push = operands.iter().filter(|op| self.may_allocate(op)).count();
+        let mut pushed = 0;
+        let mut loads = vec![];
+        for operand in operands {
+            if self.may_allocate(&operand) {
+                self.gen_expr(operand);
+                self.out += "\ncall $push\n";
+                loads.push(format!(
+                    "(i32.load (i32.sub (global.get $sp) (i32.const {})))",
+                    (to_push - pushed) * 4
+                ));
+                pushed += 1;
+            } else {
+                loads.push(self.gen_to_string(operand))
             }
-            IRExpression::Literal(Literal::Bool(b)) => {
-                self.out += &format!("(i32.const {})", if b { 1 } else { 0 });
+        }
+        (loads, to_push)
+    }
+
+    fn gen_application(&mut self, func: IRExpression, args: Vec<IRExpression>) {
+        let mut operands = vec![func];
+        operands.extend(args);
+        let (loads, pushed) = self.gen_operands(operands);
+        let mut loads = loads.into_iter();
+        self.out += &loads.next().unwrap();
+        for load in loads {
+            self.out += "\n";
+            self.out += &load;
+            self.out += "\ncall $apply\n";
+        }
+        self.pop_operands(pushed);
+    }
+
+    fn pop_operands(&mut self, pushed: usize) {
+        if pushed != 0 {
+            self.out += &format!(
+                "\n(global.set $sp (i32.sub (global.get $sp) (i32.const {})))\n",
+                pushed * 4
+            )
+        }
+    }
+
+    fn gen_expr(&mut self, expr: IRExpression) {
+        match expr {
+            IRExpression::Literal(lit) => {
+                self.out += &format!("(i32.const {})", tagged(&lit));
             }
             IRExpression::Pack { tag, args } => {
-                self.out += &format!("(i32.const {})\n", tag);
                 let args_len = args.len();
-                for arg in args {
-                    self.gen_expr(arg);
+                let (loads, pushed) = self.gen_operands(args);
+                self.out += &format!("(i32.const {})\n", tag);
+                for load in loads {
+                    self.out += &load;
                     self.out += "\n";
                 }
                 self.out += &format!("(call $construct_pack_{})", args_len);
+                self.pop_operands(pushed);
             }
             IRExpression::Let { binder, expr, body } => {
-                self.gen_expr(*expr);
-                self.out += &format!("(local.set ${})", binder);
+                self.set_slot(&binder, *expr);
                 self.gen_expr(*body)
             }
+            IRExpression::Primitive(name) => {
+                let prim = primitives::lookup(&name).expect("Unknown primitive");
+                self.out += &format!("(call ${}_c)", primitive_function(prim))
+            }
             IRExpression::Var(v) => {
-                if &v == "primadd" {
-                    self.out += "(i32.add (local.get $x) (local.get $y))"
-                } else if self.global_names.contains_key(&v) {
+                if self.global_names.contains_key(&v) {
                     self.out += &format!("(call ${}_c)", v)
                 } else {
-                    self.out += &format!("(local.get ${})", v)
+                    self.out += &self.get_slot(&v)
                 }
             }
-            IRExpression::App { func, args } => {
-                self.gen_expr(*func);
-                for arg in args {
-                    self.out += "(call $apply ";
-                    self.gen_expr(arg);
-                    self.out += ")";
+            IRExpression::App { func, args } => match *func {
+                // Saturated primitives get their instructions inlined. Their
+                // arguments are ints and bools, or a single tuple, so none of
+                // them can be moved by a collection while the others are
+                // evaluated.
+                IRExpression::Primitive(name)
+                    if args.len() == primitives::lookup(&name).unwrap().arity =>
+                {
+                    let prim = primitives::lookup(&name).unwrap();
+                    for arg in args {
+                        self.gen_expr(arg);
+                        self.out += "\n";
+                    }
+                    self.out += prim.wasm;
                 }
-            }
+                // The result of a primitive applied to more arguments than it
+                // takes is a closure, which needs to stay on the shadow stack
+                // while the remaining arguments are evaluated
+                IRExpression::Primitive(name)
+                    if args.len() > primitives::lookup(&name).unwrap().arity =>
+                {
+                    let mut args = args;
+                    let rest = args.split_off(primitives::lookup(&name).unwrap().arity);
+                    let saturated = IRExpression::App {
+                        func: Box::new(IRExpression::Primitive(name)),
+                        args,
+                    };
+                    self.gen_application(saturated, rest)
+                }
+                func => self.gen_application(func, args),
+            },
             IRExpression::Match {
                 expr_local,
                 expr,
                 cases,
+                default,
             } => {
-                self.gen_expr(*expr);
-                self.out += &format!("(local.set ${})\n", expr_local);
+                self.set_slot(&expr_local, *expr);
+                let scrutinee = self.get_slot(&expr_local);
                 let cases_len = cases.len();
                 for case in cases {
-                    self.out += &format!("(if (result i32) (i32.eq (call $get_pack_tag (local.get ${})) (i32.const {}))", expr_local, case.tag);
-                    self.out += "\n(then\n";
-                    for (ix, binder) in case.binders.into_iter().enumerate() {
-                        self.out += &format!("(local.set ${} (call $get_pack_field (local.get ${}) (i32.const {})))\n", binder, expr_local, ix);
+                    match case.pattern {
+                        IRPattern::Pack { tag, binders } => {
+                            self.out += &format!(
+                                "(if (result i32) (i32.eq (call $get_pack_tag {}) (i32.const {}))",
+                                scrutinee, tag
+                            );
+                            self.out += "\n(then\n";
+                            for (ix, binder) in binders.into_iter().enumerate() {
+                                self.out += &format!(
+                                    "(i32.store offset={} (local.get $fp) (call $get_pack_field {} (i32.const {})))\n",
+                                    self.slot(&binder),
+                                    scrutinee,
+                                    ix
+                                );
+                            }
+                        }
+                        IRPattern::Literal(lit) => {
+                            self.out += &format!(
+                                "(if (result i32) (i32.eq {} (i32.const {}))",
+                                scrutinee,
+                                tagged(&lit)
+                            );
+                            self.out += "\n(then\n";
+                        }
                     }
                     self.gen_expr(case.expr);
                     self.out += ")\n(else ";
                 }
-                self.out += "(unreachable)";
+                match default {
+                    None => self.out += "(unreachable)",
+                    Some(default) => self.gen_expr(*default),
+                }
                 for _ in 1..=cases_len {
                     self.out += "))"; // closes open (else 's and (if 's
                 }
             }
+            IRExpression::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                // The condition can be made of plain instructions, so it
+                // goes on the stack before the `if` rather than inside it.
+                // Shifting out the tag turns it into a plain 0 or 1.
+                self.gen_expr(*condition);
+                self.out += "\ni32.const 1\ni32.shr_u\n(if (result i32)\n(then\n";
+                self.gen_expr(*then_branch);
+                self.out += ")\n(else\n";
+                self.gen_expr(*else_branch);
+                self.out += "))";
+            }
         }
     }
 
     fn rts(&mut self) {
         self.out += ALLOCATOR_RTS;
         self.out += CLOSURE_RTS;
+        self.out += INT_RTS;
     }
 
     fn function_table(&mut self) {
         self.out += &format!("(table {} anyfunc)", self.global_names.len());
-        for (name, n) in self.global_names.iter() {
+        // Sorted so the generated module is the same on every run
+        let mut elems: Vec<_> = self.global_names.iter().collect();
+        elems.sort_by_key(|(_, n)| **n);
+        for (name, n) in elems {
             self.out += &format!("(elem (i32.const {}) ${})", n, name)
         }
     }
 
+    /// Untags the result of `main` when it's an int or bool, so whoever runs
+    /// the module sees plain numbers
     fn entry_point(&mut self) {
-        self.out += "\n(export \"main\" (func $main))"
+        self.out += r#"
+(func $rts:main (result i32)
+      (local $result i32)
+      (local.set $result (call $main))
+      (if (result i32) (i32.and (local.get $result) (i32.const 1))
+        (then (i32.shr_s (local.get $result) (i32.const 1)))
+        (else (local.get $result))))
+(export "main" (func $rts:main))"#
     }
 }
 
+/// The name of the function implementing a primitive. Qualified names never
+/// clash with user defined functions.
+fn primitive_function(prim: &Primitive) -> String {
+    primitives::qualified(prim.name)
+}
+
+/// Ints and bools (as 0 and 1) are represented as `2n + 1`. Heap pointers
+/// are always even, which is how the garbage collector tells them apart.
+fn tagged(lit: &Literal) -> i32 {
+    let n = match lit {
+        Literal::Int(i) => *i,
+        Literal::Bool(b) => *b as i32,
+    };
+    n.wrapping_shl(1) | 1
+}
+
+/// The first 16 pages of memory hold the shadow stack, which is where
+/// generated functions keep their frames. The rest is the heap, split into
+/// two semispaces. We allocate from one of them until it fills up, then copy
+/// everything that's still reachable from the shadow stack into the other
+/// one, and swap them.
+///
+/// Every heap object starts with a header word telling us its kind, and
+/// pointers to objects point just past it:
+///
+/// - 1: a pack, laid out as `[tag, arity, fields...]`
+/// - 2: a closure, laid out as `[arity, applied, arguments..., code pointer]`
+/// - 3: an object that has been copied during a collection, its first word
+///   holds the new address
+///
+/// Ints and bools are unboxed and odd, so any even value that points into
+/// from-space is a pointer.
 const ALLOCATOR_RTS: &str = r#"
- (memory 1)
- (global $watermark (mut i32) (i32.const 0))
+ (memory 18)
+ (global $sp (mut i32) (i32.const 0))
+ (global $stack_end i32 (i32.const 1048576))
+ (global $from_space (mut i32) (i32.const 1048576))
+ ;; Where the objects in from-space end while we're collecting
+ (global $from_end (mut i32) (i32.const 0))
+ (global $to_space (mut i32) (i32.const 1114112))
+ (global $space_size (mut i32) (i32.const 65536))
+ (global $watermark (mut i32) (i32.const 1048576))
+
+ (func $push (param $value i32)
+       (if (i32.ge_u (global.get $sp) (global.get $stack_end))
+         (then unreachable))
+       (i32.store (global.get $sp) (local.get $value))
+       (global.set $sp (i32.add (global.get $sp) (i32.const 4))))
 
- (func $allocate (param $bytes i32) (result i32)
+ (func $pop (result i32)
+       (global.set $sp (i32.sub (global.get $sp) (i32.const 4)))
+       (i32.load (global.get $sp)))
+
+ ;; Reserves a frame of $slots values on the shadow stack and returns its start
+ (func $enter_frame (param $slots i32) (result i32)
+       (local $fp i32)
+       (local $x i32)
+       (local.set $fp (global.get $sp))
+       (global.set $sp (i32.add (local.get $fp) (i32.mul (local.get $slots) (i32.const 4))))
+       (if (i32.gt_u (global.get $sp) (global.get $stack_end))
+         (then unreachable))
+       ;; Leftovers from earlier frames must not look like pointers
+       (local.set $x (local.get $fp))
+       (block
+        (loop
+         (br_if 1 (i32.ge_u (local.get $x) (global.get $sp)))
+         (i32.store (local.get $x) (i32.const 1))
+         (local.set $x (i32.add (local.get $x) (i32.const 4)))
+         (br 0)))
+       (local.get $fp))
+
+ ;; Allocates an object of the given kind with room for $bytes, and returns a
+ ;; pointer just past its header. This might collect garbage, so the caller
+ ;; needs to keep any pointers it holds on to on the shadow stack.
+ (func $allocate (param $kind i32) (param $bytes i32) (result i32)
+       (local $size i32)
        (local $res i32)
-       (set_local $res (global.get $watermark))
-       (global.set $watermark (i32.add (get_local $res) (get_local $bytes)))
-       (get_local $res))
+       (local.set $size (i32.add (local.get $bytes) (i32.const 4)))
+       (if (i32.gt_u (i32.add (global.get $watermark) (local.get $size))
+                     (i32.add (global.get $from_space) (global.get $space_size)))
+         (then (call $collect (local.get $size))))
+       (local.set $res (global.get $watermark))
+       (global.set $watermark (i32.add (local.get $res) (local.get $size)))
+       (i32.store (local.get $res) (local.get $kind))
+       (i32.add (local.get $res) (i32.const 4)))
+
+ ;; Makes room for $needed bytes. If less than half of the space would be free
+ ;; after a collection, both semispaces get replaced by bigger ones at the end
+ ;; of memory, and we copy once more to move into them.
+ (func $collect (param $needed i32)
+       (local $used i32)
+       (local $new_size i32)
+       (local $new_space i32)
+       (call $copy_live)
+       (local.set $used
+                  (i32.mul (i32.const 2)
+                           (i32.add (i32.sub (global.get $watermark) (global.get $from_space))
+                                    (local.get $needed))))
+       (if (i32.gt_u (local.get $used) (global.get $space_size))
+         (then
+          (local.set $new_size (i32.mul (global.get $space_size) (i32.const 2)))
+          (if (i32.gt_u (local.get $used) (local.get $new_size))
+            (then (local.set $new_size (local.get $used))))
+          ;; Rounds up to whole pages
+          (local.set $new_size (i32.and (i32.add (local.get $new_size) (i32.const 65535))
+                                        (i32.const -65536)))
+          (local.set $new_space (i32.mul (memory.size) (i32.const 65536)))
+          (if (i32.eq (memory.grow (i32.div_u (local.get $new_size) (i32.const 32768)))
+                      (i32.const -1))
+            (then unreachable))
+          (global.set $to_space (local.get $new_space))
+          (call $copy_live)
+          (global.set $to_space (i32.add (local.get $new_space) (local.get $new_size)))
+          (global.set $space_size (local.get $new_size)))))
+
+ ;; Copies everything reachable from the shadow stack into to-space, and
+ ;; swaps the semispaces
+ (func $copy_live
+       (local $x i32)
+       (global.set $from_end (global.get $watermark))
+       (global.set $watermark (global.get $to_space))
+       (local.set $x (i32.const 0))
+       (block
+        (loop
+         (br_if 1 (i32.ge_u (local.get $x) (global.get $sp)))
+         (i32.store (local.get $x) (call $forward (i32.load (local.get $x))))
+         (local.set $x (i32.add (local.get $x) (i32.const 4)))
+         (br 0)))
+       ;; The fields of the objects we've copied still point into from-space.
+       ;; Forwarding them copies more objects, which get scanned in turn.
+       (local.set $x (global.get $to_space))
+       (block
+        (loop
+         (br_if 1 (i32.ge_u (local.get $x) (global.get $watermark)))
+         (local.set $x (call $scan_object (i32.add (local.get $x) (i32.const 4))))
+         (br 0)))
+       (local.set $x (global.get $from_space))
+       (global.set $from_space (global.get $to_space))
+       (global.set $to_space (local.get $x)))
+
+ ;; The size of an object in bytes, including its header
+ (func $object_size (param $object i32) (result i32)
+       (if (result i32)
+           (i32.eq (i32.load (i32.sub (local.get $object) (i32.const 4))) (i32.const 1))
+         (then (i32.add (i32.const 12) (i32.mul (i32.load offset=4 (local.get $object)) (i32.const 4))))
+         (else (i32.add (i32.const 16) (i32.mul (i32.load (local.get $object)) (i32.const 4))))))
+
+ ;; Forwards the fields of an object in to-space, and returns the address of
+ ;; the header that follows it
+ (func $scan_object (param $object i32) (result i32)
+       (local $field i32)
+       (local $fields_end i32)
+       ;; The second word is the arity for packs and the number of applied
+       ;; arguments for closures. Arguments that haven't been applied yet
+       ;; aren't initialized.
+       (local.set $field (i32.add (local.get $object) (i32.const 8)))
+       (local.set $fields_end
+                  (i32.add (local.get $field)
+                           (i32.mul (i32.load offset=4 (local.get $object)) (i32.const 4))))
+       (block
+        (loop
+         (br_if 1 (i32.ge_u (local.get $field) (local.get $fields_end)))
+         (i32.store (local.get $field) (call $forward (i32.load (local.get $field))))
+         (local.set $field (i32.add (local.get $field) (i32.const 4)))
+         (br 0)))
+       (i32.add (i32.sub (local.get $object) (i32.const 4))
+                (call $object_size (local.get $object))))
+
+ ;; Returns the new address of the object $value points to, copying it into
+ ;; to-space the first time we see it. Values that don't point into
+ ;; from-space are returned unchanged.
+ (func $forward (param $value i32) (result i32)
+       (local $header i32)
+       (local $size i32)
+       (local $copy i32)
+       (if (i32.or (i32.and (local.get $value) (i32.const 1))
+                   (i32.or (i32.lt_u (local.get $value) (global.get $from_space))
+                           (i32.ge_u (local.get $value) (global.get $from_end))))
+         (then (return (local.get $value))))
+       (local.set $header (i32.sub (local.get $value) (i32.const 4)))
+       (if (i32.eq (i32.load (local.get $header)) (i32.const 3))
+         (then (return (i32.load (local.get $value)))))
+       (local.set $size (call $object_size (local.get $value)))
+       (local.set $copy (global.get $watermark))
+       (global.set $watermark (i32.add (local.get $copy) (local.get $size)))
+       (call $copy_words (local.get $header) (local.get $copy) (local.get $size))
+       (i32.store (local.get $header) (i32.const 3))
+       (i32.store (local.get $value) (i32.add (local.get $copy) (i32.const 4)))
+       (i32.add (local.get $copy) (i32.const 4)))
+
+ (func $copy_words (param $from i32) (param $to i32) (param $bytes i32)
+       (local $x i32)
+       (block
+        (loop
+         (br_if 1 (i32.ge_u (local.get $x) (local.get $bytes)))
+         (i32.store (i32.add (local.get $to) (local.get $x))
+                    (i32.load (i32.add (local.get $from) (local.get $x))))
+         (local.set $x (i32.add (local.get $x) (i32.const 4)))
+         (br 0))))
 "#;
 
 const CLOSURE_RTS: &str = r#"
  (func $make_closure (param $arity i32) (param $code_pointer i32) (result i32)
        (local $closure_start i32)
        ;; The size of a closure is 12bytes + 4bytes per argument
-       (set_local $closure_start
+       (local.set $closure_start
                   (call $allocate
+                        (i32.const 2)
                         (i32.add (i32.const 12)
-                                 (i32.mul (i32.const 4) (get_local $arity)))))
+                                 (i32.mul (i32.const 4) (local.get $arity)))))
        ;; Initializes arity
        (i32.store
-        (get_local $closure_start)
-        (get_local $arity))
+        (local.get $closure_start)
+        (local.get $arity))
        ;; Initializes applied arg counter to 0
        (i32.store
-        (i32.add (get_local $closure_start) (i32.const 4))
+        (i32.add (local.get $closure_start) (i32.const 4))
         (i32.const 0))
        ;; writes the code pointer
        (i32.store
-        (i32.add (i32.add (get_local $closure_start) (i32.const 8)) ;; skips over arity and applied counter
-                 (i32.mul (get_local $arity) (i32.const 4))) ;; skips over arguments
-        (get_local $code_pointer))
-       (get_local $closure_start))
+        (i32.add (i32.add (local.get $closure_start) (i32.const 8)) ;; skips over arity and applied counter
+                 (i32.mul (local.get $arity) (i32.const 4))) ;; skips over arguments
+        (local.get $code_pointer))
+       (local.get $closure_start))
 
  (func $copy_closure (param $closure i32) (result i32)
        (local $new_closure i32)
        (local $size i32)
-       (local $arity i32)
-       (local $x i32)
-       (set_local $arity (i32.load (get_local $closure)))
-       (set_local $size
+       (local.set $size
                   (i32.add (i32.const 12)
                            (i32.mul (i32.const 4)
-                                    (get_local $arity))))
-       (set_local $new_closure (call $allocate (get_local $size)))
-       (set_local $x (i32.const 0))
-       (block
-        (loop
-         (br_if 1 (i32.ge_s (get_local $x) (get_local $size)))
-         (i32.store
-          (i32.add (get_local $x) (get_local $new_closure))
-          (i32.load (i32.add (get_local $x) (get_local $closure))))
-         (set_local $x (i32.add (i32.const 4) (get_local $x)))
-         (br 0)))
-       (get_local $new_closure))
+                                    (i32.load (local.get $closure)))))
+       ;; Allocating might move the closure
+       (call $push (local.get $closure))
+       (local.set $new_closure (call $allocate (i32.const 2) (local.get $size)))
+       (local.set $closure (call $pop))
+       (call $copy_words (local.get $closure) (local.get $new_closure) (local.get $size))
+       (local.get $new_closure))
 
  (type $i32_to_i32 (func (param i32) (result i32)))
  (func $apply (param $closure i32) (param $arg i32) (result i32)
@@ -553,71 +1049,33 @@ const CLOSURE_RTS: &str = r#"
        (local $next_arg i32)
        (local $code_pointer_offset i32)
 
-       (set_local $closure (call $copy_closure (get_local $closure)))
+       ;; Copying the closure might move the argument
+       (call $push (local.get $arg))
+       (local.set $closure (call $copy_closure (local.get $closure)))
+       (local.set $arg (call $pop))
 
-       (set_local $arity (i32.load (get_local $closure)))
-       (set_local $applied (i32.load (i32.add (get_local $closure) (i32.const 4))))
-       (set_local $arg_start (i32.add (get_local $closure) (i32.const 8)))
-       (set_local $next_arg (i32.add (get_local $arg_start)
-                                     (i32.mul (get_local $applied)
+       (local.set $arity (i32.load (local.get $closure)))
+       (local.set $applied (i32.load (i32.add (local.get $closure) (i32.const 4))))
+       (local.set $arg_start (i32.add (local.get $closure) (i32.const 8)))
+       (local.set $next_arg (i32.add (local.get $arg_start)
+                                     (i32.mul (local.get $applied)
                                               (i32.const 4))))
-       (set_local $code_pointer_offset
-                  (i32.add (get_local $arg_start)
-                           (i32.mul (get_local $arity)
+       (local.set $code_pointer_offset
+                  (i32.add (local.get $arg_start)
+                           (i32.mul (local.get $arity)
                                     (i32.const 4))))
 
        ;; write the supplied argument into its spot
-       (i32.store (get_local $next_arg) (get_local $arg))
+       (i32.store (local.get $next_arg) (local.get $arg))
        (if (result i32)
-           (i32.eq (get_local $arity) (i32.add (get_local $applied) (i32.const 1)))
+           (i32.eq (local.get $arity) (i32.add (local.get $applied) (i32.const 1)))
          (then
           ;; if all arguments have been supplied we're ready to execute the body
-          (call_indirect (type $i32_to_i32) (get_local $arg_start) (i32.load (get_local $code_pointer_offset))))
+          (call_indirect (type $i32_to_i32) (local.get $arg_start) (i32.load (local.get $code_pointer_offset))))
          (else
           ;; If we're still missing arguments we bump the applied counter and return the new closure
-          (i32.store (i32.add (get_local $closure) (i32.const 4)) (i32.add (get_local $applied) (i32.const 1)))
-          (get_local $closure))))
-
- (func $construct_pack_0 (param $tag i32) (result i32)
-       (local $pack_start i32)
-       (set_local $pack_start (call $allocate (i32.const 8)))
-       (i32.store (local.get $pack_start) (local.get $tag))
-       ;; Writing the arity
-       (i32.store (i32.add (local.get $pack_start) (i32.const 4)) (i32.const 0))
-       (local.get $pack_start))
-
- (func $construct_pack_1 (param $tag i32) (param $val1 i32) (result i32)
-       (local $pack_start i32)
-       (set_local $pack_start (call $allocate (i32.const 12)))
-       (i32.store (local.get $pack_start) (local.get $tag))
-       ;; Writing the arity
-       (i32.store (i32.add (local.get $pack_start) (i32.const 4)) (i32.const 1))
-       ;; Writing the values
-       (i32.store (i32.add (local.get $pack_start) (i32.const 8)) (local.get $val1))
-       (local.get $pack_start))
-
- (func $construct_pack_2 (param $tag i32) (param $val1 i32) (param $val2 i32) (result i32)
-       (local $pack_start i32)
-       (set_local $pack_start (call $allocate (i32.const 16)))
-       (i32.store (local.get $pack_start) (local.get $tag))
-       ;; Writing the arity
-       (i32.store (i32.add (local.get $pack_start) (i32.const 4)) (i32.const 2))
-       ;; Writing the values
-       (i32.store (i32.add (local.get $pack_start) (i32.const 8)) (local.get $val1))
-       (i32.store (i32.add (local.get $pack_start) (i32.const 12)) (local.get $val2))
-       (local.get $pack_start))
-
- (func $construct_pack_3 (param $tag i32) (param $val1 i32) (param $val2 i32) (param $val3 i32) (result i32)
-       (local $pack_start i32)
-       (set_local $pack_start (call $allocate (i32.const 16)))
-       (i32.store (local.get $pack_start) (local.get $tag))
-       ;; Writing the arity
-       (i32.store (i32.add (local.get $pack_start) (i32.const 4)) (i32.const 3))
-       ;; Writing the values
-       (i32.store (i32.add (local.get $pack_start) (i32.const 8)) (local.get $val1))
-       (i32.store (i32.add (local.get $pack_start) (i32.const 12)) (local.get $val2))
-       (i32.store (i32.add (local.get $pack_start) (i32.const 16)) (local.get $val3))
-       (local.get $pack_start))
+          (i32.store (i32.add (local.get $closure) (i32.const 4)) (i32.add (local.get $applied) (i32.const 1)))
+          (local.get $closure))))
 
  (func $get_pack_tag (param $pack_start i32) (result i32)
        (i32.load (local.get $pack_start)))
@@ -634,3 +1092,67 @@ const CLOSURE_RTS: &str = r#"
          (else unreachable)))
 
 "#;
+
+/// The runtime function constructing packs with `arity` fields, called as
+/// `$construct_pack_<arity>` with the tag and the fields
+fn construct_pack(arity: usize) -> String {
+    let fields: Vec<String> = (1..=arity).map(|ix| format!("$val{}", ix)).collect();
+    let mut out = format!("\n (func $construct_pack_{} (param $tag i32)", arity);
+    for field in fields.iter() {
+        out += &format!(" (param {} i32)", field);
+    }
+    out += " (result i32)\n       (local $pack_start i32)\n";
+    if arity != 0 {
+        out += "       ;; Allocating might move the values\n";
+    }
+    for field in fields.iter() {
+        out += &format!("       (call $push (local.get {}))\n", field);
+    }
+    out += &format!(
+        "       (local.set $pack_start (call $allocate (i32.const 1) (i32.const {})))\n",
+        8 + 4 * arity
+    );
+    for field in fields.iter().rev() {
+        out += &format!("       (local.set {} (call $pop))\n", field);
+    }
+    out += "       (i32.store (local.get $pack_start) (local.get $tag))\n";
+    out += "       ;; Writing the arity\n";
+    out += &format!(
+        "       (i32.store (i32.add (local.get $pack_start) (i32.const 4)) (i32.const {}))\n",
+        arity
+    );
+    if arity != 0 {
+        out += "       ;; Writing the values\n";
+    }
+    for (ix, field) in fields.iter().enumerate() {
+        out += &format!(
+            "       (i32.store (i32.add (local.get $pack_start) (i32.const {})) (local.get {}))\n",
+            8 + 4 * ix,
+            field
+        );
+    }
+    out += "       (local.get $pack_start))\n";
+    out
+}
+
+/// Ints are represented as `2n + 1`, see `tagged`. Addition, subtraction and
+/// comparisons work on tagged ints as they are, everything else untags them
+/// first.
+const INT_RTS: &str = r#"
+ (func $tag_int (param $n i32) (result i32)
+       (i32.or (i32.shl (local.get $n) (i32.const 1)) (i32.const 1)))
+
+ (func $int_mul (param $x i32) (param $y i32) (result i32)
+       ;; n * 2m + 1
+       (i32.or (i32.mul (i32.shr_s (local.get $x) (i32.const 1))
+                        (i32.sub (local.get $y) (i32.const 1)))
+               (i32.const 1)))
+
+ (func $int_div (param $x i32) (param $y i32) (result i32)
+       (call $tag_int (i32.div_s (i32.shr_s (local.get $x) (i32.const 1))
+                                 (i32.shr_s (local.get $y) (i32.const 1)))))
+
+ (func $int_rem (param $x i32) (param $y i32) (result i32)
+       (call $tag_int (i32.rem_s (i32.shr_s (local.get $x) (i32.const 1))
+                                 (i32.shr_s (local.get $y) (i32.const 1)))))
+"#;
@@ -0,0 +1,329 @@
+use crate::pipeline::PipelineError;
+use crate::span::{FileId, Position, Span};
+use crate::types::TypeWarning;
+use serde_json::{json, Value};
+use std::fmt::Write;
+
+/// A source file known to the diagnostics renderer. We remember where every
+/// line starts, so we can print the offending lines for a span.
+#[derive(Debug)]
+pub struct SourceFile {
+    pub name: String,
+    pub source: String,
+    line_starts: Vec<usize>,
+}
+
+impl SourceFile {
+    pub fn new(name: &str, source: &str) -> SourceFile {
+        let mut line_starts = vec![0];
+        line_starts.extend(source.match_indices('\n').map(|(ix, _)| ix + 1));
+        SourceFile {
+            name: name.to_string(),
+            source: source.to_string(),
+            line_starts,
+        }
+    }
+
+    /// The contents of the zero-based `line`, without its line terminator
+    pub fn line(&self, line: u32) -> Option<&str> {
+        let start = *self.line_starts.get(line as usize)?;
+        let end = self
+            .line_starts
+            .get(line as usize + 1)
+            .cloned()
+            .unwrap_or(self.source.len());
+        Some(self.source[start..end].trim_end_matches(['\n', '\r']))
+    }
+}
+
+/// Maps `FileId`s to the files they were handed out for
+#[derive(Debug, Default)]
+pub struct Files {
+    files: Vec<SourceFile>,
+}
+
+impl Files {
+    pub fn new() -> Files {
+        Files { files: vec![] }
+    }
+
+    pub fn add(&mut self, name: &str, source: &str) -> FileId {
+        self.files.push(SourceFile::new(name, source));
+        FileId(self.files.len() as u32 - 1)
+    }
+
+    pub fn get(&self, file: FileId) -> Option<&SourceFile> {
+        self.files.get(file.0 as usize)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    fn name(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+}
+
+/// A report about a problem in a program. The primary label points at the
+/// problem itself, secondary labels explain how we got there.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub primary: Option<Label>,
+    pub secondary: Vec<Label>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, message: String) -> Diagnostic {
+        Diagnostic {
+            severity,
+            message,
+            primary: None,
+            secondary: vec![],
+        }
+    }
+
+    pub fn error(message: String) -> Diagnostic {
+        Diagnostic::new(Severity::Error, message)
+    }
+
+    pub fn warning(message: String) -> Diagnostic {
+        Diagnostic::new(Severity::Warning, message)
+    }
+
+    pub fn with_primary(mut self, span: Span, message: String) -> Diagnostic {
+        self.primary = Some(Label { span, message });
+        self
+    }
+
+    pub fn with_secondary(mut self, span: Span, message: String) -> Diagnostic {
+        self.secondary.push(Label { span, message });
+        self
+    }
+
+    /// Renders the diagnostic the way compilers usually print them:
+    ///
+    /// ```text
+    /// error: Failed to unify Bool with Int
+    ///  --> prog.siml:2:3
+    ///   |
+    /// 1 | let main : Int =
+    ///   |            --- expected `Int` because of this annotation
+    /// 2 |   true;
+    ///   |   ^^^^
+    /// ```
+    pub fn render(&self, files: &Files) -> String {
+        let mut out = format!("{}: {}\n", self.severity.name(), self.message);
+        let primary = match &self.primary {
+            None => return out,
+            Some(primary) => primary,
+        };
+        let file = match files.get(primary.span.file) {
+            None => {
+                writeln!(out, " --> {}", primary.span).unwrap();
+                return out;
+            }
+            Some(file) => file,
+        };
+
+        let mut labels: Vec<(&Label, char)> = vec![(primary, '^')];
+        labels.extend(
+            self.secondary
+                .iter()
+                .filter(|label| label.span.file == primary.span.file)
+                .map(|label| (label, '-')),
+        );
+        labels.sort_by_key(|(label, _)| label.span.start);
+
+        let gutter = labels
+            .iter()
+            .map(|(label, _)| (label.span.start.line + 1).to_string().len())
+            .max()
+            .unwrap_or(1);
+        let pad = " ".repeat(gutter);
+
+        writeln!(out, "{}--> {}:{}", pad, file.name, primary.span.start).unwrap();
+        writeln!(out, "{} |", pad).unwrap();
+        let mut last_line = None;
+        for (label, marker) in labels {
+            let line_number = label.span.start.line;
+            let line = file.line(line_number).unwrap_or("");
+            if last_line != Some(line_number) {
+                writeln!(
+                    out,
+                    "{:>width$} | {}",
+                    line_number + 1,
+                    line,
+                    width = gutter
+                )
+                .unwrap();
+            }
+            last_line = Some(line_number);
+            let (start, end) = underline_range(line, label.span.start, label.span.end);
+            let underline = format!(
+                "{}{}",
+                underline_padding(line, start),
+                marker.to_string().repeat(end - start)
+            );
+            if label.message.is_empty() {
+                writeln!(out, "{} | {}", pad, underline).unwrap();
+            } else {
+                writeln!(out, "{} | {} {}", pad, underline, label.message).unwrap();
+            }
+        }
+        out
+    }
+
+    /// A machine readable version of the diagnostic. Lines and columns are
+    /// one-based, like in the rendered form, offsets are zero-based byte
+    /// offsets into the file.
+    pub fn to_json(&self, files: &Files) -> Value {
+        let label_json = |label: &Label, primary: bool| {
+            json!({
+                "file": files.get(label.span.file).map(|file| file.name.clone()),
+                "start": position_json(label.span.start),
+                "end": position_json(label.span.end),
+                "message": label.message,
+                "primary": primary,
+            })
+        };
+        let mut labels: Vec<Value> = vec![];
+        if let Some(primary) = &self.primary {
+            labels.push(label_json(primary, true))
+        }
+        labels.extend(self.secondary.iter().map(|label| label_json(label, false)));
+        json!({
+            "severity": self.severity.name(),
+            "message": self.message,
+            "labels": labels,
+        })
+    }
+}
+
+fn position_json(pos: Position) -> Value {
+    json!({
+        "offset": pos.offset,
+        "line": pos.line + 1,
+        "column": pos.column + 1,
+    })
+}
+
+/// The columns to underline on `line` for a span. Spans that continue onto
+/// later lines get underlined until the end of their first line, and empty
+/// spans still get a single marker.
+fn underline_range(line: &str, start: Position, end: Position) -> (usize, usize) {
+    let start_col = start.column as usize;
+    let end_col = if end.line == start.line {
+        end.column as usize
+    } else {
+        line.chars().count()
+    };
+    (start_col, std::cmp::max(end_col, start_col + 1))
+}
+
+/// Blanks out the first `columns` characters of `line`. Tabs are kept, so
+/// the underline lines up with the echoed line however wide a tab is.
+fn underline_padding(line: &str, columns: usize) -> String {
+    line.chars()
+        .chain(std::iter::repeat(' '))
+        .take(columns)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect()
+}
+
+/// All the diagnostics for an error, there's more than one if a phase
+/// recovered from errors and kept going.
+pub fn diagnostics(err: &PipelineError) -> Vec<Diagnostic> {
+    match err {
+        PipelineError::Multiple(errs) => errs.iter().flat_map(diagnostics).collect(),
+        err => vec![Diagnostic::from(err)],
+    }
+}
+
+impl From<&PipelineError> for Diagnostic {
+    fn from(err: &PipelineError) -> Diagnostic {
+        let mut diagnostic = Diagnostic::error(format!("{}", err));
+        if let Some(span) = err.span() {
+            diagnostic = diagnostic.with_primary(span, String::new())
+        }
+        if let PipelineError::NewTypeError(err) = err {
+            for (span, reason) in err.reasons() {
+                diagnostic = diagnostic.with_secondary(span, reason)
+            }
+        }
+        diagnostic
+    }
+}
+
+impl From<&TypeWarning> for Diagnostic {
+    fn from(warning: &TypeWarning) -> Diagnostic {
+        Diagnostic::warning(format!("{}", warning)).with_primary(warning.span(), String::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::{run_file, Backend};
+    use pretty_assertions::assert_eq;
+
+    fn diagnose(source: &str) -> (Files, Diagnostic) {
+        let mut files = Files::new();
+        let file = files.add("test.siml", source);
+        let err = run_file(file, source, Backend::Term).unwrap_err();
+        (files, Diagnostic::from(&err))
+    }
+
+    #[test]
+    fn render_annotation_mismatch() {
+        let (files, diagnostic) = diagnose("let main : Int =\n  true;");
+        assert_eq!(
+            diagnostic.render(&files),
+            "error: Failed to unify Bool with Int
+ --> test.siml:2:3
+  |
+1 | let main : Int =
+  |            --- expected `Int` because of this annotation
+2 |   true;
+  |   ^^^^
+"
+        );
+    }
+
+    #[test]
+    fn render_after_tabs() {
+        let (files, diagnostic) = diagnose("let main : Int =\n\tadd 1 true;");
+        assert_eq!(
+            diagnostic.render(&files).lines().last().unwrap(),
+            "  | \t      ^^^^"
+        );
+    }
+
+    #[test]
+    fn json_labels() {
+        let (files, diagnostic) = diagnose("let main : Int =\n  true;");
+        let json = diagnostic.to_json(&files);
+        assert_eq!(json["severity"], "error");
+        assert_eq!(json["labels"][0]["primary"], true);
+        assert_eq!(json["labels"][0]["file"], "test.siml");
+        assert_eq!(json["labels"][0]["start"]["line"], 2);
+        assert_eq!(json["labels"][0]["start"]["column"], 3);
+        assert_eq!(json["labels"][1]["primary"], false);
+        assert_eq!(json["labels"][1]["start"]["line"], 1);
+    }
+}
@@ -1,5 +1,7 @@
 use crate::bi_types::Type;
 use crate::pretty::render_doc_width;
+use crate::primitives;
+use crate::span::Span;
 use crate::types;
 use pretty::{BoxDoc, Doc};
 use std::collections::HashSet;
@@ -9,12 +11,85 @@ use std::fmt;
 pub enum Declaration<B> {
     Value(ValueDeclaration<B>),
     Type(TypeDeclaration),
+    /// A declaration we failed to parse. The parser reports the actual
+    /// error, later phases skip these.
+    Error(Span),
+}
+
+impl<B: HasIdent> Declaration<B> {
+    pub fn to_doc(&self) -> Doc<BoxDoc<()>> {
+        match self {
+            Declaration::Value(vd) => doc_comment(&vd.doc).append(
+                Doc::text("let")
+                    .append(Doc::space())
+                    .append(Doc::text(&vd.name))
+                    .append(Doc::space())
+                    .append(Doc::text("="))
+                    .group()
+                    .append(Doc::space().append(vd.expr.to_doc()).nest(2))
+                    .append(Doc::text(";"))
+                    .group(),
+            ),
+            Declaration::Type(td) => {
+                let arguments = if td.arguments.is_empty() {
+                    Doc::nil()
+                } else {
+                    Doc::text("<")
+                        .append(Doc::intersperse(
+                            td.arguments.iter().map(Doc::text),
+                            Doc::text(",").append(Doc::space()),
+                        ))
+                        .append(Doc::text(">"))
+                };
+                doc_comment(&td.doc).append(
+                    Doc::text("type")
+                        .append(Doc::space())
+                        .append(Doc::text(&td.name))
+                        .append(arguments)
+                        .append(Doc::space())
+                        .append(Doc::text("{"))
+                        .group()
+                        .append(
+                            Doc::space()
+                                .append(Doc::intersperse(
+                                    td.constructors.iter().map(|dtor| dtor.to_doc().group()),
+                                    Doc::text(",").append(Doc::space()),
+                                ))
+                                .nest(2),
+                        )
+                        .append(Doc::space())
+                        .append(Doc::text("}"))
+                        .group(),
+                )
+            }
+            Declaration::Error(span) => Doc::text(format!("-- failed to parse {}", span)),
+        }
+    }
+}
+
+impl<B: HasIdent> fmt::Display for Declaration<B> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", render_doc_width(self.to_doc(), 80))
+    }
+}
+
+fn doc_comment(doc: &Option<String>) -> Doc<BoxDoc<()>> {
+    match doc {
+        None => Doc::nil(),
+        Some(doc) => Doc::concat(
+            doc.lines()
+                .map(|line| Doc::text(format!("--| {}", line)).append(Doc::newline())),
+        ),
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct ValueDeclaration<B> {
     pub name: String,
     pub expr: Expr<B>,
+    pub span: Span,
+    /// The `--|` comments right above the declaration
+    pub doc: Option<String>,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -22,12 +97,16 @@ pub struct TypeDeclaration {
     pub name: String,
     pub arguments: Vec<String>,
     pub constructors: Vec<DataConstructor>,
+    pub span: Span,
+    /// The `--|` comments right above the declaration
+    pub doc: Option<String>,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct DataConstructor {
     pub name: String,
     pub fields: Vec<Type>,
+    pub span: Span,
 }
 
 impl DataConstructor {
@@ -135,9 +214,9 @@ impl HasIdent for NewVar {
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Case<B> {
-    pub data_constructor: Dtor,
-    pub binders: Vec<B>,
+    pub pattern: Pattern<B>,
     pub expr: Expr<B>,
+    pub span: Span,
 }
 
 impl<B> Case<B> {
@@ -146,18 +225,28 @@ impl<B> Case<B> {
         F: Fn(B) -> A,
     {
         Case {
-            data_constructor: self.data_constructor,
-            binders: self.binders.into_iter().map(|binder| f(binder)).collect(),
+            pattern: self.pattern.map(f),
             expr: self.expr.map(f),
+            span: self.span,
         }
     }
 
+    fn binds(&self, var: &str) -> bool
+    where
+        B: HasIdent,
+    {
+        self.pattern
+            .binders()
+            .iter()
+            .any(|binder| var == binder.ident())
+    }
+
     pub fn free_vars(&self) -> HashSet<String>
     where
         B: HasIdent,
     {
         let mut res = self.expr.free_vars();
-        for binder in self.binders.iter() {
+        for binder in self.pattern.binders() {
             res.remove(&binder.ident());
         }
         res
@@ -167,7 +256,7 @@ impl<B> Case<B> {
     where
         B: HasIdent + Clone,
     {
-        if !self.binders.iter().any(|binder| var == binder.ident()) {
+        if !self.binds(var) {
             self.expr.subst_mut(var, replacement)
         }
     }
@@ -176,20 +265,12 @@ impl<B> Case<B> {
     where
         B: HasIdent,
     {
-        Doc::text(&self.data_constructor.ty)
-            .append(Doc::text("::"))
-            .append(Doc::text(&self.data_constructor.name))
-            .group()
-            .append(Doc::text("("))
-            .append(Doc::intersperse(
-                self.binders.iter().map(|x| Doc::text(x.ident())),
-                Doc::space(),
-            ))
-            .append(Doc::text(")"))
+        self.pattern
+            .to_doc()
             .append(Doc::space())
             .append(Doc::text("=>"))
-            .append(Doc::space())
-            .append(self.expr.to_doc())
+            .group()
+            .append(Doc::space().append(self.expr.to_doc()).nest(2))
             .group()
     }
 }
@@ -199,16 +280,146 @@ impl<B> Case<B> {
     where
         B: HasIdent + Clone,
     {
-        if !self.binders.iter().any(|binder| var == binder.ident()) {
+        if !self.binds(var) {
             self.expr.subst_var_mut(var, replacement)
         }
     }
 }
 
+/// The left hand side of a case
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Pattern<B> {
+    /// `_`, matches anything
+    Wildcard(Span),
+    /// Matches anything and binds it to a name
+    Var(B, Span),
+    Literal(Literal, Span),
+    /// `Type::Constructor(pattern, ...)`
+    Constructor {
+        dtor: Dtor,
+        fields: Vec<Pattern<B>>,
+        span: Span,
+    },
+    Tuple(Box<Pattern<B>>, Box<Pattern<B>>, Span),
+    /// `name @ pattern`, matches `pattern` and binds the whole value to `name`
+    As {
+        binder: B,
+        pattern: Box<Pattern<B>>,
+        span: Span,
+    },
+}
+
+impl<B> Pattern<B> {
+    pub fn map<A: Sized, F>(self, f: &F) -> Pattern<A>
+    where
+        F: Fn(B) -> A,
+    {
+        match self {
+            Pattern::Wildcard(span) => Pattern::Wildcard(span),
+            Pattern::Var(binder, span) => Pattern::Var(f(binder), span),
+            Pattern::Literal(lit, span) => Pattern::Literal(lit, span),
+            Pattern::Constructor { dtor, fields, span } => Pattern::Constructor {
+                dtor,
+                fields: fields.into_iter().map(|field| field.map(f)).collect(),
+                span,
+            },
+            Pattern::Tuple(fst, snd, span) => {
+                Pattern::Tuple(Box::new(fst.map(f)), Box::new(snd.map(f)), span)
+            }
+            Pattern::As {
+                binder,
+                pattern,
+                span,
+            } => Pattern::As {
+                binder: f(binder),
+                pattern: Box::new(pattern.map(f)),
+                span,
+            },
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        match self {
+            Pattern::Wildcard(span)
+            | Pattern::Var(_, span)
+            | Pattern::Literal(_, span)
+            | Pattern::Constructor { span, .. }
+            | Pattern::Tuple(_, _, span)
+            | Pattern::As { span, .. } => *span,
+        }
+    }
+
+    /// All the variables this pattern binds, from left to right
+    pub fn binders(&self) -> Vec<&B> {
+        self.binder_spans()
+            .into_iter()
+            .map(|(binder, _)| binder)
+            .collect()
+    }
+
+    /// Like `binders`, together with the span of the pattern binding them
+    pub fn binder_spans(&self) -> Vec<(&B, Span)> {
+        match self {
+            Pattern::Wildcard(_) | Pattern::Literal(_, _) => vec![],
+            Pattern::Var(binder, span) => vec![(binder, *span)],
+            Pattern::Constructor { fields, .. } => fields
+                .iter()
+                .flat_map(|field| field.binder_spans())
+                .collect(),
+            Pattern::Tuple(fst, snd, _) => {
+                let mut res = fst.binder_spans();
+                res.extend(snd.binder_spans());
+                res
+            }
+            Pattern::As {
+                binder,
+                pattern,
+                span,
+            } => {
+                let mut res = vec![(binder, *span)];
+                res.extend(pattern.binder_spans());
+                res
+            }
+        }
+    }
+
+    pub fn to_doc(&self) -> Doc<BoxDoc<()>>
+    where
+        B: HasIdent,
+    {
+        match self {
+            Pattern::Wildcard(_) => Doc::text("_"),
+            Pattern::Var(binder, _) => Doc::text(binder.ident()),
+            Pattern::Literal(lit, _) => lit.to_doc(),
+            Pattern::Constructor { dtor, fields, .. } => Doc::text(dtor.to_string())
+                .append(Doc::text("("))
+                .append(Doc::intersperse(
+                    fields.iter().map(|field| field.to_doc()),
+                    Doc::text(",").append(Doc::space()),
+                ))
+                .append(Doc::text(")"))
+                .group(),
+            Pattern::Tuple(fst, snd, _) => Doc::text("(")
+                .append(fst.to_doc())
+                .append(Doc::text(","))
+                .append(Doc::space())
+                .append(snd.to_doc())
+                .append(Doc::text(")"))
+                .group(),
+            Pattern::As {
+                binder, pattern, ..
+            } => Doc::text(binder.ident())
+                .append(Doc::text("@"))
+                .append(pattern.to_doc()),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Dtor {
     pub ty: String,
     pub name: String,
+    pub span: Span,
 }
 
 impl fmt::Display for Dtor {
@@ -219,41 +430,57 @@ impl fmt::Display for Dtor {
 
 /// The AST for expressions. It's parameterized over its variable
 /// names. This is done so the type checker can insert type
-/// information on every variable.
+/// information on every variable. Every node remembers the source
+/// range it was parsed from.
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Expr<B> {
     App {
         func: Box<Expr<B>>,
         arg: Box<Expr<B>>,
+        span: Span,
     },
     Lambda {
         binder: B,
         body: Box<Expr<B>>,
+        span: Span,
     },
     Let {
         binder: B,
         expr: Box<Expr<B>>,
         body: Box<Expr<B>>,
+        span: Span,
     },
     LetRec {
         binder: B,
         expr: Box<Expr<B>>,
         body: Box<Expr<B>>,
+        span: Span,
     },
-    Var(B),
-    Literal(Literal),
-    Tuple(Box<Expr<B>>, Box<Expr<B>>),
+    Var(B, Span),
+    Literal(Literal, Span),
+    Tuple(Box<Expr<B>>, Box<Expr<B>>, Span),
     Construction {
         dtor: Dtor,
         args: Vec<Expr<B>>,
+        span: Span,
     },
     Match {
         expr: Box<Expr<B>>,
         cases: Vec<Case<B>>,
+        span: Span,
+    },
+    If {
+        condition: Box<Expr<B>>,
+        then_branch: Box<Expr<B>>,
+        else_branch: Box<Expr<B>>,
+        span: Span,
     },
     Ann {
         expr: Box<Expr<B>>,
         ty: Type,
+        /// The source range of the annotated type
+        ty_span: Span,
+        span: Span,
     },
 }
 
@@ -261,6 +488,107 @@ pub type ParserExpr = Expr<String>;
 pub type TypedExpr = Expr<Var>;
 pub type NewTypedExpr = Expr<NewVar>;
 
+/// Infix operators. They only exist in the parser, which desugars them into
+/// applications right away.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Eq,
+    Neq,
+    Lt,
+    Lte,
+    Gt,
+    Gte,
+    And,
+    Or,
+    /// `f >> g` runs `f` first, then `g`
+    ComposeRight,
+    /// `f << g` runs `g` first, then `f`
+    ComposeLeft,
+    /// `x |> f` applies `f` to `x`
+    Pipe,
+}
+
+impl BinOp {
+    /// The primitive implementing the operator, if there is one
+    pub fn primitive(self) -> Option<&'static str> {
+        match self {
+            BinOp::Add => Some("add"),
+            BinOp::Sub => Some("sub"),
+            BinOp::Mul => Some("mul"),
+            BinOp::Div => Some("div"),
+            BinOp::Mod => Some("mod"),
+            BinOp::Eq => Some("eq"),
+            BinOp::Neq => Some("neq"),
+            BinOp::Lt => Some("lt"),
+            BinOp::Lte => Some("lte"),
+            BinOp::Gt => Some("gt"),
+            BinOp::Gte => Some("gte"),
+            BinOp::And | BinOp::Or | BinOp::ComposeRight | BinOp::ComposeLeft | BinOp::Pipe => None,
+        }
+    }
+}
+
+impl ParserExpr {
+    /// Desugars an infix operator application. Operators refer to primitives
+    /// by their qualified names, so they keep working when a program
+    /// shadows, say, `add`.
+    pub fn binary(op: BinOp, op_span: Span, lhs: ParserExpr, rhs: ParserExpr) -> ParserExpr {
+        let span = lhs.span().merge(rhs.span());
+        match op {
+            BinOp::Pipe => Expr::App {
+                func: Box::new(rhs),
+                arg: Box::new(lhs),
+                span,
+            },
+            BinOp::ComposeRight | BinOp::ComposeLeft => {
+                let (first, second) = if op == BinOp::ComposeRight {
+                    (lhs, rhs)
+                } else {
+                    (rhs, lhs)
+                };
+                // Identifiers can't start with a `$`, so this binder can't
+                // capture any variables in `first` or `second`
+                let binder = "$composed".to_string();
+                let arg = Expr::Var(binder.clone(), op_span);
+                Expr::Lambda {
+                    binder,
+                    body: Box::new(Expr::app(second, Expr::app(first, arg))),
+                    span,
+                }
+            }
+            // `&&` and `||` only evaluate their right hand side when they
+            // have to, so it can rely on the left hand side, as in
+            // `y != 0 && x / y > 1`
+            BinOp::And => Expr::If {
+                condition: Box::new(lhs),
+                then_branch: Box::new(rhs),
+                else_branch: Box::new(Expr::bool(false, op_span)),
+                span,
+            },
+            BinOp::Or => Expr::If {
+                condition: Box::new(lhs),
+                then_branch: Box::new(Expr::bool(true, op_span)),
+                else_branch: Box::new(rhs),
+                span,
+            },
+            op => {
+                let prim = primitives::qualified(op.primitive().unwrap());
+                let func = Expr::Var(prim, op_span);
+                Expr::App {
+                    func: Box::new(Expr::app(func, lhs)),
+                    arg: Box::new(rhs),
+                    span,
+                }
+            }
+        }
+    }
+}
+
 impl<B: HasIdent> fmt::Display for Expr<B> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", render_doc_width(self.to_doc(), 60))
@@ -273,42 +601,94 @@ impl<B> Expr<B> {
         F: Fn(B) -> A,
     {
         match self {
-            Expr::Var(v) => Expr::Var(f(v)),
-            Expr::Lambda { binder, body } => Expr::Lambda {
+            Expr::Var(v, span) => Expr::Var(f(v), span),
+            Expr::Lambda { binder, body, span } => Expr::Lambda {
                 binder: f(binder),
                 body: Box::new(body.map(f)),
+                span,
             },
-            Expr::Let { binder, expr, body } => Expr::Let {
+            Expr::Let {
+                binder,
+                expr,
+                body,
+                span,
+            } => Expr::Let {
                 binder: f(binder),
                 expr: Box::new(expr.map(f)),
                 body: Box::new(body.map(f)),
+                span,
             },
-            Expr::LetRec { binder, expr, body } => Expr::LetRec {
+            Expr::LetRec {
+                binder,
+                expr,
+                body,
+                span,
+            } => Expr::LetRec {
                 binder: f(binder),
                 expr: Box::new(expr.map(f)),
                 body: Box::new(body.map(f)),
+                span,
             },
-            Expr::App { func, arg } => Expr::App {
+            Expr::App { func, arg, span } => Expr::App {
                 func: Box::new(func.map(f)),
                 arg: Box::new(arg.map(f)),
+                span,
             },
-            Expr::Ann { ty, expr } => Expr::Ann {
+            Expr::Ann {
+                ty,
+                expr,
+                ty_span,
+                span,
+            } => Expr::Ann {
                 ty,
                 expr: Box::new(expr.map(f)),
+                ty_span,
+                span,
             },
-            Expr::Literal(lit) => Expr::Literal(lit),
-            Expr::Tuple(fst, snd) => Expr::Tuple(Box::new(fst.map(f)), Box::new(snd.map(f))),
-            Expr::Construction { dtor, args } => Expr::Construction {
+            Expr::Literal(lit, span) => Expr::Literal(lit, span),
+            Expr::Tuple(fst, snd, span) => {
+                Expr::Tuple(Box::new(fst.map(f)), Box::new(snd.map(f)), span)
+            }
+            Expr::Construction { dtor, args, span } => Expr::Construction {
                 dtor,
                 args: args.into_iter().map(|e| e.map(f)).collect(),
+                span,
             },
-            Expr::Match { expr, cases } => Expr::Match {
+            Expr::Match { expr, cases, span } => Expr::Match {
                 expr: Box::new(expr.map(f)),
                 cases: cases.into_iter().map(|case| case.map(f)).collect(),
+                span,
+            },
+            Expr::If {
+                condition,
+                then_branch,
+                else_branch,
+                span,
+            } => Expr::If {
+                condition: Box::new(condition.map(f)),
+                then_branch: Box::new(then_branch.map(f)),
+                else_branch: Box::new(else_branch.map(f)),
+                span,
             },
         }
     }
 
+    pub fn span(&self) -> Span {
+        match self {
+            Expr::App { span, .. }
+            | Expr::Lambda { span, .. }
+            | Expr::Let { span, .. }
+            | Expr::LetRec { span, .. }
+            | Expr::Var(_, span)
+            | Expr::Literal(_, span)
+            | Expr::Tuple(_, _, span)
+            | Expr::Construction { span, .. }
+            | Expr::Match { span, .. }
+            | Expr::If { span, .. }
+            | Expr::Ann { span, .. } => *span,
+        }
+    }
+
     pub fn to_doc(&self) -> Doc<BoxDoc<()>>
     where
         B: HasIdent,
@@ -334,7 +714,9 @@ impl<B> Expr<B> {
                     inner
                 }
             }
-            Expr::Let { binder, expr, body } => {
+            Expr::Let {
+                binder, expr, body, ..
+            } => {
                 let inner = Doc::text("let")
                     .append(Doc::space())
                     .append(
@@ -362,7 +744,9 @@ impl<B> Expr<B> {
                     inner
                 }
             }
-            Expr::LetRec { binder, expr, body } => {
+            Expr::LetRec {
+                binder, expr, body, ..
+            } => {
                 let inner = Doc::text("letrec")
                     .append(Doc::space())
                     .append(
@@ -390,16 +774,16 @@ impl<B> Expr<B> {
                     inner
                 }
             }
-            Expr::Literal(lit) => lit.to_doc(),
-            Expr::Lambda { binder, body } => Doc::text("(\\")
+            Expr::Literal(lit, _) => lit.to_doc(),
+            Expr::Lambda { binder, body, .. } => Doc::text("(\\")
                 .append(Doc::text(binder.ident_with_ty()))
                 .append(Doc::text("."))
                 .append(Doc::space())
                 .append(body.to_doc().nest(2))
                 .append(Doc::text(")"))
                 .group(),
-            Expr::Var(v) => Doc::text(v.ident()),
-            Expr::Ann { expr, ty } => Doc::text("(")
+            Expr::Var(v, _) => Doc::text(v.ident()),
+            Expr::Ann { expr, ty, .. } => Doc::text("(")
                 .append(expr.to_doc())
                 .append(Doc::space())
                 .append(
@@ -411,7 +795,7 @@ impl<B> Expr<B> {
                 )
                 .append(Doc::text(")"))
                 .group(),
-            Expr::Construction { dtor, args } => Doc::text(&dtor.ty)
+            Expr::Construction { dtor, args, .. } => Doc::text(&dtor.ty)
                 .append(Doc::text("::"))
                 .append(Doc::text(&dtor.name))
                 .append(Doc::text("("))
@@ -421,16 +805,47 @@ impl<B> Expr<B> {
                 ))
                 .append(Doc::text(")"))
                 .group(),
-            Expr::Match { expr, cases } => Doc::text("match")
+            Expr::Match { expr, cases, .. } => Doc::text("match")
                 .append(Doc::space())
                 .append(expr.to_doc())
+                .append(Doc::space())
                 .append(Doc::text("{"))
-                .append(Doc::intersperse(
-                    cases.iter().map(|case| case.to_doc()),
-                    Doc::text(","),
-                ))
+                .group()
+                .append(
+                    Doc::newline()
+                        .append(Doc::intersperse(
+                            cases.iter().map(|case| case.to_doc()),
+                            Doc::text(",").append(Doc::newline()),
+                        ))
+                        .nest(2),
+                )
+                .append(Doc::text(","))
+                .append(Doc::newline())
                 .append(Doc::text("}")),
-            Expr::Tuple(fst, snd) => Doc::text("(")
+            Expr::If {
+                condition,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                let inner = Doc::text("if")
+                    .append(Doc::space())
+                    .append(condition.to_doc())
+                    .append(Doc::space())
+                    .append(Doc::text("then"))
+                    .group()
+                    .append(Doc::space().append(then_branch.to_doc()).nest(2))
+                    .append(Doc::space())
+                    .append(Doc::text("else"))
+                    .append(Doc::space().append(else_branch.to_doc_inner(0)).nest(2))
+                    .group();
+                if depth > 0 {
+                    Doc::text("(").append(inner).append(Doc::text(")")).group()
+                } else {
+                    inner
+                }
+            }
+            Expr::Tuple(fst, snd, _) => Doc::text("(")
                 .append(fst.to_doc())
                 .append(Doc::text(","))
                 .append(Doc::space())
@@ -465,7 +880,7 @@ impl<B> Expr<B> {
         B: HasIdent + Clone,
     {
         match self {
-            Expr::Var(v) => {
+            Expr::Var(v, _) => {
                 if var == v.ident() {
                     *self = replacement.clone();
                 }
@@ -473,28 +888,32 @@ impl<B> Expr<B> {
             Expr::Ann { expr, .. } => {
                 expr.subst_mut(var, replacement);
             }
-            Expr::Lambda { binder, body } => {
+            Expr::Lambda { binder, body, .. } => {
                 if var != binder.ident() {
                     body.subst_mut(var, replacement);
                 }
             }
-            Expr::Let { binder, expr, body } => {
+            Expr::Let {
+                binder, expr, body, ..
+            } => {
                 expr.subst_mut(var, replacement);
                 if var != binder.ident() {
                     body.subst_mut(var, replacement);
                 }
             }
-            Expr::LetRec { binder, expr, body } => {
+            Expr::LetRec {
+                binder, expr, body, ..
+            } => {
                 if var != binder.ident() {
                     expr.subst_mut(var, replacement);
                     body.subst_mut(var, replacement);
                 }
             }
-            Expr::App { func, arg } => {
+            Expr::App { func, arg, .. } => {
                 func.subst_mut(var, replacement);
                 arg.subst_mut(var, replacement);
             }
-            Expr::Tuple(fst, snd) => {
+            Expr::Tuple(fst, snd, _) => {
                 fst.subst_mut(var, replacement);
                 snd.subst_mut(var, replacement);
             }
@@ -503,13 +922,23 @@ impl<B> Expr<B> {
                     arg.subst_mut(var, replacement);
                 }
             }
-            Expr::Match { expr, cases } => {
+            Expr::Match { expr, cases, .. } => {
                 expr.subst_mut(var, replacement);
                 for case in cases {
                     case.subst_mut(var, replacement);
                 }
             }
-            Expr::Literal(_) => {}
+            Expr::If {
+                condition,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                condition.subst_mut(var, replacement);
+                then_branch.subst_mut(var, replacement);
+                else_branch.subst_mut(var, replacement);
+            }
+            Expr::Literal(..) => {}
         }
     }
 
@@ -518,7 +947,7 @@ impl<B> Expr<B> {
         let mut args = vec![];
         loop {
             match bod {
-                Expr::Lambda { binder, body } => {
+                Expr::Lambda { binder, body, .. } => {
                     args.push(binder);
                     bod = *body;
                 }
@@ -533,7 +962,7 @@ impl<B> Expr<B> {
 
     fn unfold_apps(&self) -> Vec<&Self> {
         match self {
-            Expr::App { func, arg } => {
+            Expr::App { func, arg, .. } => {
                 let mut res = func.unfold_apps();
                 res.push(arg);
                 res
@@ -544,7 +973,7 @@ impl<B> Expr<B> {
 
     pub fn unfold_applications(self) -> Vec<Self> {
         match self {
-            Expr::App { func, arg } => {
+            Expr::App { func, arg, .. } => {
                 let mut res = func.unfold_applications();
                 res.push(*arg);
                 res
@@ -558,33 +987,39 @@ impl<B> Expr<B> {
         B: HasIdent,
     {
         match self {
-            Expr::Var(s) => {
+            Expr::Var(s, _) => {
                 let mut res = HashSet::new();
                 res.insert(s.ident());
                 res
             }
-            Expr::Lambda { binder, body } => {
+            Expr::Lambda { binder, body, .. } => {
                 let mut res = body.free_vars();
                 res.remove(&binder.ident());
                 res
             }
-            Expr::Let { binder, expr, body } => {
+            Expr::Let {
+                binder, expr, body, ..
+            } => {
                 let mut res = expr.free_vars();
                 let mut body_vars = body.free_vars();
                 body_vars.remove(&binder.ident());
                 res.extend(body_vars);
                 res
             }
-            Expr::LetRec { binder, expr, body } => {
+            Expr::LetRec {
+                binder, expr, body, ..
+            } => {
                 let mut res = expr.free_vars();
                 let body_vars = body.free_vars();
                 res.extend(body_vars);
                 res.remove(&binder.ident());
                 res
             }
-            Expr::App { func, arg } => func.free_vars().union(&arg.free_vars()).cloned().collect(),
-            Expr::Tuple(fst, snd) => fst.free_vars().union(&snd.free_vars()).cloned().collect(),
-            Expr::Literal(_) => HashSet::new(),
+            Expr::App { func, arg, .. } => {
+                func.free_vars().union(&arg.free_vars()).cloned().collect()
+            }
+            Expr::Tuple(fst, snd, _) => fst.free_vars().union(&snd.free_vars()).cloned().collect(),
+            Expr::Literal(..) => HashSet::new(),
             Expr::Ann { expr, .. } => expr.free_vars(),
             Expr::Construction { args, .. } => {
                 let mut res = HashSet::new();
@@ -593,33 +1028,46 @@ impl<B> Expr<B> {
                 }
                 res
             }
-            Expr::Match { expr, cases } => {
+            Expr::Match { expr, cases, .. } => {
                 let mut res = expr.free_vars();
                 for case in cases {
                     res.extend(case.free_vars())
                 }
                 res
             }
+            Expr::If {
+                condition,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                let mut res = condition.free_vars();
+                res.extend(then_branch.free_vars());
+                res.extend(else_branch.free_vars());
+                res
+            }
         }
     }
 
     pub fn app(fun: Expr<B>, arg: Expr<B>) -> Self {
+        let span = fun.span().merge(arg.span());
         Expr::App {
             func: Box::new(fun),
             arg: Box::new(arg),
+            span,
         }
     }
 
-    pub fn int(i: i32) -> Self {
-        Expr::Literal(Literal::Int(i))
+    pub fn int(i: i32, span: Span) -> Self {
+        Expr::Literal(Literal::Int(i), span)
     }
 
-    pub fn bool(b: bool) -> Self {
-        Expr::Literal(Literal::Bool(b))
+    pub fn bool(b: bool, span: Span) -> Self {
+        Expr::Literal(Literal::Bool(b), span)
     }
 
-    pub fn tuple(fst: Expr<B>, snd: Expr<B>) -> Self {
-        Expr::Tuple(Box::new(fst), Box::new(snd))
+    pub fn tuple(fst: Expr<B>, snd: Expr<B>, span: Span) -> Self {
+        Expr::Tuple(Box::new(fst), Box::new(snd), span)
     }
 }
 
@@ -657,36 +1105,40 @@ impl<B> Expr<B> {
         B: HasIdent + Clone,
     {
         match self {
-            Expr::Var(v) => {
+            Expr::Var(v, span) => {
                 if var == &v.ident() {
-                    *self = Expr::Var(v.clone().set_ident(replacement.to_string()))
+                    *self = Expr::Var(v.clone().set_ident(replacement.to_string()), *span)
                 }
             }
             Expr::Ann { expr, .. } => {
                 expr.subst_var_mut(var, replacement);
             }
-            Expr::Lambda { binder, body } => {
+            Expr::Lambda { binder, body, .. } => {
                 if var != &binder.ident() {
                     body.subst_var_mut(var, replacement);
                 }
             }
-            Expr::Let { binder, expr, body } => {
+            Expr::Let {
+                binder, expr, body, ..
+            } => {
                 expr.subst_var_mut(var, replacement);
                 if var != &binder.ident() {
                     body.subst_var_mut(var, replacement);
                 }
             }
-            Expr::LetRec { binder, expr, body } => {
+            Expr::LetRec {
+                binder, expr, body, ..
+            } => {
                 if var != &binder.ident() {
                     expr.subst_var_mut(var, replacement);
                     body.subst_var_mut(var, replacement);
                 }
             }
-            Expr::App { func, arg } => {
+            Expr::App { func, arg, .. } => {
                 func.subst_var_mut(&var, replacement);
                 arg.subst_var_mut(var, replacement);
             }
-            Expr::Tuple(fst, snd) => {
+            Expr::Tuple(fst, snd, _) => {
                 fst.subst_var_mut(&var, replacement);
                 snd.subst_var_mut(var, replacement);
             }
@@ -695,13 +1147,23 @@ impl<B> Expr<B> {
                     arg.subst_var_mut(var, replacement);
                 }
             }
-            Expr::Match { expr, cases } => {
+            Expr::Match { expr, cases, .. } => {
                 expr.subst_var_mut(&var, replacement);
                 for case in cases {
                     case.subst_var_mut(var, replacement);
                 }
             }
-            Expr::Literal(_) => {}
+            Expr::If {
+                condition,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                condition.subst_var_mut(var, replacement);
+                then_branch.subst_var_mut(var, replacement);
+                else_branch.subst_var_mut(var, replacement);
+            }
+            Expr::Literal(..) => {}
         }
     }
 }
@@ -0,0 +1,893 @@
+//! Prints programs back as source code, in a canonical layout. Unlike the
+//! `to_doc` methods on the AST, which are meant for debugging, the output
+//! parses back to the same program. Infix operators, which the parser
+//! desugars into other expressions, get turned back into operators, and
+//! comments are put back close to where they were.
+use crate::bi_types::Type;
+use crate::expr::{
+    BinOp, DataConstructor, Declaration, Expr, Literal, Pattern, TypeDeclaration, ValueDeclaration,
+};
+use crate::pipeline::{self, PipelineError};
+use crate::pretty::render_doc_width;
+use crate::primitives;
+use crate::span::{FileId, Span};
+use crate::token::{self, Comment};
+use pretty::{BoxDoc, Doc};
+
+pub const DEFAULT_WIDTH: usize = 80;
+
+type Document = Doc<'static, BoxDoc<'static, ()>>;
+
+// How tightly expressions bind, from loosest to tightest. An expression
+// needs parens when it's printed somewhere that expects a tighter one.
+
+/// Lambdas, lets, matches and ifs extend as far to the right as they can
+const OPEN: u8 = 0;
+const PIPE: u8 = 1;
+const OR: u8 = 2;
+const AND: u8 = 3;
+/// Comparisons don't associate, so neither of their operands can be one
+const COMPARE: u8 = 4;
+const ADD: u8 = 5;
+const MUL: u8 = 6;
+const COMPOSE: u8 = 7;
+const APP: u8 = 8;
+const ATOM: u8 = 9;
+
+/// The operators that desugar into primitive calls, and how tightly they bind
+const OPERATORS: &[(BinOp, &str, u8)] = &[
+    (BinOp::Eq, "==", COMPARE),
+    (BinOp::Neq, "!=", COMPARE),
+    (BinOp::Lt, "<", COMPARE),
+    (BinOp::Lte, "<=", COMPARE),
+    (BinOp::Gt, ">", COMPARE),
+    (BinOp::Gte, ">=", COMPARE),
+    (BinOp::Add, "+", ADD),
+    (BinOp::Sub, "-", ADD),
+    (BinOp::Mul, "*", MUL),
+    (BinOp::Div, "/", MUL),
+    (BinOp::Mod, "%", MUL),
+];
+
+/// The binder `Expr::binary` introduces when desugaring `>>` and `<<`
+const COMPOSED: &str = "$composed";
+
+/// Formats a program, so its lines are at most `width` characters long
+/// wherever that's possible. Fails if the program doesn't parse.
+pub fn format_program(file: FileId, input: &str, width: usize) -> Result<String, PipelineError> {
+    let decls = pipeline::parse_program(file, input)?;
+    let mut formatter = Formatter {
+        source: input,
+        comments: token::comments(input),
+        next_comment: 0,
+        width,
+    };
+    Ok(formatter.program(&decls))
+}
+
+/// An infix operator application, recovered from its desugaring
+struct Operator<'e> {
+    symbol: &'static str,
+    level: u8,
+    lhs: &'e Expr<String>,
+    rhs: &'e Expr<String>,
+}
+
+fn operator(expr: &Expr<String>) -> Option<Operator<'_>> {
+    match expr {
+        Expr::App { func, arg, .. } => {
+            if let Expr::App {
+                func: op, arg: lhs, ..
+            } = &**func
+            {
+                if let Expr::Var(name, _) = &**op {
+                    let found = OPERATORS.iter().find(|(op, _, _)| {
+                        op.primitive().map(primitives::qualified).as_ref() == Some(name)
+                    });
+                    if let Some((_, symbol, level)) = found {
+                        return Some(Operator {
+                            symbol,
+                            level: *level,
+                            lhs,
+                            rhs: arg,
+                        });
+                    }
+                }
+            }
+            // `x |> f` is the only way to end up with an argument in front
+            // of its function
+            if arg.span().start < func.span().start {
+                return Some(Operator {
+                    symbol: "|>",
+                    level: PIPE,
+                    lhs: arg,
+                    rhs: func,
+                });
+            }
+            None
+        }
+        Expr::Lambda { binder, body, .. } if binder == COMPOSED => match &**body {
+            Expr::App {
+                func: second,
+                arg: inner,
+                ..
+            } => match &**inner {
+                Expr::App { func: first, .. } => {
+                    if first.span().start <= second.span().start {
+                        Some(Operator {
+                            symbol: ">>",
+                            level: COMPOSE,
+                            lhs: first,
+                            rhs: second,
+                        })
+                    } else {
+                        Some(Operator {
+                            symbol: "<<",
+                            level: COMPOSE,
+                            lhs: second,
+                            rhs: first,
+                        })
+                    }
+                }
+                _ => None,
+            },
+            _ => None,
+        },
+        // `a && b` and `a || b` become ifs that start where their condition
+        // does, unlike the ones written out. The literal standing in for
+        // the operator comes before the right hand side.
+        Expr::If {
+            condition,
+            then_branch,
+            else_branch,
+            span,
+        } if span.start == condition.span().start => match (&**then_branch, &**else_branch) {
+            (_, Expr::Literal(Literal::Bool(false), op)) if op.start < then_branch.span().start => {
+                Some(Operator {
+                    symbol: "&&",
+                    level: AND,
+                    lhs: condition,
+                    rhs: then_branch,
+                })
+            }
+            (Expr::Literal(Literal::Bool(true), op), _) if op.start < else_branch.span().start => {
+                Some(Operator {
+                    symbol: "||",
+                    level: OR,
+                    lhs: condition,
+                    rhs: else_branch,
+                })
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn level(expr: &Expr<String>) -> u8 {
+    if let Some(op) = operator(expr) {
+        return op.level;
+    }
+    match expr {
+        Expr::Lambda { .. }
+        | Expr::Let { .. }
+        | Expr::LetRec { .. }
+        | Expr::Match { .. }
+        | Expr::If { .. } => OPEN,
+        Expr::App { .. } => APP,
+        _ => ATOM,
+    }
+}
+
+fn parens(doc: Document) -> Document {
+    Doc::text("(").append(doc).append(Doc::text(")"))
+}
+
+fn comma_separated(docs: Vec<Document>) -> Document {
+    Doc::intersperse(docs, Doc::text(",").append(Doc::space()))
+}
+
+struct Formatter<'a> {
+    source: &'a str,
+    comments: Vec<Comment>,
+    /// The first comment we haven't printed yet
+    next_comment: usize,
+    width: usize,
+}
+
+impl<'a> Formatter<'a> {
+    fn program(&mut self, decls: &[Declaration<String>]) -> String {
+        let mut out = String::new();
+        let mut previous_end = None;
+        for decl in decls {
+            let span = declaration_span(decl);
+            if let Some(end) = previous_end {
+                // Declarations the source kept together stay together
+                out.push_str(if has_blank_line(&self.source[end..span.start.offset]) {
+                    "\n\n"
+                } else {
+                    "\n"
+                })
+            }
+            previous_end = Some(span.end.offset);
+            // Comments between declarations get their own lines
+            self.comment_lines(span.start.offset, &mut out);
+            let doc = self.declaration(decl);
+            out.push_str(&render_doc_width(doc, self.width));
+            // Comments after the end of a declaration stay on its line
+            while let Some(comment) = self.comments.get(self.next_comment) {
+                if comment.start.line != span.end.line {
+                    break;
+                }
+                out.push(' ');
+                out.push_str(&comment.text);
+                self.next_comment += 1;
+            }
+        }
+        if self.next_comment < self.comments.len() && !out.is_empty() {
+            out.push_str("\n\n")
+        }
+        self.comment_lines(self.source.len(), &mut out);
+
+        let mut res: String = out
+            .trim_end()
+            .lines()
+            .map(|line| line.trim_end())
+            .collect::<Vec<_>>()
+            .join("\n");
+        res.push('\n');
+        res
+    }
+
+    /// Prints the comments before `offset` on lines of their own. Blank lines
+    /// after a comment are kept, so comments that are set apart from the
+    /// declaration below them stay that way.
+    fn comment_lines(&mut self, offset: usize, out: &mut String) {
+        while let Some(comment) = self.comments.get(self.next_comment) {
+            if comment.start.offset >= offset {
+                break;
+            }
+            out.push_str(&comment.text);
+            out.push('\n');
+            let next = match self.comments.get(self.next_comment + 1) {
+                Some(next) if next.start.offset < offset => next.start.offset,
+                _ => offset,
+            };
+            // The text in between starts with the rest of the comment's line
+            // and ends with the start of the next item's
+            if has_blank_line(&self.source[comment.end.offset..next]) {
+                out.push('\n')
+            }
+            self.next_comment += 1;
+        }
+    }
+
+    /// Takes the comments that start before `offset`
+    fn comments_before(&mut self, offset: usize) -> Vec<Comment> {
+        let mut res = vec![];
+        while let Some(comment) = self.comments.get(self.next_comment) {
+            if comment.start.offset >= offset {
+                break;
+            }
+            res.push(comment.clone());
+            self.next_comment += 1;
+        }
+        res
+    }
+
+    /// The comments before `offset`, to go in front of whatever starts there
+    fn leading_comments(&mut self, offset: usize) -> Document {
+        comments_doc(self.comments_before(offset))
+    }
+
+    /// The comments left before `offset`, after the last thing in a
+    /// declaration. Also returns whether the last one was a line comment.
+    fn trailing_comments(&mut self, offset: usize) -> (Document, bool) {
+        let comments = self.comments_before(offset);
+        let line_comment = comments.last().is_some_and(Comment::is_line_comment);
+        let doc = Doc::concat(
+            comments
+                .into_iter()
+                .map(|comment| Doc::text(" ").append(Doc::text(comment.text))),
+        );
+        (doc, line_comment)
+    }
+
+    /// Whether there's a line comment we haven't printed yet in `span`. A
+    /// group could still put the code that follows the comment on the same
+    /// line as it, so layouts containing one always break.
+    fn has_line_comment(&self, span: Span) -> bool {
+        self.comments[self.next_comment..]
+            .iter()
+            .take_while(|comment| comment.start.offset < span.end.offset)
+            .any(|comment| comment.is_line_comment() && comment.start.offset >= span.start.offset)
+    }
+
+    fn declaration(&mut self, decl: &Declaration<String>) -> Document {
+        match decl {
+            Declaration::Value(vd) => self.value_declaration(vd),
+            Declaration::Type(td) => self.type_declaration(td),
+            // We only format programs that parse, but just in case
+            Declaration::Error(span) => {
+                Doc::text(self.source[span.start.offset..span.end.offset].to_string())
+            }
+        }
+    }
+
+    fn value_declaration(&mut self, vd: &ValueDeclaration<String>) -> Document {
+        // A signature that doesn't fit on its line gets broken up, and then
+        // the body always starts on a line of its own
+        let (header, expr, fits) = match &vd.expr {
+            Expr::Ann { expr, ty, .. } => {
+                let flat = render_doc_width(type_doc(ty, false), isize::MAX as usize);
+                let header = format!("let {} : {} =", vd.name, flat);
+                if header.len() <= self.width {
+                    (Doc::text(header), &**expr, true)
+                } else {
+                    let header = Doc::text(format!("let {} :", vd.name))
+                        .append(Doc::newline().append(type_doc(ty, false)).nest(2))
+                        .append(Doc::text(" ="));
+                    (header, &**expr, false)
+                }
+            }
+            expr => (Doc::text(format!("let {} =", vd.name)), expr, true),
+        };
+        let commented = self.comments[self.next_comment..].iter().any(|comment| {
+            comment.start.offset >= expr.span().end.offset
+                && comment.start.offset < vd.span.end.offset
+        });
+        let end = if commented { "" } else { ";" };
+        let body = if fits {
+            self.body(expr, end)
+        } else {
+            self.broken_body(expr, end)
+        };
+        if !commented {
+            return doc_comment(&vd.doc).append(header).append(body);
+        }
+        let (trailing, line_comment) = self.trailing_comments(vd.span.end.offset);
+        let end = if line_comment {
+            Doc::newline().append(Doc::text(";"))
+        } else {
+            Doc::text(";")
+        };
+        doc_comment(&vd.doc)
+            .append(header)
+            .append(body)
+            .append(trailing.nest(2))
+            .append(end)
+    }
+
+    fn type_declaration(&mut self, td: &TypeDeclaration) -> Document {
+        let broken = self.has_line_comment(td.span);
+        let line = || if broken { Doc::newline() } else { Doc::space() };
+        let arguments = if td.arguments.is_empty() {
+            String::new()
+        } else {
+            format!("<{}>", td.arguments.join(", "))
+        };
+        let header = Doc::text(format!("type {}{} {{", td.name, arguments));
+        let constructors: Vec<Document> = td
+            .constructors
+            .iter()
+            .map(|dc| {
+                self.leading_comments(dc.span.start.offset)
+                    .append(data_constructor(dc))
+            })
+            .collect();
+        let (trailing, _) = self.trailing_comments(td.span.end.offset);
+        let body = if constructors.is_empty() {
+            trailing.append(Doc::space())
+        } else {
+            line()
+                .append(Doc::intersperse(
+                    constructors,
+                    Doc::text(",").append(line()),
+                ))
+                .append(trailing)
+                .nest(2)
+                .append(line())
+        };
+        doc_comment(&td.doc).append(header.append(body).append(Doc::text("}")).group())
+    }
+
+    /// Prints what goes after the `=` of a declaration or binding, the `.` of
+    /// a lambda or the `=>` of a case. Lambdas and matches start on the same
+    /// line, anything else that doesn't fit goes on the next one. `end` is
+    /// whatever closes it, so it counts towards the width.
+    fn body(&mut self, expr: &Expr<String>, end: &'static str) -> Document {
+        let line_comment = self.comments[self.next_comment..]
+            .iter()
+            .take_while(|comment| comment.start.offset < expr.span().start.offset)
+            .any(Comment::is_line_comment);
+        if line_comment || (!hugs(expr) && self.breaks(expr)) {
+            return self.broken_body(expr, end);
+        }
+        let leading = self.leading_comments(expr.span().start.offset);
+        if hugs(expr) {
+            Doc::text(" ")
+                .append(leading)
+                .append(self.expr(expr, OPEN))
+                .append(Doc::text(end))
+        } else {
+            Doc::space()
+                .append(leading)
+                .append(self.expr(expr, OPEN))
+                .append(Doc::text(end))
+                .nest(2)
+                .group()
+        }
+    }
+
+    /// Prints `expr` on the lines after its `=`, `.` or `=>`
+    fn broken_body(&mut self, expr: &Expr<String>, end: &'static str) -> Document {
+        let leading = self.leading_comments(expr.span().start.offset);
+        Doc::newline()
+            .append(leading)
+            .append(self.expr(expr, OPEN))
+            .append(Doc::text(end))
+            .nest(2)
+    }
+
+    /// Whether `expr` is always printed across several lines
+    fn breaks(&self, expr: &Expr<String>) -> bool {
+        self.has_line_comment(expr.span()) || always_breaks(expr)
+    }
+
+    /// Prints `expr` where an expression binding at least as tightly as
+    /// `prec` is expected, adding parens if it doesn't
+    fn expr(&mut self, expr: &Expr<String>, prec: u8) -> Document {
+        let leading = self.leading_comments(expr.span().start.offset);
+        let doc = self.expr_inner(expr);
+        leading.append(if level(expr) < prec { parens(doc) } else { doc })
+    }
+
+    fn expr_inner(&mut self, expr: &Expr<String>) -> Document {
+        if let Some(op) = operator(expr) {
+            return self.infix(op);
+        }
+        match expr {
+            Expr::App { .. } => {
+                let mut args = vec![];
+                let mut head = expr;
+                while let Expr::App { func, arg, .. } = head {
+                    if operator(head).is_some() {
+                        break;
+                    }
+                    args.push(&**arg);
+                    head = func;
+                }
+                let head = self.expr(head, ATOM);
+                let mut args = args.into_iter().rev();
+                // The first argument stays next to the function, the rest
+                // line up under it when they don't fit
+                let first = args.next().map(|arg| self.expr(arg, ATOM));
+                let rest: Vec<Document> = args
+                    .map(|arg| Doc::space().append(self.expr(arg, ATOM)))
+                    .collect();
+                head.append(Doc::text(" "))
+                    .append(Doc::concat(first))
+                    .append(Doc::concat(rest).nest(2))
+                    .group()
+            }
+            Expr::Lambda { binder, body, .. } => {
+                Doc::text(format!("\\{}.", binder)).append(self.body(body, ""))
+            }
+            Expr::Let {
+                binder, expr, body, ..
+            } => self.let_in("let", binder, expr, body),
+            Expr::LetRec {
+                binder, expr, body, ..
+            } => self.let_in("letrec", binder, expr, body),
+            Expr::Var(name, _) => Doc::text(name.clone()),
+            Expr::Literal(lit, _) => Doc::text(lit.print()),
+            Expr::Tuple(fst, snd, _) => {
+                let fst = self.expr(fst, OPEN);
+                let snd = self.expr(snd, OPEN);
+                parens(
+                    fst.append(Doc::text(","))
+                        .append(Doc::space())
+                        .append(snd)
+                        .nest(1),
+                )
+                .group()
+            }
+            Expr::Construction { dtor, args, .. } => {
+                let args: Vec<Document> = args.iter().map(|arg| self.expr(arg, OPEN)).collect();
+                Doc::text(format!("{}(", dtor))
+                    .append(comma_separated(args).nest(2))
+                    .append(Doc::text(")"))
+                    .group()
+            }
+            Expr::Match { expr, cases, span } => {
+                let header = Doc::text("match ")
+                    .append(self.expr(expr, OPEN))
+                    .append(Doc::text(" {"));
+                // Every case gets a line of its own, even if they'd all fit
+                // on one
+                let cases: Vec<Document> = cases
+                    .iter()
+                    .map(|case| {
+                        let leading = self.leading_comments(case.span.start.offset);
+                        Doc::newline()
+                            .append(leading)
+                            .append(pattern_doc(&case.pattern, false))
+                            .append(Doc::text(" =>"))
+                            .append(self.body(&case.expr, ","))
+                    })
+                    .collect();
+                let (trailing, _) = self.trailing_comments(span.end.offset);
+                if cases.is_empty() {
+                    return header.append(trailing).append(Doc::text(" }"));
+                }
+                header
+                    .append(Doc::concat(cases).append(trailing).nest(2))
+                    .append(Doc::newline())
+                    .append(Doc::text("}"))
+            }
+            Expr::If {
+                condition,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                let broken = self.breaks(expr);
+                let line = || if broken { Doc::newline() } else { Doc::space() };
+                let condition = self.expr(condition, OPEN);
+                let then_branch = self.expr(then_branch, OPEN);
+                // `else if` chains stay flat
+                let else_branch = match &**else_branch {
+                    Expr::If { .. } => Doc::text(" ").append(self.expr(else_branch, OPEN)),
+                    _ => line().append(self.expr(else_branch, OPEN)).nest(2),
+                };
+                Doc::text("if ")
+                    .append(condition)
+                    .append(Doc::text(" then"))
+                    .append(line().append(then_branch).nest(2))
+                    .append(line())
+                    .append(Doc::text("else"))
+                    .append(else_branch)
+                    .group()
+            }
+            Expr::Ann { expr, ty, .. } => parens(
+                self.expr(expr, OPEN)
+                    .append(Doc::text(" :"))
+                    .append(Doc::space())
+                    .append(type_doc(ty, false))
+                    .group(),
+            ),
+        }
+    }
+
+    /// Prints a chain of operators that bind equally tight, like `a + b - c`,
+    /// breaking before every operator if it doesn't fit on a line
+    fn infix(&mut self, op: Operator) -> Document {
+        let level = op.level;
+        let mut operands = vec![(op.symbol, op.rhs)];
+        let mut lhs = op.lhs;
+        if level != COMPARE {
+            while let Some(inner) = operator(lhs).filter(|inner| inner.level == level) {
+                operands.push((inner.symbol, inner.rhs));
+                lhs = inner.lhs;
+            }
+        }
+        let lhs_prec = if level == COMPARE { level + 1 } else { level };
+        let lhs = self.expr(lhs, lhs_prec);
+        let rest: Vec<Document> = operands
+            .into_iter()
+            .rev()
+            .map(|(symbol, rhs)| {
+                Doc::space()
+                    .append(Doc::text(symbol))
+                    .append(Doc::space())
+                    .append(self.expr(rhs, level + 1))
+            })
+            .collect();
+        lhs.append(Doc::concat(rest).nest(2)).group()
+    }
+
+    /// Lets always end their line, so a chain of them reads top to bottom
+    fn let_in(
+        &mut self,
+        keyword: &str,
+        binder: &str,
+        expr: &Expr<String>,
+        body: &Expr<String>,
+    ) -> Document {
+        Doc::text(format!("{} {} =", keyword, binder))
+            .append(self.body(expr, " in"))
+            .append(Doc::newline())
+            .append(self.expr(body, OPEN))
+    }
+}
+
+fn has_blank_line(text: &str) -> bool {
+    let lines: Vec<&str> = text.split('\n').collect();
+    lines.len() > 2
+        && lines[1..lines.len() - 1]
+            .iter()
+            .any(|line| line.trim().is_empty())
+}
+
+/// Comments to go in front of something. Line comments end their line.
+fn comments_doc(comments: Vec<Comment>) -> Document {
+    Doc::concat(comments.into_iter().map(|comment| {
+        let separator = if comment.is_line_comment() {
+            Doc::newline()
+        } else {
+            Doc::text(" ")
+        };
+        Doc::text(comment.text).append(separator)
+    }))
+}
+
+/// Whether what follows a `=`, `.` or `=>` should start on the same line
+fn hugs(expr: &Expr<String>) -> bool {
+    match expr {
+        Expr::Lambda { .. } => operator(expr).is_none(),
+        Expr::Match { cases, .. } => !cases.is_empty(),
+        _ => false,
+    }
+}
+
+/// Whether `expr` contains a let or a match, which always span several
+/// lines
+fn always_breaks(expr: &Expr<String>) -> bool {
+    if let Some(op) = operator(expr) {
+        return always_breaks(op.lhs) || always_breaks(op.rhs);
+    }
+    match expr {
+        Expr::Let { .. } | Expr::LetRec { .. } => true,
+        Expr::Match { cases, .. } => !cases.is_empty(),
+        Expr::Lambda { body, .. } => always_breaks(body),
+        Expr::App { func, arg, .. } => always_breaks(func) || always_breaks(arg),
+        Expr::If {
+            condition,
+            then_branch,
+            else_branch,
+            ..
+        } => always_breaks(condition) || always_breaks(then_branch) || always_breaks(else_branch),
+        Expr::Tuple(fst, snd, _) => always_breaks(fst) || always_breaks(snd),
+        Expr::Construction { args, .. } => args.iter().any(always_breaks),
+        Expr::Ann { expr, .. } => always_breaks(expr),
+        Expr::Var(..) | Expr::Literal(..) => false,
+    }
+}
+
+fn declaration_span(decl: &Declaration<String>) -> Span {
+    match decl {
+        Declaration::Value(vd) => vd.span,
+        Declaration::Type(td) => td.span,
+        Declaration::Error(span) => *span,
+    }
+}
+
+fn doc_comment(doc: &Option<String>) -> Document {
+    match doc {
+        None => Doc::nil(),
+        Some(doc) => Doc::concat(doc.split('\n').map(|line| {
+            let line = if line.is_empty() {
+                "--|".to_string()
+            } else {
+                format!("--| {}", line)
+            };
+            Doc::text(line).append(Doc::newline())
+        })),
+    }
+}
+
+fn data_constructor(dc: &DataConstructor) -> Document {
+    let fields: Vec<Document> = dc.fields.iter().map(|ty| type_doc(ty, false)).collect();
+    Doc::text(format!("{}(", dc.name))
+        .append(comma_separated(fields).nest(2))
+        .append(Doc::text(")"))
+        .group()
+}
+
+fn pattern_doc(pattern: &Pattern<String>, atom: bool) -> Document {
+    match pattern {
+        Pattern::Wildcard(_) => Doc::text("_"),
+        Pattern::Var(name, _) => Doc::text(name.clone()),
+        Pattern::Literal(lit, _) => Doc::text(lit.print()),
+        Pattern::Constructor { dtor, fields, .. } => {
+            let fields: Vec<Document> = fields
+                .iter()
+                .map(|field| pattern_doc(field, false))
+                .collect();
+            Doc::text(format!("{}(", dtor))
+                .append(comma_separated(fields).nest(2))
+                .append(Doc::text(")"))
+                .group()
+        }
+        Pattern::Tuple(fst, snd, _) => parens(
+            pattern_doc(fst, false)
+                .append(Doc::text(","))
+                .append(Doc::space())
+                .append(pattern_doc(snd, false))
+                .nest(1),
+        )
+        .group(),
+        Pattern::As {
+            binder, pattern, ..
+        } => {
+            let doc = Doc::text(format!("{} @ ", binder)).append(pattern_doc(pattern, true));
+            if atom {
+                parens(doc)
+            } else {
+                doc
+            }
+        }
+    }
+}
+
+/// Whether a type's source ends in a `>`. Two of those in a row lex as a
+/// single `>>`, which the grammar only accepts in some places.
+fn ends_with_angle(ty: &Type) -> bool {
+    match ty {
+        Type::Constructor { arguments, .. } => !arguments.is_empty(),
+        Type::Fun { result, .. } => ends_with_angle(result),
+        Type::Poly { ty, .. } => ends_with_angle(ty),
+        Type::Var(_) | Type::Existential(_) | Type::Tuple(..) => false,
+    }
+}
+
+/// Prints a type the way it's written in annotations. `atom` is set where
+/// function types and foralls need parens.
+fn type_doc(ty: &Type, atom: bool) -> Document {
+    match ty {
+        Type::Constructor { name, arguments } if arguments.is_empty() => Doc::text(name.clone()),
+        Type::Constructor { name, arguments } => {
+            let last = arguments.last().unwrap();
+            // `List<List<Int>>` closes both lists with a single `>>`, but
+            // anything that ends in more angles needs a space
+            let glued = match last {
+                Type::Constructor { arguments, .. } => {
+                    arguments.last().is_some_and(|arg| !ends_with_angle(arg))
+                }
+                _ => false,
+            };
+            let close = if ends_with_angle(last) && !glued {
+                " >"
+            } else {
+                ">"
+            };
+            let arguments: Vec<Document> =
+                arguments.iter().map(|arg| type_doc(arg, false)).collect();
+            Doc::text(format!("{}<", name))
+                .append(comma_separated(arguments).nest(2))
+                .append(Doc::text(close))
+                .group()
+        }
+        Type::Var(var) | Type::Existential(var) => Doc::text(var.clone()),
+        Type::Poly { vars, ty } => {
+            let doc = Doc::text(format!("forall {}.", vars.join(" ")))
+                .append(Doc::space().append(type_doc(ty, false)).nest(2))
+                .group();
+            if atom {
+                parens(doc)
+            } else {
+                doc
+            }
+        }
+        Type::Fun { arg, result } => {
+            let doc = type_doc(arg, true)
+                .append(Doc::text(" ->"))
+                .append(Doc::space())
+                .append(type_doc(result, false))
+                .group();
+            if atom {
+                parens(doc)
+            } else {
+                doc
+            }
+        }
+        Type::Tuple(fst, snd) => parens(
+            type_doc(fst, false)
+                .append(Doc::text(","))
+                .append(Doc::space())
+                .append(type_doc(snd, false)),
+        )
+        .group(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn format(input: &str, width: usize) -> String {
+        format_program(FileId::default(), input, width).unwrap()
+    }
+
+    #[test]
+    fn operators_and_parens() {
+        assert_eq!(
+            format(
+                "let main : Int = ((1+2)*3) - (4 - (5 |> f)) + (f >> g) (if b then 1 else 2);",
+                80
+            ),
+            "let main : Int = (1 + 2) * 3 - (4 - (5 |> f)) + (f >> g) (if b then 1 else 2);\n"
+        );
+        assert_eq!(
+            format("let main : Bool = (1 < 2) == (x |> f |> g);", 80),
+            "let main : Bool = (1 < 2) == (x |> f |> g);\n"
+        );
+        assert_eq!(
+            format(
+                "let main : Bool = (a || b) && c || (if a then true else b);",
+                80
+            ),
+            "let main : Bool = (a || b) && c || (if a then true else b);\n"
+        );
+    }
+
+    #[test]
+    fn breaks_long_lines() {
+        assert_eq!(
+            format(
+                "type Maybe<a> { Nothing(), Just(a) }
+let from_maybe : forall a. a -> Maybe<a> -> a = \\d. \\m. match m { Maybe::Just(x) => x, Maybe::Nothing() => d };",
+                40
+            ),
+            "type Maybe<a> { Nothing(), Just(a) }
+let from_maybe :
+  forall a. a -> Maybe<a> -> a =
+  \\d. \\m. match m {
+    Maybe::Just(x) => x,
+    Maybe::Nothing() => d,
+  };
+"
+        );
+    }
+
+    #[test]
+    fn nested_type_arguments() {
+        assert_eq!(
+            format(
+                "let x : A<B<C<D<Int>>> > = x; let y : A<b -> C<d> > = y;",
+                80
+            ),
+            "let x : A<B<C<D<Int>> > > = x;\nlet y : A<b -> C<d> > = y;\n"
+        );
+    }
+
+    #[test]
+    fn keeps_comments() {
+        let input = "-- A header
+
+--| Docs
+let main : Int = -- why
+  match 1 {
+    -- the only case
+    x => x {- inline -}
+  }; -- trailing
+-- at the end
+";
+        assert_eq!(
+            format(input, 80),
+            "-- A header
+
+--| Docs
+let main : Int =
+  -- why
+  match 1 {
+    -- the only case
+    x => x, {- inline -}
+  }; -- trailing
+
+-- at the end
+"
+        );
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        let input = "type List<a> { Nil(), Cons(a, List<a>) }
+let main : Int = letrec len = \\xs. match xs { List::Nil() => 0, List::Cons(_, rest) => 1 + len rest } in len (List::Cons(1, List::Nil()));";
+        for width in [10, 40, 80] {
+            let once = format(input, width);
+            assert_eq!(format(&once, width), once);
+        }
+    }
+}
@@ -0,0 +1,461 @@
+use crate::expr::Literal;
+use crate::pretty::render_doc_width;
+use pretty::{BoxDoc, Doc};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct IR {
+    pub globals: Vec<IRDeclaration>,
+    pub entry_point: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct IRDeclaration {
+    pub name: String,
+    pub arguments: Vec<String>,
+    pub locals: Vec<String>,
+    pub expr: IRExpression,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum IRExpression {
+    App {
+        func: Box<IRExpression>,
+        args: Vec<IRExpression>,
+    },
+    Let {
+        binder: String,
+        expr: Box<IRExpression>,
+        body: Box<IRExpression>,
+    },
+    // Is there an opportunity to differentiate locals from globals here?
+    Var(String),
+    /// A reference to one of the built-in `primitives`
+    Primitive(String),
+    Literal(Literal),
+    Pack {
+        tag: u32,
+        args: Vec<IRExpression>,
+    },
+    Match {
+        expr_local: String,
+        expr: Box<IRExpression>,
+        cases: Vec<IRCase>,
+        /// Evaluated if none of the cases match
+        default: Option<Box<IRExpression>>,
+    },
+    If {
+        condition: Box<IRExpression>,
+        then_branch: Box<IRExpression>,
+        else_branch: Box<IRExpression>,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct IRCase {
+    pub pattern: IRPattern,
+    pub expr: IRExpression,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum IRPattern {
+    /// Matches a pack with the given tag, and binds its fields
+    Pack {
+        tag: u32,
+        binders: Vec<String>,
+    },
+    Literal(Literal),
+}
+
+fn comma_separated<'a, I>(docs: I) -> Doc<'a, BoxDoc<'a, ()>>
+where
+    I: IntoIterator<Item = Doc<'a, BoxDoc<'a, ()>>>,
+{
+    Doc::intersperse(docs, Doc::text(",").append(Doc::space()))
+}
+
+fn names(names: &[String]) -> Doc<BoxDoc<()>> {
+    comma_separated(names.iter().map(Doc::text)).nest(2).group()
+}
+
+impl IR {
+    pub fn to_doc(&self) -> Doc<BoxDoc<()>> {
+        Doc::intersperse(
+            self.globals.iter().map(|decl| decl.to_doc()),
+            Doc::newline().append(Doc::newline()),
+        )
+        .append(Doc::newline())
+        .append(Doc::newline())
+        .append(Doc::text(format!("entry {}", self.entry_point)))
+    }
+}
+
+impl fmt::Display for IR {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", render_doc_width(self.to_doc(), 80))
+    }
+}
+
+impl IRDeclaration {
+    /// Prints as `name(arguments) [locals] = expr`, the locals are left out
+    /// if there are none
+    pub fn to_doc(&self) -> Doc<BoxDoc<()>> {
+        let locals = if self.locals.is_empty() {
+            Doc::nil()
+        } else {
+            Doc::text(" [")
+                .append(names(&self.locals))
+                .append(Doc::text("]"))
+        };
+        Doc::text(&self.name)
+            .append(Doc::text("("))
+            .append(names(&self.arguments))
+            .append(Doc::text(")"))
+            .append(locals)
+            .append(Doc::text(" ="))
+            .append(Doc::newline().append(self.expr.to_doc()).nest(2))
+    }
+}
+
+impl fmt::Display for IRDeclaration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", render_doc_width(self.to_doc(), 80))
+    }
+}
+
+impl IRPattern {
+    pub fn to_doc(&self) -> Doc<BoxDoc<()>> {
+        match self {
+            IRPattern::Pack { tag, binders } => Doc::text(format!("#{}(", tag))
+                .append(names(binders))
+                .append(Doc::text(")")),
+            IRPattern::Literal(lit) => lit.to_doc(),
+        }
+    }
+}
+
+impl IRExpression {
+    pub fn to_doc(&self) -> Doc<BoxDoc<()>> {
+        match self {
+            IRExpression::App { func, args } => {
+                let func = match **func {
+                    IRExpression::Var(_) | IRExpression::Primitive(_) => func.to_doc(),
+                    _ => Doc::text("(").append(func.to_doc()).append(Doc::text(")")),
+                };
+                func.append(Doc::text("("))
+                    .append(comma_separated(args.iter().map(|arg| arg.to_doc())).nest(2))
+                    .append(Doc::text(")"))
+                    .group()
+            }
+            IRExpression::Let { binder, expr, body } => Doc::text("let")
+                .append(Doc::space())
+                .append(Doc::text(binder))
+                .append(Doc::space())
+                .append(Doc::text("="))
+                .append(Doc::space().append(expr.to_doc()).nest(2))
+                .append(Doc::space())
+                .append(Doc::text("in"))
+                .group()
+                .append(Doc::newline())
+                .append(body.to_doc()),
+            IRExpression::Var(v) => Doc::text(v),
+            IRExpression::Primitive(name) => Doc::text(format!("prim:{}", name)),
+            IRExpression::Literal(lit) => lit.to_doc(),
+            IRExpression::Pack { tag, args } => Doc::text(format!("#{}(", tag))
+                .append(comma_separated(args.iter().map(|arg| arg.to_doc())).nest(2))
+                .append(Doc::text(")"))
+                .group(),
+            IRExpression::Match {
+                expr_local,
+                expr,
+                cases,
+                default,
+            } => {
+                let mut arms: Vec<Doc<BoxDoc<()>>> = cases
+                    .iter()
+                    .map(|case| {
+                        case.pattern
+                            .to_doc()
+                            .append(Doc::text(" =>"))
+                            .append(Doc::space().append(case.expr.to_doc()).nest(2))
+                            .group()
+                    })
+                    .collect();
+                if let Some(default) = default {
+                    arms.push(
+                        Doc::text("_ =>")
+                            .append(Doc::space().append(default.to_doc()).nest(2))
+                            .group(),
+                    )
+                }
+                Doc::text(format!("match {} =", expr_local))
+                    .append(Doc::space().append(expr.to_doc()).nest(2))
+                    .append(Doc::space())
+                    .append(Doc::text("{"))
+                    .group()
+                    .append(
+                        Doc::newline()
+                            .append(Doc::intersperse(
+                                arms,
+                                Doc::text(",").append(Doc::newline()),
+                            ))
+                            .nest(2),
+                    )
+                    .append(Doc::newline())
+                    .append(Doc::text("}"))
+            }
+            IRExpression::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => Doc::text("if")
+                .append(Doc::space())
+                .append(condition.to_doc())
+                .append(Doc::space())
+                .append(Doc::text("then"))
+                .group()
+                .append(Doc::space().append(then_branch.to_doc()).nest(2))
+                .append(Doc::space())
+                .append(Doc::text("else"))
+                .append(Doc::space().append(else_branch.to_doc()).nest(2))
+                .group(),
+        }
+    }
+}
+
+impl fmt::Display for IRExpression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", render_doc_width(self.to_doc(), 80))
+    }
+}
+
+impl IR {
+    /// The arities of all the packs the program constructs
+    pub fn pack_arities(&self) -> BTreeSet<usize> {
+        let mut arities = BTreeSet::new();
+        for decl in self.globals.iter() {
+            decl.expr.pack_arities(&mut arities)
+        }
+        arities
+    }
+}
+
+impl IRExpression {
+    fn pack_arities(&self, arities: &mut BTreeSet<usize>) {
+        match self {
+            IRExpression::App { func, args } => {
+                func.pack_arities(arities);
+                for arg in args {
+                    arg.pack_arities(arities)
+                }
+            }
+            IRExpression::Let { expr, body, .. } => {
+                expr.pack_arities(arities);
+                body.pack_arities(arities)
+            }
+            IRExpression::Var(_) | IRExpression::Primitive(_) | IRExpression::Literal(_) => {}
+            IRExpression::Pack { args, .. } => {
+                arities.insert(args.len());
+                for arg in args {
+                    arg.pack_arities(arities)
+                }
+            }
+            IRExpression::Match {
+                expr,
+                cases,
+                default,
+                ..
+            } => {
+                expr.pack_arities(arities);
+                for case in cases {
+                    case.expr.pack_arities(arities)
+                }
+                if let Some(default) = default {
+                    default.pack_arities(arities)
+                }
+            }
+            IRExpression::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                condition.pack_arities(arities);
+                then_branch.pack_arities(arities);
+                else_branch.pack_arities(arities)
+            }
+        }
+    }
+
+    /// Collects the names of all variables referenced in the expression
+    fn referenced_vars(&self, vars: &mut BTreeSet<String>) {
+        match self {
+            IRExpression::App { func, args } => {
+                func.referenced_vars(vars);
+                for arg in args {
+                    arg.referenced_vars(vars)
+                }
+            }
+            IRExpression::Let { expr, body, .. } => {
+                expr.referenced_vars(vars);
+                body.referenced_vars(vars)
+            }
+            IRExpression::Var(v) => {
+                vars.insert(v.clone());
+            }
+            IRExpression::Primitive(_) | IRExpression::Literal(_) => {}
+            IRExpression::Pack { args, .. } => {
+                for arg in args {
+                    arg.referenced_vars(vars)
+                }
+            }
+            IRExpression::Match {
+                expr,
+                cases,
+                default,
+                ..
+            } => {
+                expr.referenced_vars(vars);
+                for case in cases {
+                    case.expr.referenced_vars(vars)
+                }
+                if let Some(default) = default {
+                    default.referenced_vars(vars)
+                }
+            }
+            IRExpression::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                condition.referenced_vars(vars);
+                then_branch.referenced_vars(vars);
+                else_branch.referenced_vars(vars)
+            }
+        }
+    }
+
+    /// Applies every reference to a global function to the values it
+    /// captures
+    fn pass_captured(self, captures: &HashMap<String, BTreeSet<String>>) -> IRExpression {
+        match self {
+            IRExpression::Var(v) => match captures.get(&v) {
+                Some(captured) if !captured.is_empty() => IRExpression::App {
+                    func: Box::new(IRExpression::Var(v)),
+                    args: captured.iter().cloned().map(IRExpression::Var).collect(),
+                },
+                _ => IRExpression::Var(v),
+            },
+            IRExpression::App { func, args } => IRExpression::App {
+                func: Box::new(func.pass_captured(captures)),
+                args: args
+                    .into_iter()
+                    .map(|arg| arg.pass_captured(captures))
+                    .collect(),
+            },
+            IRExpression::Let { binder, expr, body } => IRExpression::Let {
+                binder,
+                expr: Box::new(expr.pass_captured(captures)),
+                body: Box::new(body.pass_captured(captures)),
+            },
+            IRExpression::Primitive(_) | IRExpression::Literal(_) => self,
+            IRExpression::Pack { tag, args } => IRExpression::Pack {
+                tag,
+                args: args
+                    .into_iter()
+                    .map(|arg| arg.pass_captured(captures))
+                    .collect(),
+            },
+            IRExpression::Match {
+                expr_local,
+                expr,
+                cases,
+                default,
+            } => IRExpression::Match {
+                expr_local,
+                expr: Box::new(expr.pass_captured(captures)),
+                cases: cases
+                    .into_iter()
+                    .map(|case| IRCase {
+                        pattern: case.pattern,
+                        expr: case.expr.pass_captured(captures),
+                    })
+                    .collect(),
+                default: default.map(|default| Box::new(default.pass_captured(captures))),
+            },
+            IRExpression::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => IRExpression::If {
+                condition: Box::new(condition.pass_captured(captures)),
+                then_branch: Box::new(then_branch.pass_captured(captures)),
+                else_branch: Box::new(else_branch.pass_captured(captures)),
+            },
+        }
+    }
+}
+
+/// Closure conversion. Lowering lifts every lambda into a global function
+/// that only takes the lambda's own binders as arguments, so any locals it
+/// refers to from its enclosing scope would be out of scope in the lifted
+/// body.
+///
+/// We find these captured locals for every global, add them as its leading
+/// arguments, and apply every reference to the global to the captured values.
+/// That way they get stored in the closure record built by `$make_closure`,
+/// and the lifted body loads them like any other argument.
+pub fn closure_convert(ir: IR) -> IR {
+    let mut captures: HashMap<String, BTreeSet<String>> = ir
+        .globals
+        .iter()
+        .map(|decl| (decl.name.clone(), BTreeSet::new()))
+        .collect();
+
+    // A function also needs to capture everything captured by the functions
+    // it references, and lifted functions can reference each other
+    // recursively, so we iterate until nothing changes.
+    loop {
+        let mut changed = false;
+        for decl in ir.globals.iter() {
+            let mut vars = BTreeSet::new();
+            decl.expr.referenced_vars(&mut vars);
+            let mut captured = BTreeSet::new();
+            for var in vars {
+                match captures.get(&var) {
+                    Some(transitive) => captured.extend(transitive.iter().cloned()),
+                    None => {
+                        captured.insert(var);
+                    }
+                }
+            }
+            captured.retain(|var| !decl.arguments.contains(var) && !decl.locals.contains(var));
+            if captured != captures[&decl.name] {
+                captures.insert(decl.name.clone(), captured);
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    let globals = ir
+        .globals
+        .into_iter()
+        .map(|decl| {
+            let mut arguments: Vec<String> = captures[&decl.name].iter().cloned().collect();
+            arguments.extend(decl.arguments);
+            IRDeclaration {
+                name: decl.name,
+                arguments,
+                locals: decl.locals,
+                expr: decl.expr.pass_captured(&captures),
+            }
+        })
+        .collect();
+    IR {
+        globals,
+        entry_point: ir.entry_point,
+    }
+}
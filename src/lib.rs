@@ -1,16 +1,23 @@
 #[macro_use]
 extern crate log;
+extern crate dirs;
 extern crate rustyline;
 
 pub mod bi_types;
 pub mod codegen;
+pub mod diagnostics;
 pub mod expr;
+pub mod formatter;
 pub mod grammar;
+pub mod ir;
+pub mod lsp;
+pub mod patterns;
 pub mod pipeline;
 pub mod pretty;
+pub mod primitives;
 pub mod repl;
+pub mod span;
 pub mod term;
 pub mod token;
 pub mod types;
 pub mod wasm;
-// pub mod ir; // implement closure conversion here
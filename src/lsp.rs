@@ -0,0 +1,725 @@
+//! A language server, speaking the Language Server Protocol over stdio. It
+//! publishes diagnostics, shows the types of variables on hover, jumps to
+//! top-level definitions and lists the declarations in a file.
+//!
+//! Positions on the wire are zero-based lines and characters. We count
+//! characters like our spans do, rather than in UTF-16 code units, which only
+//! makes a difference for non-ASCII source.
+use crate::diagnostics::{self, Diagnostic, Label, Severity};
+use crate::expr::{Declaration, Dtor, Expr, NewVar, Pattern};
+use crate::pipeline::{self, PipelineError};
+use crate::span::{FileId, Position, Span};
+use crate::types::{self, TypeChecker};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+/// JSON-RPC error codes
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+/// LSP's `SymbolKind`s for the declarations we list
+const SYMBOL_ENUM: u32 = 10;
+const SYMBOL_FUNCTION: u32 = 12;
+const SYMBOL_VARIABLE: u32 = 13;
+const SYMBOL_ENUM_MEMBER: u32 = 22;
+
+/// What we know about an open document, recomputed on every edit
+#[derive(Debug)]
+struct Document {
+    source: String,
+    /// Declarations that failed to parse show up as `Declaration::Error`
+    decls: Vec<Declaration<String>>,
+    /// The declarations up to the first type error
+    typed: Vec<(Declaration<NewVar>, types::Type)>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Document {
+    fn new(source: String) -> Document {
+        let file = FileId::default();
+        let (decls, errors) = pipeline::parse(file, &source);
+        let mut diagnostics: Vec<Diagnostic> =
+            errors.iter().flat_map(diagnostics::diagnostics).collect();
+
+        // Checking one declaration at a time means we can still show types
+        // for everything before the first type error
+        let mut type_checker = TypeChecker::new();
+        let mut typed = vec![];
+        let mut failed = !errors.is_empty();
+        for decl in decls.iter().cloned() {
+            match type_checker.infer_decl(decl) {
+                Ok(decl) => typed.extend(decl),
+                Err(err) => {
+                    let err = PipelineError::NewTypeError(err);
+                    diagnostics.extend(diagnostics::diagnostics(&err));
+                    failed = true;
+                    break;
+                }
+            }
+        }
+        diagnostics.extend(type_checker.take_warnings().iter().map(Diagnostic::from));
+        if !failed {
+            if let Err(err) = pipeline::lower(typed.clone()) {
+                diagnostics.extend(diagnostics::diagnostics(&err))
+            }
+        }
+
+        Document {
+            source,
+            decls,
+            typed,
+            diagnostics,
+        }
+    }
+
+    /// The `name : type` of the variable at `pos`
+    fn hover(&self, pos: Position) -> Option<(String, Span)> {
+        for (decl, ty) in self.typed.iter() {
+            let vd = match decl {
+                Declaration::Value(vd) if contains(vd.span, pos) => vd,
+                _ => continue,
+            };
+            if let Some(var) = var_at(&vd.expr, pos) {
+                return Some(var);
+            }
+            let name = name_span(&self.source, vd.span, "let", &vd.name);
+            if contains(name, pos) {
+                return Some((format!("{} : {}", vd.name, ty), name));
+            }
+        }
+        None
+    }
+
+    /// Where the top-level value or data constructor referred to at `pos`
+    /// is declared
+    fn definition(&self, pos: Position) -> Option<Span> {
+        let reference = self.decls.iter().find_map(|decl| match decl {
+            Declaration::Value(vd) if contains(vd.span, pos) => {
+                reference_at(&vd.expr, pos, &mut vec![])
+            }
+            _ => None,
+        })?;
+        match reference {
+            Reference::Value(name) => {
+                let mut candidates = self.decls.iter().filter_map(|decl| match decl {
+                    Declaration::Value(vd) if vd.name == name => Some(vd),
+                    _ => None,
+                });
+                // A redefinition shadows the ones before it, so we prefer
+                // the closest declaration that isn't after the reference
+                let first = candidates.next()?;
+                let vd = candidates.rfind(|vd| vd.span.start <= pos).unwrap_or(first);
+                Some(name_span(&self.source, vd.span, "let", &vd.name))
+            }
+            Reference::Dtor(dtor) => self.decls.iter().rev().find_map(|decl| match decl {
+                Declaration::Type(td) if td.name == dtor.ty => td
+                    .constructors
+                    .iter()
+                    .find(|dc| dc.name == dtor.name)
+                    .map(|dc| name_span(&self.source, dc.span, "", &dc.name)),
+                _ => None,
+            }),
+        }
+    }
+
+    /// The declarations in the document, with their data constructors nested
+    /// under type declarations
+    fn symbols(&self) -> Vec<Value> {
+        let ty_of = |name: &str| {
+            self.typed.iter().find_map(|(decl, ty)| match decl {
+                Declaration::Value(vd) if vd.name == name => Some(ty.to_string()),
+                _ => None,
+            })
+        };
+        self.decls
+            .iter()
+            .filter_map(|decl| match decl {
+                Declaration::Value(vd) => {
+                    let kind = if is_function(&vd.expr) {
+                        SYMBOL_FUNCTION
+                    } else {
+                        SYMBOL_VARIABLE
+                    };
+                    let mut symbol = json!({
+                        "name": vd.name,
+                        "kind": kind,
+                        "range": range(vd.span),
+                        "selectionRange": range(name_span(&self.source, vd.span, "let", &vd.name)),
+                    });
+                    if let Some(ty) = ty_of(&vd.name) {
+                        symbol["detail"] = json!(ty)
+                    }
+                    Some(symbol)
+                }
+                Declaration::Type(td) => {
+                    let children: Vec<Value> = td
+                        .constructors
+                        .iter()
+                        .map(|dc| {
+                            json!({
+                                "name": dc.name,
+                                "kind": SYMBOL_ENUM_MEMBER,
+                                "range": range(dc.span),
+                                "selectionRange": range(name_span(&self.source, dc.span, "", &dc.name)),
+                            })
+                        })
+                        .collect();
+                    Some(json!({
+                        "name": td.name,
+                        "kind": SYMBOL_ENUM,
+                        "range": range(td.span),
+                        "selectionRange": range(name_span(&self.source, td.span, "type", &td.name)),
+                        "children": children,
+                    }))
+                }
+                Declaration::Error(_) => None,
+            })
+            .collect()
+    }
+}
+
+fn is_function<B>(expr: &Expr<B>) -> bool {
+    match expr {
+        Expr::Lambda { .. } => true,
+        Expr::Ann { expr, .. } => is_function(expr),
+        _ => false,
+    }
+}
+
+/// Whether the cursor at `pos` touches `span`. A cursor right after an
+/// identifier still counts as being on it.
+fn contains(span: Span, pos: Position) -> bool {
+    let pos = (pos.line, pos.column);
+    (span.start.line, span.start.column) <= pos && pos <= (span.end.line, span.end.column)
+}
+
+/// The innermost variable at `pos`, along with its type
+fn var_at(expr: &Expr<NewVar>, pos: Position) -> Option<(String, Span)> {
+    if !contains(expr.span(), pos) {
+        return None;
+    }
+    match expr {
+        Expr::Var(var, span) => Some((format!("{} : {}", var.name, var.ty), *span)),
+        Expr::Match { expr, cases, .. } => var_at(expr, pos).or_else(|| {
+            cases.iter().find_map(|case| {
+                pattern_var_at(&case.pattern, pos).or_else(|| var_at(&case.expr, pos))
+            })
+        }),
+        _ => children(expr).into_iter().find_map(|e| var_at(e, pos)),
+    }
+}
+
+fn pattern_var_at(pattern: &Pattern<NewVar>, pos: Position) -> Option<(String, Span)> {
+    match pattern {
+        Pattern::Var(var, span) if contains(*span, pos) => {
+            Some((format!("{} : {}", var.name, var.ty), *span))
+        }
+        Pattern::Constructor { fields, .. } => {
+            fields.iter().find_map(|field| pattern_var_at(field, pos))
+        }
+        Pattern::Tuple(fst, snd, _) => {
+            pattern_var_at(fst, pos).or_else(|| pattern_var_at(snd, pos))
+        }
+        Pattern::As { pattern, .. } => pattern_var_at(pattern, pos),
+        _ => None,
+    }
+}
+
+/// Something that refers to a top-level declaration
+#[derive(Debug)]
+enum Reference<'a> {
+    Value(&'a str),
+    Dtor(&'a Dtor),
+}
+
+/// The reference to a top-level declaration at `pos`. Variables that are
+/// bound locally, which `bound` keeps track of, don't count.
+fn reference_at<'a>(
+    expr: &'a Expr<String>,
+    pos: Position,
+    bound: &mut Vec<&'a str>,
+) -> Option<Reference<'a>> {
+    if !contains(expr.span(), pos) {
+        return None;
+    }
+    match expr {
+        Expr::Var(name, _) if bound.contains(&name.as_str()) => None,
+        Expr::Var(name, _) => Some(Reference::Value(name)),
+        Expr::Construction { dtor, args, .. } => {
+            if contains(dtor.span, pos) {
+                return Some(Reference::Dtor(dtor));
+            }
+            args.iter().find_map(|arg| reference_at(arg, pos, bound))
+        }
+        Expr::Lambda { binder, body, .. } => {
+            scoped(bound, vec![binder], |bound| reference_at(body, pos, bound))
+        }
+        Expr::Let {
+            binder, expr, body, ..
+        } => reference_at(expr, pos, bound)
+            .or_else(|| scoped(bound, vec![binder], |bound| reference_at(body, pos, bound))),
+        Expr::LetRec {
+            binder, expr, body, ..
+        } => scoped(bound, vec![binder], |bound| {
+            reference_at(expr, pos, bound).or_else(|| reference_at(body, pos, bound))
+        }),
+        Expr::Match { expr, cases, .. } => reference_at(expr, pos, bound).or_else(|| {
+            cases.iter().find_map(|case| {
+                pattern_dtor_at(&case.pattern, pos).or_else(|| {
+                    scoped(bound, case.pattern.binders(), |bound| {
+                        reference_at(&case.expr, pos, bound)
+                    })
+                })
+            })
+        }),
+        _ => children(expr)
+            .into_iter()
+            .find_map(|e| reference_at(e, pos, bound)),
+    }
+}
+
+fn pattern_dtor_at<B>(pattern: &Pattern<B>, pos: Position) -> Option<Reference<'_>> {
+    match pattern {
+        Pattern::Constructor { dtor, fields, .. } => {
+            if contains(dtor.span, pos) {
+                return Some(Reference::Dtor(dtor));
+            }
+            fields.iter().find_map(|field| pattern_dtor_at(field, pos))
+        }
+        Pattern::Tuple(fst, snd, _) => {
+            pattern_dtor_at(fst, pos).or_else(|| pattern_dtor_at(snd, pos))
+        }
+        Pattern::As { pattern, .. } => pattern_dtor_at(pattern, pos),
+        _ => None,
+    }
+}
+
+/// Runs `f` with `binders` in scope
+fn scoped<'a, A>(
+    bound: &mut Vec<&'a str>,
+    binders: Vec<&'a String>,
+    f: impl FnOnce(&mut Vec<&'a str>) -> A,
+) -> A {
+    let len = bound.len();
+    bound.extend(binders.into_iter().map(|binder| binder.as_str()));
+    let res = f(bound);
+    bound.truncate(len);
+    res
+}
+
+/// The direct subexpressions of `expr`, except for the ones in match arms
+fn children<B>(expr: &Expr<B>) -> Vec<&Expr<B>> {
+    match expr {
+        Expr::App { func, arg, .. } => vec![func, arg],
+        Expr::Lambda { body, .. } => vec![body],
+        Expr::Let { expr, body, .. } | Expr::LetRec { expr, body, .. } => vec![expr, body],
+        Expr::Var(..) | Expr::Literal(..) => vec![],
+        Expr::Tuple(fst, snd, _) => vec![fst, snd],
+        Expr::Construction { args, .. } => args.iter().collect(),
+        Expr::Match { expr, .. } => vec![expr],
+        Expr::If {
+            condition,
+            then_branch,
+            else_branch,
+            ..
+        } => vec![condition, then_branch, else_branch],
+        Expr::Ann { expr, .. } => vec![expr],
+    }
+}
+
+/// The span of the name a declaration starting at `span` declares, right
+/// after `keyword`. Falls back to the whole declaration if the source
+/// doesn't look like we expect.
+fn name_span(source: &str, span: Span, keyword: &str, name: &str) -> Span {
+    let rest = match source.get(span.start.offset..) {
+        Some(rest) if rest.starts_with(keyword) => &rest[keyword.len()..],
+        _ => return span,
+    };
+    let trimmed = rest.trim_start();
+    if !trimmed.starts_with(name) {
+        return span;
+    }
+    let start = span.start.offset + keyword.len() + rest.len() - trimmed.len();
+    Span::new(
+        span.file,
+        position_at(source, start),
+        position_at(source, start + name.len()),
+    )
+}
+
+fn position_at(source: &str, offset: usize) -> Position {
+    let before = &source[..offset];
+    let line_start = before.rfind('\n').map_or(0, |ix| ix + 1);
+    Position {
+        offset,
+        line: before.matches('\n').count() as u32,
+        column: before[line_start..].chars().count() as u32,
+    }
+}
+
+fn position(pos: Position) -> Value {
+    json!({ "line": pos.line, "character": pos.column })
+}
+
+fn range(span: Span) -> Value {
+    json!({ "start": position(span.start), "end": position(span.end) })
+}
+
+fn lsp_diagnostic(uri: &str, diagnostic: &Diagnostic) -> Value {
+    let severity = match diagnostic.severity {
+        Severity::Error => 1,
+        Severity::Warning => 2,
+    };
+    let span = diagnostic
+        .primary
+        .as_ref()
+        .map(|label| label.span)
+        .unwrap_or_default();
+    let related: Vec<Value> = diagnostic
+        .secondary
+        .iter()
+        .map(|Label { span, message }| {
+            json!({
+                "location": { "uri": uri, "range": range(*span) },
+                "message": message,
+            })
+        })
+        .collect();
+    json!({
+        "range": range(span),
+        "severity": severity,
+        "source": "siml",
+        "message": diagnostic.message,
+        "relatedInformation": related,
+    })
+}
+
+/// Keeps track of the open documents and answers requests about them. The
+/// transport lives in `run`, so the server can be driven by tests.
+#[derive(Debug, Default)]
+pub struct Server {
+    documents: HashMap<String, Document>,
+    shutting_down: bool,
+}
+
+impl Server {
+    pub fn new() -> Server {
+        Server::default()
+    }
+
+    /// Handles a single message. Returns the messages to send back, and
+    /// whether the client told us to exit.
+    pub fn handle(&mut self, message: &Value) -> (Vec<Value>, bool) {
+        let method = message["method"].as_str().unwrap_or("");
+        let params = &message["params"];
+        let id = message.get("id").cloned();
+        debug!("lsp: {}", method);
+
+        let mut notifications = vec![];
+        let result: Result<Value, (i64, String)> = match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    // Full document sync, we re-check everything anyway
+                    "textDocumentSync": 1,
+                    "hoverProvider": true,
+                    "definitionProvider": true,
+                    "documentSymbolProvider": true,
+                },
+                "serverInfo": { "name": "siml" },
+            })),
+            "shutdown" => {
+                self.shutting_down = true;
+                Ok(Value::Null)
+            }
+            "exit" => return (vec![], true),
+            "textDocument/didOpen" => {
+                let document = &params["textDocument"];
+                let uri = document["uri"].as_str().unwrap_or("");
+                let text = document["text"].as_str().unwrap_or("");
+                notifications.push(self.update(uri, text.to_string()));
+                Ok(Value::Null)
+            }
+            "textDocument/didChange" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+                // We only ask for full syncs, so the last change has the
+                // whole document
+                let text = params["contentChanges"]
+                    .as_array()
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change["text"].as_str());
+                if let Some(text) = text {
+                    notifications.push(self.update(uri, text.to_string()))
+                }
+                Ok(Value::Null)
+            }
+            "textDocument/didClose" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+                self.documents.remove(uri);
+                notifications.push(publish_diagnostics(uri, &[]));
+                Ok(Value::Null)
+            }
+            "textDocument/hover" => {
+                self.with_position(params, |document, pos| match document.hover(pos) {
+                    Some((contents, span)) => json!({
+                        "contents": {
+                            "kind": "markdown",
+                            "value": format!("```siml\n{}\n```", contents),
+                        },
+                        "range": range(span),
+                    }),
+                    None => Value::Null,
+                })
+            }
+            "textDocument/definition" => {
+                let uri = params["textDocument"]["uri"].clone();
+                self.with_position(params, |document, pos| match document.definition(pos) {
+                    Some(span) => json!({ "uri": uri, "range": range(span) }),
+                    None => Value::Null,
+                })
+            }
+            "textDocument/documentSymbol" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+                match self.documents.get(uri) {
+                    Some(document) => Ok(json!(document.symbols())),
+                    None => Err((INVALID_PARAMS, format!("Unknown document {}", uri))),
+                }
+            }
+            method => Err((METHOD_NOT_FOUND, format!("Unknown method {}", method))),
+        };
+
+        // Notifications don't get a response, not even an error
+        let mut out = notifications;
+        if let Some(id) = id {
+            out.push(match result {
+                Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                Err((code, message)) => json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": { "code": code, "message": message },
+                }),
+            })
+        }
+        (out, false)
+    }
+
+    /// Re-checks a document and returns the notification with its
+    /// diagnostics
+    fn update(&mut self, uri: &str, text: String) -> Value {
+        let document = Document::new(text);
+        let notification = publish_diagnostics(uri, &document.diagnostics);
+        self.documents.insert(uri.to_string(), document);
+        notification
+    }
+
+    fn with_position(
+        &self,
+        params: &Value,
+        f: impl FnOnce(&Document, Position) -> Value,
+    ) -> Result<Value, (i64, String)> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+        let document = self
+            .documents
+            .get(uri)
+            .ok_or_else(|| (INVALID_PARAMS, format!("Unknown document {}", uri)))?;
+        let line = params["position"]["line"].as_u64();
+        let character = params["position"]["character"].as_u64();
+        match (line, character) {
+            (Some(line), Some(character)) => Ok(f(
+                document,
+                Position {
+                    offset: 0,
+                    line: line as u32,
+                    column: character as u32,
+                },
+            )),
+            _ => Err((INVALID_PARAMS, "Missing position".to_string())),
+        }
+    }
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: &[Diagnostic]) -> Value {
+    let diagnostics: Vec<Value> = diagnostics
+        .iter()
+        .map(|diagnostic| lsp_diagnostic(uri, diagnostic))
+        .collect();
+    json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": { "uri": uri, "diagnostics": diagnostics },
+    })
+}
+
+/// Reads a message with its `Content-Length` header. Returns `None` once the
+/// client closes the stream.
+pub fn read_message(input: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok()
+        }
+    }
+    let length = length.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "Missing Content-Length header")
+    })?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+pub fn write_message(output: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+/// Serves requests on stdin until the client tells us to exit
+pub fn run() -> io::Result<()> {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let mut input = stdin.lock();
+    let mut output = stdout.lock();
+    let mut server = Server::new();
+    while let Some(message) = read_message(&mut input)? {
+        let (responses, exit) = server.handle(&message);
+        for response in responses.iter() {
+            write_message(&mut output, response)?
+        }
+        if exit {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const URI: &str = "file:///prog.siml";
+
+    const PROGRAM: &str = "type Maybe<a> { Nothing(), Just(a) }
+
+let default : Int = 0;
+
+let from_maybe : Maybe<Int> -> Int = \\m.
+  match m { Maybe::Just(x) => x, Maybe::Nothing() => default };
+
+let main : Int = from_maybe (Maybe::Just(1));
+";
+
+    fn open(server: &mut Server, text: &str) -> Value {
+        let (mut out, _) = server.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": URI, "languageId": "siml", "version": 1, "text": text } },
+        }));
+        out.remove(0)
+    }
+
+    fn request(server: &mut Server, method: &str, line: u32, character: u32) -> Value {
+        let (mut out, _) = server.handle(&json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": {
+                "textDocument": { "uri": URI },
+                "position": { "line": line, "character": character },
+            },
+        }));
+        out.remove(0)["result"].take()
+    }
+
+    #[test]
+    fn publishes_diagnostics() {
+        let mut server = Server::new();
+        let notification = open(&mut server, PROGRAM);
+        assert_eq!(notification["params"]["diagnostics"], json!([]));
+
+        let notification = open(&mut server, "let x : Int =\n  true;");
+        let diagnostic = &notification["params"]["diagnostics"][0];
+        assert_eq!(diagnostic["message"], "Failed to unify Bool with Int");
+        assert_eq!(
+            diagnostic["range"],
+            json!({ "start": { "line": 1, "character": 2 }, "end": { "line": 1, "character": 6 } })
+        );
+        assert_eq!(diagnostic["relatedInformation"][0]["location"]["uri"], URI);
+    }
+
+    #[test]
+    fn hover_shows_types() {
+        let mut server = Server::new();
+        open(&mut server, PROGRAM);
+        // The `x` in `=> x`
+        let hover = request(&mut server, "textDocument/hover", 5, 30);
+        assert_eq!(hover["contents"]["value"], "```siml\nx : Int\n```");
+        // The name of a declaration
+        let hover = request(&mut server, "textDocument/hover", 4, 6);
+        assert_eq!(
+            hover["contents"]["value"],
+            "```siml\nfrom_maybe : Maybe<Int> → Int\n```"
+        );
+        assert_eq!(
+            request(&mut server, "textDocument/hover", 1, 0),
+            Value::Null
+        );
+    }
+
+    #[test]
+    fn goes_to_definitions() {
+        let mut server = Server::new();
+        open(&mut server, PROGRAM);
+        // `default` in the match
+        let location = request(&mut server, "textDocument/definition", 5, 56);
+        assert_eq!(location["uri"], URI);
+        assert_eq!(
+            location["range"]["start"],
+            json!({ "line": 2, "character": 4 })
+        );
+        // The `Just` in `main`
+        let location = request(&mut server, "textDocument/definition", 7, 37);
+        assert_eq!(
+            location["range"],
+            json!({ "start": { "line": 0, "character": 27 }, "end": { "line": 0, "character": 31 } })
+        );
+        // `m` is bound by the lambda
+        assert_eq!(
+            request(&mut server, "textDocument/definition", 5, 8),
+            Value::Null
+        );
+    }
+
+    #[test]
+    fn lists_symbols() {
+        let mut server = Server::new();
+        open(&mut server, PROGRAM);
+        let (mut out, _) = server.handle(&json!({
+            "jsonrpc": "2.0",
+            "id": 2,
+            "method": "textDocument/documentSymbol",
+            "params": { "textDocument": { "uri": URI } },
+        }));
+        let symbols = out.remove(0)["result"].take();
+        let names: Vec<&str> = symbols
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|symbol| symbol["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["Maybe", "default", "from_maybe", "main"]);
+        assert_eq!(symbols[0]["children"][1]["name"], "Just");
+        assert_eq!(symbols[2]["kind"], SYMBOL_FUNCTION);
+        assert_eq!(symbols[1]["detail"], "Int");
+    }
+}
@@ -6,13 +6,245 @@ extern crate siml;
 use fern::colors::{Color, ColoredLevelConfig};
 use notify::DebouncedEvent;
 use notify::{RecommendedWatcher, RecursiveMode, Watcher};
-use siml::pipeline;
+use siml::diagnostics::{diagnostics, Diagnostic, Files};
+use siml::formatter;
+use siml::lsp;
+use siml::pipeline::{self, Backend, Phase};
+use siml::repl;
+use siml::span::FileId;
 use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
 use std::sync::mpsc::channel;
-use std::thread;
 use std::time::Duration;
 
-fn setup_logger() {
+const USAGE: &str = "Usage: siml [-v...] <command>
+
+Commands:
+    run <file> [--backend term|wasm]    Runs a program and prints its result
+    check <file>                        Type checks a program
+    build <file> -o <out.wasm>          Compiles a program to wasm
+    repl [--backend term|wasm]          Starts an interactive session
+    watch <file> [--backend term|wasm]  Runs a program whenever it changes
+    fmt <file> [--check] [--width <n>]  Formats a program in place
+    lsp                                 Starts a language server on stdio
+
+Options:
+    -v, --verbose    Logs more, repeat for even more output
+    --error-format human|json
+                     How to print errors and warnings, `json` prints one
+                     object per line on stderr
+    --check          Makes `fmt` fail if the file isn't formatted, rather
+                     than formatting it
+    -w, --width      The line width `fmt` aims for, defaults to 80
+    -h, --help       Prints this message
+
+Debugging the compiler:
+    --dump-ast       Prints the parsed program
+    --dump-typed     Prints the program with the types of all binders
+    --dump-ir        Prints the IR the wasm backend generates code from
+    --dump-wat       Prints the generated WAT
+    --dump-to-files  Writes dumps next to the source file rather than to
+                     stdout, `prog.siml` gets dumped to `prog.ir` etc.";
+
+/// The program failed to compile or run
+const EXIT_FAILURE: i32 = 1;
+/// We couldn't make sense of the command line
+const EXIT_USAGE: i32 = 2;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum BackendArg {
+    Term,
+    Wasm,
+}
+
+impl BackendArg {
+    fn backend(self) -> Backend<'static> {
+        match self {
+            BackendArg::Term => Backend::Term,
+            BackendArg::Wasm => Backend::WasmRun,
+        }
+    }
+}
+
+/// How diagnostics get printed
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum ErrorFormat {
+    Human,
+    Json,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    Run {
+        file: PathBuf,
+        backend: BackendArg,
+    },
+    Check {
+        file: PathBuf,
+    },
+    Build {
+        file: PathBuf,
+        output: PathBuf,
+    },
+    Repl {
+        backend: BackendArg,
+    },
+    Watch {
+        file: PathBuf,
+        backend: BackendArg,
+    },
+    Fmt {
+        file: PathBuf,
+        check: bool,
+        width: usize,
+    },
+    Lsp,
+    Help,
+}
+
+/// The intermediate results of the pipeline we were asked to show
+#[derive(Debug, Default)]
+struct Dumps {
+    phases: Vec<Phase>,
+    to_files: bool,
+}
+
+#[derive(Debug)]
+struct Options {
+    verbosity: usize,
+    error_format: ErrorFormat,
+    dumps: Dumps,
+    command: Command,
+}
+
+fn parse_backend(arg: Option<String>) -> Result<BackendArg, String> {
+    match arg.as_deref() {
+        Some("term") => Ok(BackendArg::Term),
+        Some("wasm") => Ok(BackendArg::Wasm),
+        Some(other) => Err(format!(
+            "Unknown backend `{}`, expected `term` or `wasm`",
+            other
+        )),
+        None => Err("Missing value for --backend".to_string()),
+    }
+}
+
+fn parse_error_format(arg: Option<String>) -> Result<ErrorFormat, String> {
+    match arg.as_deref() {
+        Some("human") => Ok(ErrorFormat::Human),
+        Some("json") => Ok(ErrorFormat::Json),
+        Some(other) => Err(format!(
+            "Unknown error format `{}`, expected `human` or `json`",
+            other
+        )),
+        None => Err("Missing value for --error-format".to_string()),
+    }
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+    let mut verbosity = 0;
+    let mut error_format = ErrorFormat::Human;
+    let mut dumps = Dumps::default();
+    let mut backend = None;
+    let mut output = None;
+    let mut check = false;
+    let mut width = formatter::DEFAULT_WIDTH;
+    let mut positional = vec![];
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                return Ok(Options {
+                    verbosity,
+                    error_format,
+                    dumps,
+                    command: Command::Help,
+                })
+            }
+            "-v" | "--verbose" => verbosity += 1,
+            "--error-format" => error_format = parse_error_format(args.next())?,
+            "-b" | "--backend" => backend = Some(parse_backend(args.next())?),
+            "-o" | "--output" => match args.next() {
+                Some(path) => output = Some(PathBuf::from(path)),
+                None => return Err("Missing value for --output".to_string()),
+            },
+            "--check" => check = true,
+            "-w" | "--width" => match args.next().map(|width| width.parse::<usize>()) {
+                Some(Ok(w)) if w > 0 => width = w,
+                Some(_) => return Err("--width expects a positive number".to_string()),
+                None => return Err("Missing value for --width".to_string()),
+            },
+            "--dump-to-files" => dumps.to_files = true,
+            flag if flag.starts_with("--dump-") => {
+                let name = &flag["--dump-".len()..];
+                match Phase::from_name(name) {
+                    Some(phase) => dumps.phases.push(phase),
+                    None => return Err(format!("Unknown phase `{}` to dump", name)),
+                }
+            }
+            flag if flag.starts_with("-vv") && flag[1..].chars().all(|c| c == 'v') => {
+                verbosity += flag.len() - 1
+            }
+            flag if flag.starts_with('-') => return Err(format!("Unknown option `{}`", flag)),
+            _ => positional.push(arg),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let subcommand = positional
+        .next()
+        .ok_or_else(|| "Missing command".to_string())?;
+    let mut file = || {
+        positional
+            .next()
+            .map(PathBuf::from)
+            .ok_or_else(|| format!("`{}` expects a file", subcommand))
+    };
+    let command = match subcommand.as_str() {
+        "run" => Command::Run {
+            file: file()?,
+            backend: backend.unwrap_or(BackendArg::Term),
+        },
+        "check" => Command::Check { file: file()? },
+        "build" => Command::Build {
+            file: file()?,
+            output: output.ok_or_else(|| "`build` expects an output path (-o)".to_string())?,
+        },
+        "repl" => Command::Repl {
+            backend: backend.unwrap_or(BackendArg::Term),
+        },
+        "watch" => Command::Watch {
+            file: file()?,
+            backend: backend.unwrap_or(BackendArg::Term),
+        },
+        "fmt" => Command::Fmt {
+            file: file()?,
+            check,
+            width,
+        },
+        "lsp" => Command::Lsp,
+        "help" => Command::Help,
+        other => return Err(format!("Unknown command `{}`", other)),
+    };
+    if let Some(extra) = positional.next() {
+        return Err(format!("Unexpected argument `{}`", extra));
+    }
+    Ok(Options {
+        verbosity,
+        error_format,
+        dumps,
+        command,
+    })
+}
+
+fn setup_logger(verbosity: usize) {
+    let level = match verbosity {
+        0 => log::LevelFilter::Warn,
+        1 => log::LevelFilter::Info,
+        2 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
     let colors = ColoredLevelConfig::new()
         .info(Color::Green)
         .debug(Color::Blue);
@@ -24,75 +256,181 @@ fn setup_logger() {
                 message
             ))
         })
-        .level(log::LevelFilter::Info)
-        // .level_for("siml::bi_types", log::LevelFilter::Debug)
-        .chain(std::io::stdout())
+        .level(level)
+        .chain(std::io::stderr())
         .apply();
 }
 
-fn watch_file() -> notify::Result<()> {
-    let (tx, rx) = channel();
-    let mut watcher: RecommendedWatcher = Watcher::new(tx, Duration::from_secs(2))?;
-    watcher.watch("prog.siml", RecursiveMode::Recursive)?;
-    loop {
-        match rx.recv() {
-            Ok(DebouncedEvent::Write(_)) => run_file(),
-            Ok(DebouncedEvent::Create(_)) => run_file(),
-            Ok(_ev) => {
-                // Uncomment if you want to debug watcher failures
-                // println!("{:?}", _ev)
-            }
-            Err(e) => println!("watch error: {:?}", e),
+fn read_source(path: &Path) -> Result<String, i32> {
+    fs::read_to_string(path).map_err(|err| {
+        eprintln!("error: Failed to read {}: {}", path.display(), err);
+        EXIT_FAILURE
+    })
+}
+
+/// Prints `diagnostic` on stderr
+fn emit(files: &Files, format: ErrorFormat, diagnostic: &Diagnostic) {
+    match format {
+        ErrorFormat::Human => eprint!("{}", diagnostic.render(files)),
+        ErrorFormat::Json => eprintln!("{}", diagnostic.to_json(files)),
+    }
+}
+
+fn report(files: &Files, format: ErrorFormat, err: &pipeline::PipelineError) -> i32 {
+    for diagnostic in diagnostics(err) {
+        emit(files, format, &diagnostic)
+    }
+    EXIT_FAILURE
+}
+
+/// Prints the requested phases of the pipeline, or writes them next to
+/// `path`
+fn dump(
+    dumps: &Dumps,
+    format: ErrorFormat,
+    path: &Path,
+    files: &Files,
+    file: FileId,
+    source: &str,
+) -> Result<(), i32> {
+    for phase in dumps.phases.iter() {
+        let res =
+            pipeline::dump(file, source, *phase).map_err(|err| report(files, format, &err))?;
+        if dumps.to_files {
+            let dump_path = path.with_extension(phase.name());
+            fs::write(&dump_path, res + "\n").map_err(|err| {
+                eprintln!("error: Failed to write {}: {}", dump_path.display(), err);
+                EXIT_FAILURE
+            })?
+        } else {
+            println!("{}", res)
         }
     }
+    Ok(())
 }
-fn watch_wasm_file() -> notify::Result<()> {
+
+/// Runs `path` with `backend` and prints the result. Errors are reported on
+/// stderr.
+fn run_file(path: &Path, backend: Backend, dumps: &Dumps, format: ErrorFormat) -> Result<(), i32> {
+    let source = read_source(path)?;
+    let mut files = Files::new();
+    let file = files.add(&path.display().to_string(), &source);
+    dump(dumps, format, path, &files, file, &source)?;
+    let (tys, warnings) =
+        pipeline::typecheck(file, &source).map_err(|err| report(&files, format, &err))?;
+    for warning in warnings.iter() {
+        emit(&files, format, &Diagnostic::from(warning))
+    }
+    let res = pipeline::run_checked(tys, backend).map_err(|err| report(&files, format, &err))?;
+    println!("{}", res);
+    Ok(())
+}
+
+fn check_file(path: &Path, dumps: &Dumps, format: ErrorFormat) -> Result<(), i32> {
+    let source = read_source(path)?;
+    let mut files = Files::new();
+    let file = files.add(&path.display().to_string(), &source);
+    dump(dumps, format, path, &files, file, &source)?;
+    let (_, warnings) =
+        pipeline::typecheck(file, &source).map_err(|err| report(&files, format, &err))?;
+    for warning in warnings.iter() {
+        emit(&files, format, &Diagnostic::from(warning))
+    }
+    Ok(())
+}
+
+/// Formats `path` in place, or with `check` only reports whether it's
+/// formatted already
+fn format_file(path: &Path, check: bool, width: usize, format: ErrorFormat) -> Result<(), i32> {
+    let source = read_source(path)?;
+    let mut files = Files::new();
+    let file = files.add(&path.display().to_string(), &source);
+    let formatted = formatter::format_program(file, &source, width)
+        .map_err(|err| report(&files, format, &err))?;
+    if formatted == source {
+        return Ok(());
+    }
+    if check {
+        eprintln!("error: {} isn't formatted", path.display());
+        return Err(EXIT_FAILURE);
+    }
+    fs::write(path, formatted).map_err(|err| {
+        eprintln!("error: Failed to write {}: {}", path.display(), err);
+        EXIT_FAILURE
+    })
+}
+
+fn watch_file(
+    path: &Path,
+    backend: BackendArg,
+    dumps: &Dumps,
+    format: ErrorFormat,
+) -> Result<(), i32> {
+    let run = || {
+        // Failures are reported, but shouldn't stop us from watching
+        let _ = run_file(path, backend.backend(), dumps, format);
+    };
+    let watch_error = |err: notify::Error| {
+        eprintln!("error: Failed to watch {}: {:?}", path.display(), err);
+        EXIT_FAILURE
+    };
     let (tx, rx) = channel();
-    let mut watcher: RecommendedWatcher = Watcher::new(tx, Duration::from_secs(2))?;
-    watcher.watch("wasm_prog.siml", RecursiveMode::Recursive)?;
+    let mut watcher: RecommendedWatcher =
+        Watcher::new(tx, Duration::from_secs(2)).map_err(watch_error)?;
+    watcher
+        .watch(path, RecursiveMode::NonRecursive)
+        .map_err(watch_error)?;
+    run();
     loop {
         match rx.recv() {
-            Ok(DebouncedEvent::Write(_)) => run_wasm_file(),
-            Ok(DebouncedEvent::Create(_)) => run_wasm_file(),
+            Ok(DebouncedEvent::Write(_)) => run(),
+            Ok(DebouncedEvent::Create(_)) => run(),
             Ok(_ev) => {
                 // Uncomment if you want to debug watcher failures
                 // println!("{:?}", _ev)
             }
-            Err(e) => println!("watch error: {:?}", e),
+            Err(err) => {
+                eprintln!("error: Watcher disconnected: {}", err);
+                return Err(EXIT_FAILURE);
+            }
         }
     }
 }
 
-fn run_file() {
-    let source_file = fs::read_to_string("prog.siml").expect("Failed to read the source file.");
-    let res = pipeline::run_program(&source_file, pipeline::Backend::Term);
-    println!("{:?}", res)
-}
-
-fn run_wasm_file() {
-    let source_file =
-        fs::read_to_string("wasm_prog.siml").expect("Failed to read the source file.");
-    let res = pipeline::run_program(&source_file, pipeline::Backend::WasmRun);
-    println!("{:?}", res)
+fn execute(options: Options) -> Result<(), i32> {
+    let dumps = &options.dumps;
+    let format = options.error_format;
+    match options.command {
+        Command::Run { file, backend } => run_file(&file, backend.backend(), dumps, format),
+        Command::Check { file } => check_file(&file, dumps, format),
+        Command::Build { file, output } => run_file(&file, Backend::Wasm(&output), dumps, format),
+        Command::Repl { backend } => {
+            repl::run(backend.backend());
+            Ok(())
+        }
+        Command::Watch { file, backend } => watch_file(&file, backend, dumps, format),
+        Command::Fmt { file, check, width } => format_file(&file, check, width, format),
+        Command::Lsp => lsp::run().map_err(|err| {
+            eprintln!("error: Language server failed: {}", err);
+            EXIT_FAILURE
+        }),
+        Command::Help => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+    }
 }
 
 fn main() {
-    let use_wasm = true;
-    setup_logger();
-    if use_wasm {
-        run_wasm_file();
-        thread::spawn(move || {
-            watch_wasm_file().expect("WASM File watcher failed");
-        });
-    } else {
-        run_file();
-        thread::spawn(move || {
-            watch_file().expect("File watcher failed");
-        });
-    }
-
-    loop {
-        thread::sleep(Duration::from_secs(2))
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("error: {}\n\n{}", err, USAGE);
+            process::exit(EXIT_USAGE)
+        }
+    };
+    setup_logger(options.verbosity);
+    if let Err(code) = execute(options) {
+        process::exit(code)
     }
-    // repl::run();
 }
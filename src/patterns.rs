@@ -0,0 +1,522 @@
+//! Pattern matching support: checking a match for exhaustiveness and
+//! redundancy, and compiling nested patterns into decision trees made up of
+//! flat, single-level switches the backends know how to lower.
+use crate::expr::{Case, DataConstructor, Dtor, Expr, HasIdent, Literal, Pattern, TypeDeclaration};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Anything that knows the data constructors of the types in a program
+pub trait Signatures {
+    fn constructors(&self, ty: &str) -> Option<&[DataConstructor]>;
+}
+
+impl Signatures for HashMap<String, Vec<DataConstructor>> {
+    fn constructors(&self, ty: &str) -> Option<&[DataConstructor]> {
+        self.get(ty).map(|dtors| dtors.as_slice())
+    }
+}
+
+impl Signatures for Vec<TypeDeclaration> {
+    fn constructors(&self, ty: &str) -> Option<&[DataConstructor]> {
+        self.iter()
+            .find(|ty_decl| ty_decl.name == ty)
+            .map(|ty_decl| ty_decl.constructors.as_slice())
+    }
+}
+
+/// What a pattern checks about the head of a value, ignoring its fields
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum Head {
+    Constructor { ty: String, name: String },
+    Literal(Literal),
+    Tuple,
+}
+
+/// A pattern stripped of binders, which don't matter for coverage
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum Pat {
+    Wildcard,
+    Head(Head, Vec<Pat>),
+}
+
+impl Pat {
+    fn from_pattern<B>(pattern: &Pattern<B>) -> Pat {
+        match pattern {
+            Pattern::Wildcard(_) | Pattern::Var(_, _) => Pat::Wildcard,
+            Pattern::As { pattern, .. } => Pat::from_pattern(pattern),
+            Pattern::Literal(lit, _) => Pat::Head(Head::Literal(lit.clone()), vec![]),
+            Pattern::Constructor { dtor, fields, .. } => Pat::Head(
+                Head::Constructor {
+                    ty: dtor.ty.clone(),
+                    name: dtor.name.clone(),
+                },
+                fields.iter().map(Pat::from_pattern).collect(),
+            ),
+            Pattern::Tuple(fst, snd, _) => Pat::Head(
+                Head::Tuple,
+                vec![Pat::from_pattern(fst), Pat::from_pattern(snd)],
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Pat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Pat::Wildcard => write!(f, "_"),
+            Pat::Head(Head::Literal(lit), _) => write!(f, "{}", lit.print()),
+            Pat::Head(Head::Tuple, fields) => write!(f, "({}, {})", fields[0], fields[1]),
+            Pat::Head(Head::Constructor { ty, name }, fields) => write!(
+                f,
+                "{}::{}({})",
+                ty,
+                name,
+                fields
+                    .iter()
+                    .map(|field| field.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+type Row = Vec<Pat>;
+
+/// The result of checking the cases of a match
+#[derive(Debug, PartialEq, Eq)]
+pub struct Coverage {
+    /// Example values none of the cases match, printed as patterns
+    pub missing: Vec<String>,
+    /// Indices of the cases that can never match, because earlier cases
+    /// already match everything they would
+    pub redundant: Vec<usize>,
+}
+
+/// Checks a list of patterns for exhaustiveness and redundancy. This is the
+/// "usefulness" algorithm from Maranget's "Warnings for pattern matching".
+pub fn check_coverage<'a, B: 'a, S: Signatures>(
+    signatures: &S,
+    patterns: impl IntoIterator<Item = &'a Pattern<B>>,
+) -> Coverage {
+    let checker = CoverageChecker { signatures };
+    let mut rows: Vec<Row> = vec![];
+    let mut redundant = vec![];
+    for (ix, pattern) in patterns.into_iter().enumerate() {
+        let row = vec![Pat::from_pattern(pattern)];
+        if !checker.useful(&rows, &row) {
+            redundant.push(ix)
+        }
+        rows.push(row);
+    }
+    let missing = checker
+        .missing(&rows, 1)
+        .into_iter()
+        .map(|mut witness| witness.remove(0).to_string())
+        .collect();
+    Coverage { missing, redundant }
+}
+
+struct CoverageChecker<'a, S> {
+    signatures: &'a S,
+}
+
+impl<'a, S: Signatures> CoverageChecker<'a, S> {
+    /// All the heads a value could have, if there's finitely many of them,
+    /// given one of them. Includes the number of fields for every head.
+    fn signature(&self, head: &Head) -> Option<Vec<(Head, usize)>> {
+        match head {
+            Head::Tuple => Some(vec![(Head::Tuple, 2)]),
+            Head::Literal(Literal::Bool(_)) => Some(vec![
+                (Head::Literal(Literal::Bool(true)), 0),
+                (Head::Literal(Literal::Bool(false)), 0),
+            ]),
+            Head::Literal(Literal::Int(_)) => None,
+            Head::Constructor { ty, .. } => {
+                let dtors = self.signatures.constructors(ty)?;
+                Some(
+                    dtors
+                        .iter()
+                        .map(|dtor| {
+                            (
+                                Head::Constructor {
+                                    ty: ty.clone(),
+                                    name: dtor.name.clone(),
+                                },
+                                dtor.fields.len(),
+                            )
+                        })
+                        .collect(),
+                )
+            }
+        }
+    }
+
+    /// The distinct heads in the first column of the matrix
+    fn heads(&self, rows: &[Row]) -> Vec<Head> {
+        let mut heads = vec![];
+        for row in rows {
+            if let Pat::Head(head, _) = &row[0] {
+                if !heads.contains(head) {
+                    heads.push(head.clone())
+                }
+            }
+        }
+        heads
+    }
+
+    /// The signature of the first column, if it's completely covered by the
+    /// heads in it.
+    fn complete_signature(&self, heads: &[Head]) -> Option<Vec<(Head, usize)>> {
+        let signature = self.signature(heads.first()?)?;
+        if signature.iter().all(|(head, _)| heads.contains(head)) {
+            Some(signature)
+        } else {
+            None
+        }
+    }
+
+    /// Keeps the rows that match `head` in their first column, and replaces
+    /// that column with the patterns for its fields.
+    fn specialize(&self, rows: &[Row], head: &Head, arity: usize) -> Vec<Row> {
+        rows.iter()
+            .filter_map(|row| {
+                let mut fields = match &row[0] {
+                    Pat::Wildcard => vec![Pat::Wildcard; arity],
+                    Pat::Head(h, fields) if h == head => fields.clone(),
+                    Pat::Head(_, _) => return None,
+                };
+                fields.extend(row[1..].iter().cloned());
+                Some(fields)
+            })
+            .collect()
+    }
+
+    /// The rows that match anything in their first column, without it
+    fn default_matrix(&self, rows: &[Row]) -> Vec<Row> {
+        rows.iter()
+            .filter(|row| row[0] == Pat::Wildcard)
+            .map(|row| row[1..].to_vec())
+            .collect()
+    }
+
+    /// Whether `row` matches any values none of the `rows` match
+    fn useful(&self, rows: &[Row], row: &[Pat]) -> bool {
+        if row.is_empty() {
+            return rows.is_empty();
+        }
+        match &row[0] {
+            Pat::Head(head, fields) => {
+                let mut specialized_row = fields.clone();
+                specialized_row.extend(row[1..].iter().cloned());
+                self.useful(&self.specialize(rows, head, fields.len()), &specialized_row)
+            }
+            Pat::Wildcard => match self.complete_signature(&self.heads(rows)) {
+                Some(signature) => signature.into_iter().any(|(head, arity)| {
+                    let mut specialized_row = vec![Pat::Wildcard; arity];
+                    specialized_row.extend(row[1..].iter().cloned());
+                    self.useful(&self.specialize(rows, &head, arity), &specialized_row)
+                }),
+                None => self.useful(&self.default_matrix(rows), &row[1..]),
+            },
+        }
+    }
+
+    /// Rows of `width` patterns matching values that none of the `rows` match
+    fn missing(&self, rows: &[Row], width: usize) -> Vec<Row> {
+        if width == 0 {
+            return if rows.is_empty() {
+                vec![vec![]]
+            } else {
+                vec![]
+            };
+        }
+        let heads = self.heads(rows);
+        if let Some(signature) = self.complete_signature(&heads) {
+            let mut result = vec![];
+            for (head, arity) in signature {
+                let specialized = self.specialize(rows, &head, arity);
+                for mut witness in self.missing(&specialized, arity + width - 1) {
+                    let rest = witness.split_off(arity);
+                    let mut row = vec![Pat::Head(head.clone(), witness)];
+                    row.extend(rest);
+                    result.push(row)
+                }
+            }
+            return result;
+        }
+
+        let witnesses = self.missing(&self.default_matrix(rows), width - 1);
+        if witnesses.is_empty() {
+            return vec![];
+        }
+        // Figure out which heads we could put in front of the witnesses
+        let prefixes: Vec<Pat> = match heads.first().and_then(|head| self.signature(head)) {
+            Some(signature) => signature
+                .into_iter()
+                .filter(|(head, _)| !heads.contains(head))
+                .map(|(head, arity)| Pat::Head(head, vec![Pat::Wildcard; arity]))
+                .collect(),
+            None => vec![Pat::Wildcard],
+        };
+        let mut result = vec![];
+        for prefix in prefixes {
+            for witness in witnesses.iter() {
+                let mut row = vec![prefix.clone()];
+                row.extend(witness.iter().cloned());
+                result.push(row)
+            }
+        }
+        result
+    }
+}
+
+/// A flat pattern, that only looks at the head of a value and binds its
+/// fields to variables.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum SimplePattern {
+    Constructor { dtor: Dtor, binders: Vec<String> },
+    Literal(Literal),
+    Tuple(String, String),
+}
+
+/// A decision tree, the result of compiling a match with nested patterns.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Decision<B> {
+    /// Evaluates the body of a case. All the variables its pattern bound
+    /// have been renamed to the variables holding the matched values.
+    Leaf(Expr<B>),
+    /// Inspects the value in `scrutinee` and continues with the first case
+    /// that matches it, or the default if none does. A missing default means
+    /// the cases are exhaustive.
+    Switch {
+        scrutinee: String,
+        cases: Vec<(SimplePattern, Decision<B>)>,
+        default: Option<Box<Decision<B>>>,
+    },
+}
+
+/// A case in the middle of being compiled. Every column tests the value in
+/// a variable against a pattern.
+#[derive(Clone)]
+struct Clause<B> {
+    columns: Vec<(String, Pattern<B>)>,
+    body: Expr<B>,
+}
+
+impl<B: HasIdent + Clone> Clause<B> {
+    /// Turns binders into renamings in the body, and drops the columns that
+    /// match anything.
+    fn bind_variables(self) -> Clause<B> {
+        let mut body = self.body;
+        let mut columns = vec![];
+        for (var, pattern) in self.columns {
+            let mut pattern = pattern;
+            loop {
+                match pattern {
+                    Pattern::Var(binder, _) => {
+                        body = body.subst_var(&binder.ident(), &var);
+                        break;
+                    }
+                    Pattern::As {
+                        binder,
+                        pattern: inner,
+                        ..
+                    } => {
+                        body = body.subst_var(&binder.ident(), &var);
+                        pattern = *inner;
+                    }
+                    Pattern::Wildcard(_) => break,
+                    pattern => {
+                        columns.push((var, pattern));
+                        break;
+                    }
+                }
+            }
+        }
+        Clause { columns, body }
+    }
+
+    fn column(&self, var: &str) -> Option<&Pattern<B>> {
+        self.columns
+            .iter()
+            .find(|(v, _)| v == var)
+            .map(|(_, pattern)| pattern)
+    }
+
+    /// Replaces the test on `var` with tests on the variables holding its
+    /// fields.
+    fn expand(mut self, var: &str, fields: Vec<(String, Pattern<B>)>) -> Clause<B> {
+        let ix = self.columns.iter().position(|(v, _)| v == var).unwrap();
+        self.columns.splice(ix..=ix, fields);
+        self
+    }
+}
+
+/// Compiles the cases of a match on the value in the variable `scrutinee`
+/// into a decision tree. Cases are tried in order. Variables for the fields
+/// of matched values are created with `fresh`.
+pub fn compile_match<B, S, F>(
+    signatures: &S,
+    scrutinee: &str,
+    cases: Vec<Case<B>>,
+    fresh: &mut F,
+) -> Decision<B>
+where
+    B: HasIdent + Clone,
+    S: Signatures,
+    F: FnMut(&str) -> String,
+{
+    let clauses = cases
+        .into_iter()
+        .map(|case| Clause {
+            columns: vec![(scrutinee.to_string(), case.pattern)],
+            body: case.expr,
+        })
+        .collect();
+    let compiler = MatchCompiler { signatures };
+    compiler
+        .compile(clauses, fresh)
+        .unwrap_or(Decision::Switch {
+            scrutinee: scrutinee.to_string(),
+            cases: vec![],
+            default: None,
+        })
+}
+
+struct MatchCompiler<'a, S> {
+    signatures: &'a S,
+}
+
+impl<'a, S: Signatures> MatchCompiler<'a, S> {
+    /// Returns `None` if there are no clauses left to match
+    fn compile<B, F>(&self, clauses: Vec<Clause<B>>, fresh: &mut F) -> Option<Decision<B>>
+    where
+        B: HasIdent + Clone,
+        F: FnMut(&str) -> String,
+    {
+        let clauses: Vec<Clause<B>> = clauses.into_iter().map(Clause::bind_variables).collect();
+        let first = clauses.first()?;
+        let (var, pattern) = match first.columns.first() {
+            None => return Some(Decision::Leaf(clauses.into_iter().next().unwrap().body)),
+            Some((var, pattern)) => (var.clone(), pattern.clone()),
+        };
+
+        match pattern {
+            Pattern::Tuple(..) => {
+                let fst = fresh("fst");
+                let snd = fresh("snd");
+                let expanded = clauses
+                    .into_iter()
+                    .map(|clause| match clause.column(&var).cloned() {
+                        Some(Pattern::Tuple(p1, p2, _)) => {
+                            clause.expand(&var, vec![(fst.clone(), *p1), (snd.clone(), *p2)])
+                        }
+                        _ => clause,
+                    })
+                    .collect();
+                let decision = self.compile(expanded, fresh)?;
+                Some(Decision::Switch {
+                    scrutinee: var,
+                    cases: vec![(SimplePattern::Tuple(fst, snd), decision)],
+                    default: None,
+                })
+            }
+            Pattern::Constructor { ref dtor, .. } => {
+                let mut dtors: Vec<Dtor> = vec![];
+                for clause in clauses.iter() {
+                    if let Some(Pattern::Constructor { dtor, .. }) = clause.column(&var) {
+                        if !dtors.iter().any(|d| d.name == dtor.name) {
+                            dtors.push(dtor.clone())
+                        }
+                    }
+                }
+                let complete = match self.signatures.constructors(&dtor.ty) {
+                    Some(all) => all.iter().all(|c| dtors.iter().any(|d| d.name == c.name)),
+                    None => false,
+                };
+                let mut cases = vec![];
+                for dtor in dtors {
+                    let mut binders = vec![];
+                    let specialized = clauses
+                        .iter()
+                        .filter_map(|clause| match clause.column(&var) {
+                            None => Some(clause.clone()),
+                            Some(Pattern::Constructor {
+                                dtor: d, fields, ..
+                            }) if d.name == dtor.name => {
+                                while binders.len() < fields.len() {
+                                    binders.push(fresh("field"))
+                                }
+                                let fields = binders.iter().cloned().zip(fields.clone()).collect();
+                                Some(clause.clone().expand(&var, fields))
+                            }
+                            Some(_) => None,
+                        })
+                        .collect();
+                    let decision = self.compile(specialized, fresh)?;
+                    cases.push((SimplePattern::Constructor { dtor, binders }, decision));
+                }
+                let default = if complete {
+                    None
+                } else {
+                    self.compile(self.default_clauses(&clauses, &var), fresh)
+                };
+                Some(Decision::Switch {
+                    scrutinee: var,
+                    cases,
+                    default: default.map(Box::new),
+                })
+            }
+            Pattern::Literal(..) => {
+                let mut literals: Vec<Literal> = vec![];
+                for clause in clauses.iter() {
+                    if let Some(Pattern::Literal(lit, _)) = clause.column(&var) {
+                        if !literals.contains(lit) {
+                            literals.push(lit.clone())
+                        }
+                    }
+                }
+                let complete = literals.contains(&Literal::Bool(true))
+                    && literals.contains(&Literal::Bool(false));
+                let mut cases = vec![];
+                for lit in literals {
+                    let specialized = clauses
+                        .iter()
+                        .filter_map(|clause| match clause.column(&var) {
+                            None => Some(clause.clone()),
+                            Some(Pattern::Literal(l, _)) if *l == lit => {
+                                Some(clause.clone().expand(&var, vec![]))
+                            }
+                            Some(_) => None,
+                        })
+                        .collect();
+                    let decision = self.compile(specialized, fresh)?;
+                    cases.push((SimplePattern::Literal(lit), decision));
+                }
+                let default = if complete {
+                    None
+                } else {
+                    self.compile(self.default_clauses(&clauses, &var), fresh)
+                };
+                Some(Decision::Switch {
+                    scrutinee: var,
+                    cases,
+                    default: default.map(Box::new),
+                })
+            }
+            Pattern::Wildcard(_) | Pattern::Var(..) | Pattern::As { .. } => {
+                unreachable!("bind_variables removes irrefutable columns")
+            }
+        }
+    }
+
+    /// The clauses that don't test `var`
+    fn default_clauses<B: Clone>(&self, clauses: &[Clause<B>], var: &str) -> Vec<Clause<B>> {
+        clauses
+            .iter()
+            .filter(|clause| clause.columns.iter().all(|(v, _)| v != var))
+            .cloned()
+            .collect()
+    }
+}
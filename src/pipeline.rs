@@ -1,14 +1,21 @@
 use crate::bi_types::{TypeChecker, TypeError};
 use crate::codegen::{Codegen, CodegenError, Lowering};
-use crate::expr::{Declaration, ValueDeclaration};
+use crate::expr::{Declaration, Expr, NewVar, ValueDeclaration};
 use crate::grammar;
+use crate::ir;
+use crate::ir::IR;
+use crate::span::{FileId, Position, Span};
 use crate::term::{EvalError, Term};
 use crate::token;
+use crate::token::{LexError, Token};
 use crate::types;
 use crate::wasm;
+use lalrpop_util::ParseError;
+use std::fmt;
 use std::path::Path;
+use wasmi::RuntimeValue;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum Backend<'a> {
     Term,
     WasmRun,
@@ -17,19 +24,169 @@ pub enum Backend<'a> {
 
 #[derive(Debug)]
 pub enum PipelineError {
-    ParseError(String),
+    LexError(Span, LexError),
+    ParseError(Span, String),
     TypeError(TypeError),
     NewTypeError(types::TypeError),
     EvalError(EvalError),
     CodegenError(CodegenError),
     WasmError(String),
+    /// Reported when a phase recovers from errors and keeps going
+    Multiple(Vec<PipelineError>),
+}
+
+impl PipelineError {
+    /// The source range this error points at, if it's known
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            PipelineError::LexError(span, _) => Some(*span),
+            PipelineError::ParseError(span, _) => Some(*span),
+            PipelineError::TypeError(err) => err.span(),
+            PipelineError::NewTypeError(err) => err.span(),
+            PipelineError::EvalError(err) => err.span(),
+            PipelineError::CodegenError(err) => err.span(),
+            PipelineError::WasmError(_) => None,
+            PipelineError::Multiple(errs) => errs.iter().find_map(|err| err.span()),
+        }
+    }
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PipelineError::LexError(_, err) => write!(f, "{}", err),
+            PipelineError::ParseError(_, message) => write!(f, "{}", message),
+            PipelineError::TypeError(err) => write!(f, "{}", err),
+            PipelineError::NewTypeError(err) => write!(f, "{}", err),
+            PipelineError::EvalError(err) => write!(f, "{}", err),
+            PipelineError::CodegenError(err) => write!(f, "{}", err),
+            PipelineError::WasmError(err) => write!(f, "{}", err),
+            PipelineError::Multiple(errs) => {
+                for (ix, err) in errs.iter().enumerate() {
+                    if ix > 0 {
+                        writeln!(f)?
+                    }
+                    write!(f, "{}", err)?
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn parse_error(file: FileId, err: ParseError<Position, Token, LexError>) -> PipelineError {
+    let (span, message) = match err {
+        ParseError::InvalidToken { location } => (
+            Span::new(file, location, location),
+            "Invalid token".to_string(),
+        ),
+        ParseError::UnrecognizedEOF { location, expected } => (
+            Span::new(file, location, location),
+            format!(
+                "Unexpected end of input, expected one of: {}",
+                expected.join(", ")
+            ),
+        ),
+        ParseError::UnrecognizedToken {
+            token: (start, token, end),
+            expected,
+        } => (
+            Span::new(file, start, end),
+            format!(
+                "Unexpected token `{}`, expected one of: {}",
+                token,
+                expected.join(", ")
+            ),
+        ),
+        ParseError::ExtraToken {
+            token: (start, token, end),
+        } => (
+            Span::new(file, start, end),
+            format!("Unexpected extra token `{}`", token),
+        ),
+        ParseError::User { error } => {
+            return PipelineError::LexError(Span::new(file, error.start, error.end), error)
+        }
+    };
+    PipelineError::ParseError(span, message)
+}
+
+/// Parses a program, recovering from syntax errors at declaration
+/// boundaries. Returns the declarations we could make sense of, with
+/// `Declaration::Error` in place of the broken ones, and every error we ran
+/// into along the way.
+pub fn parse(file: FileId, input: &str) -> (Vec<Declaration<String>>, Vec<PipelineError>) {
+    // Lex errors become error tokens, so the parser recovers from them and
+    // keeps going
+    let mut lex_errors = vec![];
+    let lexer = token::Lexer::new(input).map(|token| {
+        token.or_else(|err| {
+            let (start, end) = (err.start, err.end);
+            lex_errors.push(PipelineError::LexError(Span::new(file, start, end), err));
+            Ok((start, Token::Error, end))
+        })
+    });
+    let mut recovered = vec![];
+    let res = grammar::ProgramParser::new().parse(file, &mut recovered, lexer);
+    let mut errors: Vec<PipelineError> = recovered
+        .into_iter()
+        .map(|recovery| recovery.error)
+        .chain(res.as_ref().err().cloned())
+        .filter(|err| !is_error_token(err))
+        .map(|err| parse_error(file, err))
+        .chain(lex_errors)
+        .collect();
+    errors.sort_by_key(|err| err.span().map(|span| span.start));
+    (res.unwrap_or_default(), errors)
+}
+
+/// Whether the parser choked on an error token, which we've reported as a
+/// lex error already
+fn is_error_token(err: &ParseError<Position, Token, LexError>) -> bool {
+    match err {
+        ParseError::UnrecognizedToken { token, .. } | ParseError::ExtraToken { token } => {
+            token.1 == Token::Error
+        }
+        _ => false,
+    }
+}
+
+/// Like `parse`, but fails if there were any syntax errors
+pub fn parse_program(file: FileId, input: &str) -> Result<Vec<Declaration<String>>, PipelineError> {
+    let (prog, mut errors) = parse(file, input);
+    match errors.len() {
+        0 => Ok(prog),
+        1 => Err(errors.remove(0)),
+        _ => Err(PipelineError::Multiple(errors)),
+    }
+}
+
+/// Parses a single expression
+pub fn parse_expr(file: FileId, input: &str) -> Result<Expr<String>, PipelineError> {
+    let lexer = token::Lexer::new(input);
+    grammar::ExprParser::new()
+        .parse(file, &mut vec![], lexer)
+        .map_err(|err| parse_error(file, err))
 }
 
 pub fn run_program(input: &str, backend: Backend) -> Result<String, PipelineError> {
-    let lexer = token::Lexer::new(&input);
-    let prog = grammar::ProgramParser::new()
-        .parse(lexer)
-        .map_err(|err| PipelineError::ParseError(format!("Parse failure: {:?}", err)))?;
+    run_file(FileId::default(), input, backend)
+}
+
+/// Parses and type checks a program. Returns the typed declarations and
+/// the warnings the type checker came up with.
+#[allow(clippy::type_complexity)]
+pub fn typecheck(
+    file: FileId,
+    input: &str,
+) -> Result<
+    (
+        Vec<(Declaration<NewVar>, types::Type)>,
+        Vec<types::TypeWarning>,
+    ),
+    PipelineError,
+> {
+    let prog = parse_program(file, input)?;
     // For when running without a type checker
     // let tys: Vec<(Declaration<String>, u32)> = prog.into_iter().map(|d| (d, 42)).collect();
     // For when running the bidirectional type checker
@@ -43,10 +200,123 @@ pub fn run_program(input: &str, backend: Backend) -> Result<String, PipelineErro
     let tys = type_checker
         .infer_prog(prog)
         .map_err(PipelineError::NewTypeError)?;
+    Ok((tys, type_checker.take_warnings()))
+}
+
+/// Lowers a typed program to the closure converted IR the wasm backend
+/// generates code from
+pub fn lower(tys: Vec<(Declaration<NewVar>, types::Type)>) -> Result<IR, PipelineError> {
+    let lowered = Lowering::new()
+        .lower(tys)
+        .map_err(PipelineError::CodegenError)?;
+    Ok(ir::closure_convert(lowered))
+}
+
+/// Compiles a type checked program to WAT
+pub fn codegen(tys: Vec<(Declaration<NewVar>, types::Type)>) -> Result<String, PipelineError> {
+    Ok(Codegen::new().codegen(lower(tys)?))
+}
+
+/// Compiles a program to WAT
+pub fn compile(file: FileId, input: &str) -> Result<String, PipelineError> {
+    let (tys, _) = typecheck(file, input)?;
+    codegen(tys)
+}
+
+/// The intermediate results of the pipeline, which can be dumped to debug
+/// the compiler
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Phase {
+    /// The declarations, as they came out of the parser
+    Ast,
+    /// The declarations with every binder annotated with its type
+    Typed,
+    /// The closure converted IR
+    Ir,
+    /// The generated WAT
+    Wat,
+}
+
+impl Phase {
+    pub const ALL: &'static [Phase] = &[Phase::Ast, Phase::Typed, Phase::Ir, Phase::Wat];
+
+    pub fn from_name(name: &str) -> Option<Phase> {
+        Phase::ALL
+            .iter()
+            .cloned()
+            .find(|phase| phase.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Ast => "ast",
+            Phase::Typed => "typed",
+            Phase::Ir => "ir",
+            Phase::Wat => "wat",
+        }
+    }
+}
+
+/// Runs the pipeline up to `phase`, and renders its result
+pub fn dump(file: FileId, input: &str, phase: Phase) -> Result<String, PipelineError> {
+    let res = match phase {
+        Phase::Ast => {
+            let (prog, errors) = parse(file, input);
+            if !errors.is_empty() {
+                return Err(PipelineError::Multiple(errors));
+            }
+            render_declarations(prog.iter().map(|decl| decl.to_string()))
+        }
+        Phase::Typed => {
+            let (tys, _) = typecheck(file, input)?;
+            render_declarations(tys.iter().map(|(decl, ty)| match decl {
+                Declaration::Value(vd) => format!("{} : {}\n{}", vd.name, ty, decl),
+                _ => decl.to_string(),
+            }))
+        }
+        Phase::Ir => {
+            let (tys, _) = typecheck(file, input)?;
+            lower(tys)?.to_string()
+        }
+        Phase::Wat => wasm::pretty_wat(&compile(file, input)?),
+    };
+    Ok(res)
+}
+
+fn render_declarations(decls: impl Iterator<Item = String>) -> String {
+    decls.collect::<Vec<_>>().join("\n\n")
+}
+
+/// Like `run_program`, but the spans in any errors point into `file`
+pub fn run_file(file: FileId, input: &str, backend: Backend) -> Result<String, PipelineError> {
+    let (tys, _) = typecheck(file, input)?;
+    run_checked(tys, backend)
+}
+
+/// Prints what `main` returned like the term backend would. Ints and bools
+/// come back untagged, anything else lives on the heap and we can only say
+/// what type it has.
+fn wasm_value(res: Option<RuntimeValue>, ty: Option<types::Type>) -> String {
+    match (res, ty) {
+        (Some(RuntimeValue::I32(n)), Some(ty)) if ty == types::Type::int() => n.to_string(),
+        (Some(RuntimeValue::I32(n)), Some(ty)) if ty == types::Type::bool() => (n != 0).to_string(),
+        (Some(_), Some(ty)) => format!("<{}>", ty),
+        (res, _) => format!("{:?}", res),
+    }
+}
 
+/// Runs a type checked program with `backend`
+pub fn run_checked(
+    tys: Vec<(Declaration<NewVar>, types::Type)>,
+    backend: Backend,
+) -> Result<String, PipelineError> {
+    let mut main_ty = None;
     for (decl, ty) in tys.iter() {
         if let Declaration::Value(vd) = decl {
-            info!("{} : {}", vd.name, ty)
+            info!("{} : {}", vd.name, ty);
+            if vd.name == "main" {
+                main_ty = Some(ty.clone())
+            }
         }
     }
     match backend {
@@ -56,21 +326,14 @@ pub fn run_program(input: &str, backend: Backend) -> Result<String, PipelineErro
             Ok(format!("{}", res))
         }
         Backend::WasmRun => {
-            let lowered = Lowering::new()
-                .lower(tys)
-                .map_err(PipelineError::CodegenError)?;
-            let prog = Codegen::new().codegen(lowered);
-            // info!("{}", wasm::pretty_wat(&prog));
+            let prog = codegen(tys)?;
             let res =
                 wasm::run_wasm(prog).map_err(|err| PipelineError::WasmError(format!("{}", err)))?;
-            Ok(format!("{:?}", res))
+            Ok(wasm_value(res, main_ty))
         }
         Backend::Wasm(path) => {
-            let lowered = Lowering::new()
-                .lower(tys)
-                .map_err(PipelineError::CodegenError)?;
-            let prog = Codegen::new().codegen(lowered);
-            wasm::output_wasm(prog, path);
+            let prog = codegen(tys)?;
+            wasm::output_wasm(prog, path).map_err(PipelineError::WasmError)?;
             Ok(format!("Created wasm at: {}", path.display()))
         }
     }
@@ -0,0 +1,286 @@
+use crate::bi_types::Type;
+use crate::expr::Literal;
+use crate::grammar::TypeParser;
+use crate::span::FileId;
+use crate::term::{EvalError, Term};
+use crate::token::Lexer;
+
+/// A function that's built into the language. Primitives are in scope
+/// everywhere, unless a binding with the same name shadows them.
+#[derive(Debug)]
+pub struct Primitive {
+    pub name: &'static str,
+    pub arity: usize,
+    /// The primitive's type, written like a type annotation
+    pub type_scheme: &'static str,
+    /// Runs the primitive in the term interpreter, once it's been applied to
+    /// `arity` arguments
+    pub eval: fn(Vec<Term>) -> Result<Term, EvalError>,
+    /// The wasm instructions implementing the primitive. They expect the
+    /// arguments on the stack and leave the result there. Ints and bools are
+    /// tagged, see `codegen::tagged`.
+    pub wasm: &'static str,
+}
+
+impl Primitive {
+    pub fn ty(&self) -> Type {
+        TypeParser::new()
+            .parse(FileId::default(), &mut vec![], Lexer::new(self.type_scheme))
+            .expect("Invalid type scheme for primitive")
+    }
+}
+
+pub const PRIMITIVES: &[Primitive] = &[
+    Primitive {
+        name: "add",
+        arity: 2,
+        type_scheme: "Int -> Int -> Int",
+        eval: add,
+        wasm: "i32.add i32.const 1 i32.sub",
+    },
+    Primitive {
+        name: "sub",
+        arity: 2,
+        type_scheme: "Int -> Int -> Int",
+        eval: sub,
+        wasm: "i32.sub i32.const 1 i32.add",
+    },
+    Primitive {
+        name: "mul",
+        arity: 2,
+        type_scheme: "Int -> Int -> Int",
+        eval: mul,
+        wasm: "call $int_mul",
+    },
+    Primitive {
+        name: "div",
+        arity: 2,
+        type_scheme: "Int -> Int -> Int",
+        eval: div,
+        wasm: "call $int_div",
+    },
+    Primitive {
+        name: "mod",
+        arity: 2,
+        type_scheme: "Int -> Int -> Int",
+        eval: modulo,
+        wasm: "call $int_rem",
+    },
+    Primitive {
+        name: "eq",
+        arity: 2,
+        type_scheme: "Int -> Int -> Bool",
+        eval: eq,
+        wasm: "i32.eq call $tag_int",
+    },
+    Primitive {
+        name: "neq",
+        arity: 2,
+        type_scheme: "Int -> Int -> Bool",
+        eval: neq,
+        wasm: "i32.ne call $tag_int",
+    },
+    Primitive {
+        name: "lt",
+        arity: 2,
+        type_scheme: "Int -> Int -> Bool",
+        eval: lt,
+        wasm: "i32.lt_s call $tag_int",
+    },
+    Primitive {
+        name: "lte",
+        arity: 2,
+        type_scheme: "Int -> Int -> Bool",
+        eval: lte,
+        wasm: "i32.le_s call $tag_int",
+    },
+    Primitive {
+        name: "gt",
+        arity: 2,
+        type_scheme: "Int -> Int -> Bool",
+        eval: gt,
+        wasm: "i32.gt_s call $tag_int",
+    },
+    Primitive {
+        name: "gte",
+        arity: 2,
+        type_scheme: "Int -> Int -> Bool",
+        eval: gte,
+        wasm: "i32.ge_s call $tag_int",
+    },
+    Primitive {
+        name: "not",
+        arity: 1,
+        type_scheme: "Bool -> Bool",
+        eval: not,
+        wasm: "i32.const 2 i32.xor",
+    },
+    Primitive {
+        name: "and",
+        arity: 2,
+        type_scheme: "Bool -> Bool -> Bool",
+        eval: and,
+        wasm: "i32.and",
+    },
+    Primitive {
+        name: "or",
+        arity: 2,
+        type_scheme: "Bool -> Bool -> Bool",
+        eval: or,
+        wasm: "i32.or",
+    },
+    Primitive {
+        name: "fst",
+        arity: 1,
+        type_scheme: "forall a b. (a, b) -> a",
+        eval: fst,
+        wasm: "i32.const 0 call $get_pack_field",
+    },
+    Primitive {
+        name: "snd",
+        arity: 1,
+        type_scheme: "forall a b. (a, b) -> b",
+        eval: snd,
+        wasm: "i32.const 1 call $get_pack_field",
+    },
+];
+
+const QUALIFIER: &str = "prim:";
+
+/// Primitives can also be referred to by a qualified name. Identifiers
+/// can't contain a `:`, so these can't be shadowed by user definitions.
+pub fn qualified(name: &str) -> String {
+    format!("{}{}", QUALIFIER, name)
+}
+
+/// Finds a primitive by its plain or qualified name
+pub fn lookup(name: &str) -> Option<&'static Primitive> {
+    let name = name.strip_prefix(QUALIFIER).unwrap_or(name);
+    PRIMITIVES.iter().find(|prim| prim.name == name)
+}
+
+fn int(i: i32) -> Term {
+    Term::Literal(Literal::Int(i))
+}
+
+fn bool(b: bool) -> Term {
+    Term::Literal(Literal::Bool(b))
+}
+
+fn ints(name: &str, args: Vec<Term>) -> Result<(i32, i32), EvalError> {
+    match args.as_slice() {
+        [Term::Literal(Literal::Int(x)), Term::Literal(Literal::Int(y))] => Ok((*x, *y)),
+        _ => Err(EvalError::InvalidPrimitiveArguments(name.to_string(), args)),
+    }
+}
+
+fn bools(name: &str, args: Vec<Term>) -> Result<(bool, bool), EvalError> {
+    match args.as_slice() {
+        [Term::Literal(Literal::Bool(x)), Term::Literal(Literal::Bool(y))] => Ok((*x, *y)),
+        _ => Err(EvalError::InvalidPrimitiveArguments(name.to_string(), args)),
+    }
+}
+
+// Arithmetic wraps around on overflow. Tagging leaves the wasm backend with
+// 31 bit ints, so it wraps around earlier.
+
+fn add(args: Vec<Term>) -> Result<Term, EvalError> {
+    let (x, y) = ints("add", args)?;
+    Ok(int(x.wrapping_add(y)))
+}
+
+fn sub(args: Vec<Term>) -> Result<Term, EvalError> {
+    let (x, y) = ints("sub", args)?;
+    Ok(int(x.wrapping_sub(y)))
+}
+
+fn mul(args: Vec<Term>) -> Result<Term, EvalError> {
+    let (x, y) = ints("mul", args)?;
+    Ok(int(x.wrapping_mul(y)))
+}
+
+fn div(args: Vec<Term>) -> Result<Term, EvalError> {
+    match ints("div", args)? {
+        (_, 0) => Err(EvalError::DivisionByZero),
+        (x, y) => Ok(int(x.wrapping_div(y))),
+    }
+}
+
+fn modulo(args: Vec<Term>) -> Result<Term, EvalError> {
+    match ints("mod", args)? {
+        (_, 0) => Err(EvalError::DivisionByZero),
+        (x, y) => Ok(int(x.wrapping_rem(y))),
+    }
+}
+
+fn eq(args: Vec<Term>) -> Result<Term, EvalError> {
+    let (x, y) = ints("eq", args)?;
+    Ok(bool(x == y))
+}
+
+fn neq(args: Vec<Term>) -> Result<Term, EvalError> {
+    let (x, y) = ints("neq", args)?;
+    Ok(bool(x != y))
+}
+
+fn lt(args: Vec<Term>) -> Result<Term, EvalError> {
+    let (x, y) = ints("lt", args)?;
+    Ok(bool(x < y))
+}
+
+fn lte(args: Vec<Term>) -> Result<Term, EvalError> {
+    let (x, y) = ints("lte", args)?;
+    Ok(bool(x <= y))
+}
+
+fn gt(args: Vec<Term>) -> Result<Term, EvalError> {
+    let (x, y) = ints("gt", args)?;
+    Ok(bool(x > y))
+}
+
+fn gte(args: Vec<Term>) -> Result<Term, EvalError> {
+    let (x, y) = ints("gte", args)?;
+    Ok(bool(x >= y))
+}
+
+fn not(args: Vec<Term>) -> Result<Term, EvalError> {
+    match args.as_slice() {
+        [Term::Literal(Literal::Bool(b))] => Ok(bool(!b)),
+        _ => Err(EvalError::InvalidPrimitiveArguments(
+            "not".to_string(),
+            args,
+        )),
+    }
+}
+
+fn and(args: Vec<Term>) -> Result<Term, EvalError> {
+    let (x, y) = bools("and", args)?;
+    Ok(bool(x && y))
+}
+
+fn or(args: Vec<Term>) -> Result<Term, EvalError> {
+    let (x, y) = bools("or", args)?;
+    Ok(bool(x || y))
+}
+
+fn fst(mut args: Vec<Term>) -> Result<Term, EvalError> {
+    match args.pop() {
+        Some(Term::Pack { mut values, .. }) if values.len() == 2 => Ok(values.swap_remove(0)),
+        Some(term) => Err(EvalError::ProjectingFst(term)),
+        None => Err(EvalError::InvalidPrimitiveArguments(
+            "fst".to_string(),
+            args,
+        )),
+    }
+}
+
+fn snd(mut args: Vec<Term>) -> Result<Term, EvalError> {
+    match args.pop() {
+        Some(Term::Pack { mut values, .. }) if values.len() == 2 => Ok(values.swap_remove(1)),
+        Some(term) => Err(EvalError::ProjectingSnd(term)),
+        None => Err(EvalError::InvalidPrimitiveArguments(
+            "snd".to_string(),
+            args,
+        )),
+    }
+}
@@ -1,54 +1,622 @@
-use crate::bi_types::{Type, TypeChecker, TypeError};
-use crate::grammar;
-use crate::term;
-use crate::term::Term;
-use crate::token;
+use crate::diagnostics::{diagnostics, Diagnostic, Files};
+use crate::expr::{Declaration, Expr, NewVar, ValueDeclaration};
+use crate::pipeline::{self, Backend, Phase, PipelineError};
+use crate::primitives::PRIMITIVES;
+use crate::span::FileId;
+use crate::term::Evaluator;
+use crate::token::{Lexer, Token};
+use crate::types::{self, TypeChecker};
+use crate::wasm;
+use rustyline::completion::Completer;
 use rustyline::error::ReadlineError;
-use rustyline::Editor;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::{Context, Editor, Helper};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
 
-fn print_ty_res(ty_res: Result<Type, TypeError>) -> String {
-    match ty_res {
-        Err(err) => err.print(),
-        Ok(ty) => format!("{}", ty),
+/// The wasm backend runs expressions by compiling them as `main`, together
+/// with all declarations entered so far
+const ENTRY_POINT: &str = "main";
+
+type TypedDeclaration = (Declaration<NewVar>, types::Type);
+
+const HELP: &str = "Enter declarations to add them to the session, or expressions to evaluate them.
+
+Commands:
+    :type <expr>          Shows the type of an expression, without evaluating it
+    :load <file>          Replaces the session with the declarations in a file
+    :reload               Loads the last loaded file again
+    :browse               Lists the types and values declared so far
+    :backend [term|wasm]  Shows or switches the backend expressions run on
+    :dump <phase> <expr>  Shows an expression after one of the compiler's
+                          phases, one of: ast, typed, ir, wat
+    :help                 Shows this message";
+
+/// The commands `HELP` lists, for completion
+const COMMANDS: &[&str] = &[
+    ":type", ":load", ":reload", ":browse", ":backend", ":dump", ":help",
+];
+
+#[derive(Debug)]
+pub enum ReplError {
+    PipelineError(PipelineError),
+    /// A command we couldn't make sense of, or that failed
+    CommandError(String),
+}
+
+impl From<PipelineError> for ReplError {
+    fn from(err: PipelineError) -> ReplError {
+        ReplError::PipelineError(err)
     }
 }
 
-fn print_eval_res(res: Result<Term, term::EvalError>) -> String {
-    match res {
-        Err(err) => err.print(),
-        Ok(term) => format!("{}", term),
+impl fmt::Display for ReplError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplError::PipelineError(err) => write!(f, "{}", err),
+            ReplError::CommandError(message) => write!(f, "{}", message),
+        }
     }
 }
 
-pub fn run_term(input: &str) {
-    let lexer = token::Lexer::new(input);
-    let res = grammar::ExprParser::new().parse(lexer);
-    match res {
-        Err(err) => error!("Parse failure: {:?}", err),
-        Ok(expr) => {
-            info!("Expr:\n{}", expr);
-            let mut type_checker = TypeChecker::new();
-            let ty_res = type_checker.synth(&expr);
-            info!("Inferred: {}", print_ty_res(ty_res));
-            let eval_res = Term::eval_expr(expr);
-            info!("Evaled: {}", print_eval_res(eval_res));
+fn command_error<A>(message: String) -> Result<A, ReplError> {
+    Err(ReplError::CommandError(message))
+}
+
+/// What a line of input turned out to be
+enum Input {
+    Expr(Expr<String>),
+    Declarations(Vec<Declaration<String>>),
+}
+
+/// Everything a REPL session has accumulated. Declarations are checked and
+/// evaluated as they are entered, so later input can refer to them.
+pub struct Session {
+    pub backend: Backend<'static>,
+    /// Every line of input becomes a file, so diagnostics can point into it
+    files: Files,
+    type_checker: TypeChecker,
+    /// The values of the declarations for the term backend
+    evaluator: Evaluator,
+    /// The checked declarations, in the order they were entered. The wasm
+    /// backend compiles these whenever it runs an expression.
+    program: Vec<TypedDeclaration>,
+    supply: u32,
+    /// The file `:reload` loads
+    loaded: Option<PathBuf>,
+}
+
+impl Session {
+    pub fn new(backend: Backend<'static>) -> Session {
+        Session {
+            backend,
+            files: Files::new(),
+            type_checker: TypeChecker::new(),
+            evaluator: Evaluator::new(),
+            program: vec![],
+            supply: 0,
+            loaded: None,
+        }
+    }
+
+    /// Runs a line of input. Declarations report the types of the names they
+    /// bind, expressions report their value and type, and lines starting
+    /// with a `:` are commands. If anything goes wrong the session stays as
+    /// it was.
+    pub fn eval(&mut self, input: &str) -> Result<String, ReplError> {
+        if let Some(command) = input.trim_start().strip_prefix(':') {
+            return self.command(command);
+        }
+        let file = self.files.add("<repl>", input);
+        let res = match parse_input(file, input)? {
+            Input::Expr(expr) => self.eval_expr(expr)?,
+            Input::Declarations(decls) => {
+                let (warnings, bound) = self.declare(decls)?;
+                format!("{}{}", warnings, bound.join("\n"))
+            }
+        };
+        Ok(res)
+    }
+
+    /// Renders an error returned by `eval`
+    pub fn render_error(&self, err: &ReplError) -> String {
+        match err {
+            ReplError::PipelineError(err) => diagnostics(err)
+                .iter()
+                .map(|diagnostic| diagnostic.render(&self.files))
+                .collect(),
+            ReplError::CommandError(message) => format!("error: {}\n", message),
+        }
+    }
+
+    fn command(&mut self, command: &str) -> Result<String, ReplError> {
+        let (name, argument) = match command.find(char::is_whitespace) {
+            Some(ix) => (&command[..ix], command[ix..].trim()),
+            None => (command, ""),
+        };
+        match name {
+            "t" | "type" => {
+                let file = self.files.add("<repl>", argument);
+                let expr = pipeline::parse_expr(file, argument)?;
+                let (_, ty) = self
+                    .type_checker
+                    .clone()
+                    .infer_expr(expr)
+                    .map_err(PipelineError::NewTypeError)?;
+                Ok(format!("{} : {}", argument, ty))
+            }
+            "l" | "load" if !argument.is_empty() => self.load(PathBuf::from(argument)),
+            "r" | "reload" => match self.loaded.clone() {
+                Some(path) => self.load(path),
+                None => command_error("There's no file to reload, use `:load` first".to_string()),
+            },
+            "b" | "browse" => Ok(self.browse()),
+            "backend" => {
+                match argument {
+                    "" => {}
+                    "term" => self.backend = Backend::Term,
+                    "wasm" => self.backend = Backend::WasmRun,
+                    other => {
+                        return command_error(format!(
+                            "Unknown backend `{}`, expected `term` or `wasm`",
+                            other
+                        ))
+                    }
+                }
+                let name = if self.backend == Backend::Term {
+                    "term"
+                } else {
+                    "wasm"
+                };
+                Ok(format!("Using the {} backend", name))
+            }
+            "d" | "dump" => {
+                let (phase, expr) = match argument.find(char::is_whitespace) {
+                    Some(ix) => (&argument[..ix], argument[ix..].trim()),
+                    None => (argument, ""),
+                };
+                match Phase::from_name(phase) {
+                    Some(phase) if !expr.is_empty() => self.dump(phase, expr),
+                    _ => command_error("Usage: `:dump ast|typed|ir|wat <expr>`".to_string()),
+                }
+            }
+            "h" | "help" | "?" => Ok(HELP.to_string()),
+            _ => command_error(format!(
+                "Unknown command `:{}`, `:help` lists the commands",
+                command.trim()
+            )),
+        }
+    }
+
+    /// Replaces everything declared so far with the declarations in `path`
+    fn load(&mut self, path: PathBuf) -> Result<String, ReplError> {
+        let source = match fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(err) => {
+                return command_error(format!("Failed to read {}: {}", path.display(), err))
+            }
+        };
+        let file = self.files.add(&path.display().to_string(), &source);
+        let decls = pipeline::parse_program(file, &source)?;
+        let type_checker = std::mem::replace(&mut self.type_checker, TypeChecker::new());
+        let evaluator = std::mem::take(&mut self.evaluator);
+        let program = std::mem::take(&mut self.program);
+        match self.declare(decls) {
+            Ok((warnings, _)) => {
+                let res = format!("{}Loaded {}", warnings, path.display());
+                self.loaded = Some(path);
+                Ok(res)
+            }
+            Err(err) => {
+                self.type_checker = type_checker;
+                self.evaluator = evaluator;
+                self.program = program;
+                Err(err.into())
+            }
+        }
+    }
+
+    /// Everything a name typed at the prompt could refer to: primitives,
+    /// values, types and their constructors
+    pub fn completions(&self) -> Vec<String> {
+        let mut names: Vec<String> = PRIMITIVES
+            .iter()
+            .map(|prim| prim.name.to_string())
+            .collect();
+        for (decl, _) in self.program.iter() {
+            match decl {
+                Declaration::Value(vd) if !vd.name.contains('#') => names.push(vd.name.clone()),
+                Declaration::Value(_) => {}
+                Declaration::Type(td) => {
+                    names.push(td.name.clone());
+                    for dtor in td.constructors.iter() {
+                        names.push(format!("{}::{}", td.name, dtor.name))
+                    }
+                }
+                Declaration::Error(_) => {}
+            }
         }
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// Lists the data types and values in scope, in the order they were
+    /// declared. Declarations that have been shadowed are left out.
+    fn browse(&self) -> String {
+        let mut lines = vec![];
+        for (ix, (decl, ty)) in self.program.iter().enumerate() {
+            match decl {
+                Declaration::Type(td) => {
+                    let shadowed = self.program[ix + 1..].iter().any(|(later, _)| match later {
+                        Declaration::Type(later) => later.name == td.name,
+                        _ => false,
+                    });
+                    if !shadowed {
+                        lines.push(decl.to_string())
+                    }
+                }
+                // Shadowed values have been renamed by `define`
+                Declaration::Value(vd) if !vd.name.contains('#') => {
+                    lines.push(format!("{} : {}", vd.name, ty))
+                }
+                _ => {}
+            }
+        }
+        lines.join("\n")
+    }
+
+    /// Shows an expression after `phase`. The later phases compile it as
+    /// `main`, together with the declarations in the session.
+    fn dump(&mut self, phase: Phase, input: &str) -> Result<String, ReplError> {
+        let file = self.files.add("<repl>", input);
+        let expr = pipeline::parse_expr(file, input)?;
+        if phase == Phase::Ast {
+            return Ok(expr.to_string());
+        }
+        let (expr, ty) = self
+            .type_checker
+            .clone()
+            .infer_expr(expr)
+            .map_err(PipelineError::NewTypeError)?;
+        if phase == Phase::Typed {
+            return Ok(format!("{}\n: {}", expr, ty));
+        }
+        let program = self.with_entry_point(expr, ty);
+        let res = match phase {
+            Phase::Ir => pipeline::lower(program)?.to_string(),
+            _ => wasm::pretty_wat(&pipeline::codegen(program)?),
+        };
+        Ok(res)
+    }
+
+    /// The declarations in the session, followed by `expr` as `main`
+    fn with_entry_point(&mut self, expr: Expr<NewVar>, ty: types::Type) -> Vec<TypedDeclaration> {
+        let mut program = self.program.clone();
+        let span = expr.span();
+        let main = ValueDeclaration {
+            name: ENTRY_POINT.to_string(),
+            expr,
+            span,
+            doc: None,
+        };
+        self.supply += 1;
+        define(&mut program, main, ty, self.supply);
+        program
+    }
+
+    fn warnings(&self, type_checker: &mut TypeChecker) -> String {
+        type_checker
+            .take_warnings()
+            .iter()
+            .map(|warning| Diagnostic::from(warning).render(&self.files))
+            .collect()
+    }
+
+    fn eval_expr(&mut self, expr: Expr<String>) -> Result<String, PipelineError> {
+        let mut type_checker = self.type_checker.clone();
+        let (expr, ty) = type_checker
+            .infer_expr(expr)
+            .map_err(PipelineError::NewTypeError)?;
+        let warnings = self.warnings(&mut type_checker);
+        let value = match self.backend {
+            Backend::Term => self
+                .evaluator
+                .eval_expr(expr)
+                .map_err(PipelineError::EvalError)?
+                .to_string(),
+            backend => {
+                let program = self.with_entry_point(expr, ty.clone());
+                pipeline::run_checked(program, backend)?
+            }
+        };
+        Ok(format!("{}{} : {}", warnings, value, ty))
+    }
+
+    /// Checks and evaluates declarations, and returns the warnings for them
+    /// along with the names they bind
+    fn declare(
+        &mut self,
+        decls: Vec<Declaration<String>>,
+    ) -> Result<(String, Vec<String>), PipelineError> {
+        let mut type_checker = self.type_checker.clone();
+        let mut evaluator = self.evaluator.clone();
+        let mut program = self.program.clone();
+        let mut out = vec![];
+        for decl in decls {
+            let (decl, ty) = match type_checker
+                .infer_decl(decl)
+                .map_err(PipelineError::NewTypeError)?
+            {
+                None => continue,
+                Some(typed) => typed,
+            };
+            evaluator
+                .eval_decl(decl.clone())
+                .map_err(PipelineError::EvalError)?;
+            match decl {
+                Declaration::Value(vd) => {
+                    out.push(format!("{} : {}", vd.name, ty));
+                    self.supply += 1;
+                    define(&mut program, vd, ty, self.supply)
+                }
+                decl => program.push((decl, ty)),
+            }
+        }
+        let warnings = self.warnings(&mut type_checker);
+        self.type_checker = type_checker;
+        self.evaluator = evaluator;
+        self.program = program;
+        Ok((warnings, out))
     }
 }
 
-pub fn run() {
-    // `()` can be used when no completer is required
-    let mut rl = Editor::<()>::new();
-    if rl.load_history("history.txt").is_err() {
-        println!("No previous history.");
+/// Input is either an expression, or declarations. We're lenient about the
+/// `;` after the last declaration.
+fn parse_input(file: FileId, input: &str) -> Result<Input, PipelineError> {
+    let expr_err = match pipeline::parse_expr(file, input) {
+        Ok(expr) => return Ok(Input::Expr(expr)),
+        Err(err) => err,
+    };
+    let trimmed = input.trim_end();
+    let program_err = match pipeline::parse_program(file, trimmed) {
+        Ok(decls) => return Ok(Input::Declarations(decls)),
+        Err(err) => err,
+    };
+    if !trimmed.ends_with(';') {
+        let program = format!("{};", trimmed);
+        if let Ok(decls) = pipeline::parse_program(file, &program) {
+            return Ok(Input::Declarations(decls));
+        }
+    }
+    let starts_with = |keyword: &str| trimmed.trim_start().starts_with(keyword);
+    let has_in = trimmed.split_whitespace().any(|word| word == "in");
+    if starts_with("type") || starts_with("--|") || (starts_with("let") && !has_in) {
+        Err(program_err)
+    } else {
+        Err(expr_err)
     }
+}
 
-    loop {
-        let readline = rl.readline(">> ");
-        match readline {
+/// Adds a value declaration to a program that might already declare the
+/// same name. Every global needs a unique name in wasm, so the earlier
+/// declaration gets renamed, along with all references to it.
+fn define(
+    program: &mut Vec<TypedDeclaration>,
+    vd: ValueDeclaration<NewVar>,
+    ty: types::Type,
+    supply: u32,
+) {
+    let previous = program.iter().position(|(decl, _)| match decl {
+        Declaration::Value(previous) => previous.name == vd.name,
+        _ => false,
+    });
+    if let Some(ix) = previous {
+        let renamed = format!("{}#{}", vd.name, supply);
+        for (decl, _) in program[ix..].iter_mut() {
+            if let Declaration::Value(decl) = decl {
+                if decl.name == vd.name {
+                    decl.name = renamed.clone()
+                } else {
+                    decl.expr.subst_var_mut(&vd.name, &renamed)
+                }
+            }
+        }
+    }
+    program.push((Declaration::Value(vd), ty))
+}
+
+/// Whether `input` needs more lines before it can be evaluated, because it
+/// has unclosed braces or parens, ends in the middle of an expression, or
+/// has a `let` that's still waiting for its `in`. Input that doesn't lex is
+/// complete, so the error gets reported right away.
+pub fn is_incomplete(input: &str) -> bool {
+    if input.trim_start().starts_with(':') {
+        return false;
+    }
+    let mut depth: i32 = 0;
+    let mut open_lets = 0;
+    let mut declaration_start = true;
+    let mut last = None;
+    for token in Lexer::new(input) {
+        let token = match token {
+            Ok((_, token, _)) => token,
+            Err(_) => return false,
+        };
+        match token {
+            Token::LParen | Token::LBrace => depth += 1,
+            Token::RParen | Token::RBrace => depth -= 1,
+            // Top-level declarations don't take an `in`
+            Token::Let | Token::LetRec if !declaration_start => open_lets += 1,
+            Token::In if open_lets > 0 => open_lets -= 1,
+            _ => {}
+        }
+        declaration_start =
+            depth == 0 && matches!(token, Token::Semi | Token::RBrace | Token::DocComment(_));
+        last = Some(token);
+    }
+    let dangling = matches!(
+        last,
+        Some(
+            Token::Lambda
+                | Token::Equals
+                | Token::Dot
+                | Token::Colon
+                | Token::DoubleColon
+                | Token::Arrow
+                | Token::FatArrow
+                | Token::Match
+                | Token::Let
+                | Token::LetRec
+                | Token::In
+                | Token::If
+                | Token::Then
+                | Token::Else
+                | Token::Type
+                | Token::DocComment(_)
+        )
+    );
+    depth > 0 || open_lets > 0 || dangling
+}
+
+/// The start of the name ending at `pos` in `line`, and the names it could
+/// be completed to
+fn complete_name(names: &[String], line: &str, pos: usize) -> (usize, Vec<String>) {
+    let start = line[..pos]
+        .rfind(|c: char| !(c.is_alphanumeric() || c == '_' || c == ':'))
+        .map_or(0, |ix| ix + 1);
+    let prefix = &line[start..pos];
+    let candidates = if start == 0 && prefix.starts_with(':') {
+        COMMANDS
+            .iter()
+            .filter(|command| command.starts_with(prefix))
+            .map(|command| command.to_string())
+            .collect()
+    } else if prefix.is_empty() {
+        vec![]
+    } else {
+        names
+            .iter()
+            .filter(|name| name.starts_with(prefix))
+            .cloned()
+            .collect()
+    };
+    (start, candidates)
+}
+
+/// Completes the names bound in the session. `run` refreshes the names
+/// after every line.
+#[derive(Debug, Default)]
+struct ReplHelper {
+    names: Vec<String>,
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(complete_name(&self.names, line, pos))
+    }
+}
+
+impl Hinter for ReplHelper {}
+
+impl Highlighter for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+/// Where we keep the history, so it's shared between all the directories
+/// the REPL gets started in
+fn history_path() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join("siml").join("history.txt"))
+}
+
+fn load_history(rl: &mut Editor<ReplHelper>) -> Option<PathBuf> {
+    let path = match history_path() {
+        Some(path) => path,
+        None => {
+            eprintln!("warning: Couldn't find a data directory, history won't be saved");
+            return None;
+        }
+    };
+    match rl.load_history(&path) {
+        Ok(()) => {}
+        Err(ReadlineError::Io(ref err)) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => eprintln!(
+            "warning: Failed to load the history from {}: {}",
+            path.display(),
+            err
+        ),
+    }
+    Some(path)
+}
+
+fn save_history(rl: &Editor<ReplHelper>, path: &PathBuf) {
+    let res = match path.parent() {
+        Some(dir) => fs::create_dir_all(dir).map_err(ReadlineError::Io),
+        None => Ok(()),
+    }
+    .and_then(|()| rl.save_history(path));
+    if let Err(err) = res {
+        eprintln!(
+            "warning: Failed to save the history to {}: {}",
+            path.display(),
+            err
+        )
+    }
+}
+
+/// Reads lines until they make up a complete input, see `is_incomplete`.
+/// Interrupting a continuation line throws away what's been entered so far.
+fn read_input(rl: &mut Editor<ReplHelper>) -> rustyline::Result<String> {
+    let mut input = rl.readline(">> ")?;
+    while is_incomplete(&input) {
+        match rl.readline(".. ") {
             Ok(line) => {
-                rl.add_history_entry(&line);
-                run_term(&line);
+                input.push('\n');
+                input.push_str(&line)
+            }
+            Err(ReadlineError::Interrupted) => return Ok(String::new()),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(input)
+}
+
+pub fn run(backend: Backend<'static>) {
+    let mut session = Session::new(backend);
+    let mut rl = Editor::<ReplHelper>::new();
+    rl.set_helper(Some(ReplHelper {
+        names: session.completions(),
+    }));
+    let history = load_history(&mut rl);
+
+    loop {
+        match read_input(&mut rl) {
+            Ok(input) => {
+                if input.trim().is_empty() {
+                    continue;
+                }
+                rl.add_history_entry(input.as_str());
+                match session.eval(&input) {
+                    Ok(out) => {
+                        if !out.is_empty() {
+                            println!("{}", out)
+                        }
+                    }
+                    Err(err) => eprint!("{}", session.render_error(&err)),
+                }
+                if let Some(helper) = rl.helper_mut() {
+                    helper.names = session.completions()
+                }
             }
             Err(ReadlineError::Interrupted) => {
                 println!("CTRL-C");
@@ -64,5 +632,191 @@ pub fn run() {
             }
         }
     }
-    rl.save_history("history.txt").unwrap();
+    if let Some(path) = history {
+        save_history(&rl, &path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn eval(session: &mut Session, input: &str) -> String {
+        session
+            .eval(input)
+            .unwrap_or_else(|err| panic!("{}", session.render_error(&err)))
+    }
+
+    #[test]
+    fn declarations_persist() {
+        for backend in [Backend::Term, Backend::WasmRun] {
+            let mut session = Session::new(backend);
+            assert_eq!(
+                eval(&mut session, "type Maybe<a> { Nothing(), Just(a) }"),
+                ""
+            );
+            assert_eq!(
+                eval(
+                    &mut session,
+                    "let from_maybe : forall a. a -> Maybe<a> -> a = \\d. \\m. match m { Maybe::Just(x) => x, Maybe::Nothing() => d }"
+                ),
+                "from_maybe : ∀ a. a → Maybe<a> → a"
+            );
+            assert_eq!(eval(&mut session, "let x : Int = 20;"), "x : Int");
+            let res = eval(&mut session, "from_maybe 0 (Maybe::Just(x + 1))");
+            assert!(res.ends_with(" : Int"), "{}", res);
+            assert!(res.contains("21"), "{}", res);
+        }
+    }
+
+    #[test]
+    fn redefinitions_shadow() {
+        for backend in [Backend::Term, Backend::WasmRun] {
+            let mut session = Session::new(backend);
+            eval(&mut session, "let x : Int = 1");
+            eval(&mut session, "let y : Int = x + 1");
+            eval(&mut session, "let x : Bool = true");
+            let res = eval(&mut session, "if x then y else 0");
+            assert!(res.contains('2'), "{}", res);
+        }
+    }
+
+    #[test]
+    fn errors_leave_the_session_alone() {
+        let mut session = Session::new(Backend::Term);
+        eval(&mut session, "let x : Int = 1");
+        assert!(session
+            .eval("let y : Int = x + true; let z : Int = 2;")
+            .is_err());
+        assert!(session.eval("z").is_err());
+        let err = session.eval("y").unwrap_err();
+        assert!(session.render_error(&err).contains("--> <repl>:1:1"));
+        assert_eq!(eval(&mut session, "x"), "1 : Int");
+    }
+
+    #[test]
+    fn type_command_doesnt_evaluate() {
+        let mut session = Session::new(Backend::Term);
+        eval(&mut session, "let x : Int = 1");
+        assert_eq!(
+            eval(&mut session, ":type \\y. add x y"),
+            "\\y. add x y : Int → Int"
+        );
+        assert_eq!(eval(&mut session, ":t x"), "x : Int");
+        assert!(session.eval(":type 1 / 0").is_ok());
+        assert!(session.eval(":type x true").is_err());
+    }
+
+    #[test]
+    fn load_and_reload() {
+        let path = std::env::temp_dir().join(format!("siml_repl_{}.siml", std::process::id()));
+        fs::write(&path, "let x : Int = 1;\nlet y : Int = add x 1;").unwrap();
+        let mut session = Session::new(Backend::Term);
+        eval(&mut session, "let z : Int = 3");
+        let res = eval(&mut session, &format!(":load {}", path.display()));
+        assert!(res.starts_with("Loaded "), "{}", res);
+        assert_eq!(eval(&mut session, "y"), "2 : Int");
+        assert!(session.eval("z").is_err());
+
+        fs::write(&path, "let y : Int = true;").unwrap();
+        assert!(session.eval(":reload").is_err());
+        assert_eq!(eval(&mut session, "y"), "2 : Int");
+
+        fs::write(&path, "let y : Bool = true;").unwrap();
+        eval(&mut session, ":reload");
+        assert_eq!(eval(&mut session, "y"), "true : Bool");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn browse_lists_what_is_in_scope() {
+        let mut session = Session::new(Backend::Term);
+        eval(&mut session, "type Maybe<a> { Nothing(), Just(a) }");
+        eval(&mut session, "let x : Int = 1");
+        eval(&mut session, "let x : Bool = true");
+        assert_eq!(
+            eval(&mut session, ":browse"),
+            "type Maybe<a> { Nothing(), Just(a) }\nx : Bool"
+        );
+    }
+
+    #[test]
+    fn switching_backends() {
+        let mut session = Session::new(Backend::Term);
+        eval(&mut session, "let x : Int = 41");
+        assert_eq!(eval(&mut session, ":backend"), "Using the term backend");
+        assert_eq!(
+            eval(&mut session, ":backend wasm"),
+            "Using the wasm backend"
+        );
+        assert_eq!(eval(&mut session, "x + 1"), "42 : Int");
+        assert!(session.eval(":backend jvm").is_err());
+    }
+
+    #[test]
+    fn dump_phases() {
+        let mut session = Session::new(Backend::Term);
+        eval(&mut session, "let x : Int = 1");
+        let ir = eval(&mut session, ":dump ir x + 1");
+        assert!(ir.contains("main"), "{}", ir);
+        let wat = eval(&mut session, ":dump wat x + 1");
+        assert!(wat.starts_with("(module"), "{}", wat);
+        assert!(session.eval(":dump llvm x").is_err());
+        assert!(session.eval(":dump ir").is_err());
+    }
+
+    #[test]
+    fn unknown_commands() {
+        let mut session = Session::new(Backend::Term);
+        let err = session.eval(":frobnicate").unwrap_err();
+        assert_eq!(
+            session.render_error(&err),
+            "error: Unknown command `:frobnicate`, `:help` lists the commands\n"
+        );
+        assert!(session.eval(":reload").is_err());
+    }
+
+    #[test]
+    fn completes_names_in_scope() {
+        let mut session = Session::new(Backend::Term);
+        eval(&mut session, "type Maybe<a> { Nothing(), Just(a) }");
+        eval(&mut session, "let map_maybe : Int = 1");
+        let names = session.completions();
+        assert_eq!(
+            complete_name(&names, "1 + ma", 6),
+            (4, vec!["map_maybe".to_string()])
+        );
+        assert_eq!(
+            complete_name(&names, "(Maybe::", 8),
+            (
+                1,
+                vec!["Maybe::Just".to_string(), "Maybe::Nothing".to_string()]
+            )
+        );
+        assert_eq!(complete_name(&names, "ad", 2), (0, vec!["add".to_string()]));
+        assert_eq!(
+            complete_name(&names, ":re", 3),
+            (0, vec![":reload".to_string()])
+        );
+        assert_eq!(complete_name(&names, "1 + ", 4), (4, vec![]));
+    }
+
+    #[test]
+    fn incomplete_input() {
+        assert!(is_incomplete("type Maybe<a> {"));
+        assert!(is_incomplete("(add 1"));
+        assert!(is_incomplete("let x = 1 in"));
+        assert!(is_incomplete("let y : Int = let x = 1"));
+        assert!(is_incomplete("let x : Int ="));
+        assert!(is_incomplete("match x {\n  Maybe::Just(y) =>"));
+        assert!(!is_incomplete("let x : Int = 1"));
+        assert!(!is_incomplete(
+            "let x : Int = let y = 1 in y;\nlet z : Int = 2"
+        ));
+        assert!(!is_incomplete("let x = 1 in x"));
+        assert!(!is_incomplete("type Maybe<a> { Nothing(), Just(a) }"));
+        assert!(!is_incomplete("add 1)"));
+        assert!(!is_incomplete(":type let x = 1 in"));
+    }
 }
@@ -0,0 +1,51 @@
+use std::fmt;
+
+/// Identifies a source file. The pipeline hands these out, so spans from
+/// different files can be told apart when reporting errors.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Default)]
+pub struct FileId(pub u32);
+
+/// A position in a source file. `offset` is a byte offset into the source,
+/// `line` and `column` are zero-based and count characters.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Default)]
+pub struct Position {
+    pub offset: usize,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line + 1, self.column + 1)
+    }
+}
+
+/// A range of source code in a file, from `start` (inclusive) to `end`
+/// (exclusive).
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Default)]
+pub struct Span {
+    pub file: FileId,
+    pub start: Position,
+    pub end: Position,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+impl Span {
+    pub fn new(file: FileId, start: Position, end: Position) -> Span {
+        Span { file, start, end }
+    }
+
+    /// The smallest span covering both `self` and `other`
+    pub fn merge(self, other: Span) -> Span {
+        Span {
+            file: self.file,
+            start: std::cmp::min(self.start, other.start),
+            end: std::cmp::max(self.end, other.end),
+        }
+    }
+}
@@ -1,4 +1,7 @@
-use crate::expr::{Case, Declaration, Expr, HasIdent, Literal, TypeDeclaration};
+use crate::expr::{Declaration, Dtor, Expr, HasIdent, Literal, TypeDeclaration};
+use crate::patterns::{self, Decision, SimplePattern};
+use crate::primitives;
+use crate::span::Span;
 use std::collections::HashMap;
 use std::fmt;
 
@@ -9,18 +12,30 @@ pub enum Term {
     App {
         func: Box<Term>,
         arg: Box<Term>,
+        span: Span,
     },
     Lambda {
         binder: String,
         body: Box<Term>,
     },
-    Var(String),
+    Var(String, Span),
+    /// Binds `binder` in both `expr` and `body`
+    LetRec {
+        binder: String,
+        expr: Box<Term>,
+        body: Box<Term>,
+    },
     Closure {
         binder: String,
         body: Box<Term>,
         env: Env,
     },
     Literal(Literal),
+    /// A primitive that's been applied to fewer arguments than it takes
+    Primitive {
+        name: String,
+        args: Vec<Term>,
+    },
     Pack {
         tag: u32,
         arity: u32,
@@ -29,38 +44,59 @@ pub enum Term {
     Case {
         expr: Box<Term>,
         cases: Vec<TermMatch>,
+        /// Evaluated if none of the cases match
+        default: Option<Box<Term>>,
+        span: Span,
     },
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct TermMatch {
-    pub tag: u32,
-    pub binders: Vec<String>,
+    pub pattern: TermPattern,
     pub expr: Term,
 }
 
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum TermPattern {
+    /// Matches a pack with the given tag, and binds its fields
+    Pack {
+        tag: u32,
+        binders: Vec<String>,
+    },
+    Literal(Literal),
+}
+
 impl fmt::Display for Term {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.print())
     }
 }
 
+#[derive(Clone)]
 struct Lowering {
     /// All type declarations in the to-be-lowered program
     types: Vec<TypeDeclaration>,
+    supply: u32,
 }
 
 impl Lowering {
     pub fn new() -> Lowering {
-        Lowering { types: vec![] }
+        Lowering {
+            types: vec![],
+            supply: 0,
+        }
     }
 
-    pub fn lower_prog<B: HasIdent>(mut self, prog: Vec<Declaration<B>>) -> Vec<(String, Term)> {
+    pub fn lower_prog<B: HasIdent + Clone>(
+        mut self,
+        prog: Vec<Declaration<B>>,
+    ) -> Vec<(String, Term)> {
         let mut values = vec![];
         for decl in prog {
             match decl {
                 Declaration::Value(v) => values.push(v),
                 Declaration::Type(t) => self.types.push(t),
+                Declaration::Error(_) => {}
             }
         }
         values
@@ -69,74 +105,140 @@ impl Lowering {
             .collect()
     }
 
-    pub fn lower_expr<B: HasIdent>(&self, expr: Expr<B>) -> Term {
+    pub fn lower_expr<B: HasIdent + Clone>(&mut self, expr: Expr<B>) -> Term {
         match expr {
-            Expr::App { func, arg } => Term::App {
+            Expr::App { func, arg, span } => Term::App {
                 func: Box::new(self.lower_expr(*func)),
                 arg: Box::new(self.lower_expr(*arg)),
+                span,
             },
-            Expr::Lambda { binder, body } => Term::Lambda {
+            Expr::Lambda { binder, body, .. } => Term::Lambda {
                 binder: binder.ident(),
                 body: Box::new(self.lower_expr(*body)),
             },
-            Expr::Let { binder, expr, body } => Term::App {
+            Expr::Let {
+                binder,
+                expr,
+                body,
+                span,
+            } => Term::App {
                 func: Box::new(Term::Lambda {
                     binder: binder.ident(),
                     body: Box::new(self.lower_expr(*body)),
                 }),
                 arg: Box::new(self.lower_expr(*expr)),
+                span,
             },
-            Expr::LetRec { binder, expr, body } => unreachable!("Do recursive bindings"),
-            Expr::Var(s) => match self.tag_for_constructor(s.ident()) {
-                None => Term::Var(s.ident()),
-                Some(tag) => Term::Pack {
-                    tag,
-                    arity: 0,
-                    values: vec![],
-                },
+            Expr::LetRec {
+                binder, expr, body, ..
+            } => Term::LetRec {
+                binder: binder.ident(),
+                expr: Box::new(self.lower_expr(*expr)),
+                body: Box::new(self.lower_expr(*body)),
             },
-            Expr::Literal(lit) => Term::Literal(lit.clone()),
+            Expr::Var(s, span) => Term::Var(s.ident(), span),
+            Expr::Literal(lit, _) => Term::Literal(lit.clone()),
             Expr::Ann { expr, .. } => self.lower_expr(*expr),
-            Expr::Tuple(fst, snd) => Term::Pack {
+            Expr::Tuple(fst, snd, _) => Term::Pack {
                 tag: 1,
                 arity: 2,
                 values: vec![self.lower_expr(*fst), self.lower_expr(*snd)],
             },
-            Expr::Construction { dtor, args } => {
-                let tag = self.tag_for_constructor(dtor.name).unwrap();
+            Expr::Construction { dtor, args, .. } => {
+                let tag = self
+                    .tag_for_constructor(&dtor)
+                    .expect("Failed to find data constructor during lowering");
                 Term::Pack {
                     tag,
                     arity: args.len() as u32,
                     values: args.into_iter().map(|arg| self.lower_expr(arg)).collect(),
                 }
             }
-            Expr::Match { expr, cases } => Term::Case {
-                expr: Box::new(self.lower_expr(*expr)),
-                cases: cases.into_iter().map(|m| self.lower_match(m)).collect(),
+            Expr::If {
+                condition,
+                then_branch,
+                else_branch,
+                span,
+            } => Term::Case {
+                expr: Box::new(self.lower_expr(*condition)),
+                cases: vec![
+                    TermMatch {
+                        pattern: TermPattern::Literal(Literal::Bool(true)),
+                        expr: self.lower_expr(*then_branch),
+                    },
+                    TermMatch {
+                        pattern: TermPattern::Literal(Literal::Bool(false)),
+                        expr: self.lower_expr(*else_branch),
+                    },
+                ],
+                default: None,
+                span,
             },
+            Expr::Match { expr, cases, span } => {
+                self.supply += 1;
+                let scrutinee = format!("$match{}", self.supply);
+                let supply = &mut self.supply;
+                let decision =
+                    patterns::compile_match(&self.types, &scrutinee, cases, &mut |name: &str| {
+                        *supply += 1;
+                        format!("${}{}", name, supply)
+                    });
+                Term::App {
+                    func: Box::new(Term::Lambda {
+                        binder: scrutinee,
+                        body: Box::new(self.lower_decision(decision, span)),
+                    }),
+                    arg: Box::new(self.lower_expr(*expr)),
+                    span,
+                }
+            }
         }
     }
 
-    fn lower_match<B: HasIdent>(&self, match_: Case<B>) -> TermMatch {
-        TermMatch {
-            tag: self
-                .tag_for_constructor(match_.data_constructor.name)
-                .expect("Failed to find data constructor during lowering"),
-            binders: vec![],
-            expr: self.lower_expr(match_.expr),
+    fn lower_decision<B: HasIdent + Clone>(&mut self, decision: Decision<B>, span: Span) -> Term {
+        match decision {
+            Decision::Leaf(expr) => self.lower_expr(expr),
+            Decision::Switch {
+                scrutinee,
+                cases,
+                default,
+            } => Term::Case {
+                expr: Box::new(Term::Var(scrutinee, span)),
+                cases: cases
+                    .into_iter()
+                    .map(|(pattern, decision)| TermMatch {
+                        pattern: self.lower_pattern(pattern),
+                        expr: self.lower_decision(decision, span),
+                    })
+                    .collect(),
+                default: default.map(|decision| Box::new(self.lower_decision(*decision, span))),
+                span,
+            },
         }
     }
 
-    fn tag_for_constructor(&self, ctor: String) -> Option<u32> {
-        self.types.iter().find_map(|t| {
-            t.constructors.iter().enumerate().find_map(|(ix, c)| {
-                if c.name == ctor {
-                    Some(ix as u32)
-                } else {
-                    None
-                }
-            })
-        })
+    fn lower_pattern(&self, pattern: SimplePattern) -> TermPattern {
+        match pattern {
+            SimplePattern::Constructor { dtor, binders } => TermPattern::Pack {
+                tag: self
+                    .tag_for_constructor(&dtor)
+                    .expect("Failed to find data constructor during lowering"),
+                binders,
+            },
+            SimplePattern::Literal(lit) => TermPattern::Literal(lit),
+            SimplePattern::Tuple(fst, snd) => TermPattern::Pack {
+                tag: 1,
+                binders: vec![fst, snd],
+            },
+        }
+    }
+
+    fn tag_for_constructor(&self, dtor: &Dtor) -> Option<u32> {
+        let ty = self.types.iter().find(|t| t.name == dtor.ty)?;
+        ty.constructors
+            .iter()
+            .position(|c| c.name == dtor.name)
+            .map(|ix| ix as u32)
     }
 }
 
@@ -144,10 +246,13 @@ impl Lowering {
 pub enum EvalError {
     UnknownVar(String),
     ApplyingNonLambda(Term),
-    AddingNonNumbers(Term, Term),
+    InvalidPrimitiveArguments(String, Vec<Term>),
+    DivisionByZero,
     ProjectingFst(Term),
+    ProjectingSnd(Term),
     MatchOnNonPack(Term),
     FailedPatternMatch(Term),
+    Located(Span, Box<EvalError>),
 }
 
 impl fmt::Display for EvalError {
@@ -161,16 +266,39 @@ impl EvalError {
         match self {
             EvalError::UnknownVar(var) => format!("Unknown variable: {}", var),
             EvalError::ApplyingNonLambda(term) => format!("{} is not a function", term),
-            EvalError::AddingNonNumbers(t1, t2) => {
-                format!("Attempting to add non-ints: {} + {}", t1, t2)
-            }
-            EvalError::ProjectingFst(term) => {
+            EvalError::InvalidPrimitiveArguments(name, args) => format!(
+                "Invalid arguments for {}: {}",
+                name,
+                args.iter()
+                    .map(|arg| arg.print())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+            EvalError::DivisionByZero => "Division by zero".to_string(),
+            EvalError::ProjectingFst(term) | EvalError::ProjectingSnd(term) => {
                 format!("Attempting to project from a non-tuple: {}", term)
             }
             EvalError::MatchOnNonPack(term) => format!("Attempting to pattern match on: {}", term),
             EvalError::FailedPatternMatch(term) => {
                 format!("Failed to find a matching pattern for: {}", term)
             }
+            EvalError::Located(_, err) => err.print(),
+        }
+    }
+
+    /// Attaches a source location to an error, unless it already has a more
+    /// precise one.
+    pub fn at(self, span: Span) -> EvalError {
+        match self {
+            EvalError::Located(..) => self,
+            err => EvalError::Located(span, Box::new(err)),
+        }
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            EvalError::Located(span, _) => Some(*span),
+            _ => None,
         }
     }
 }
@@ -179,11 +307,60 @@ fn initial_env() -> Env {
     HashMap::new()
 }
 
+/// Evaluates declarations one at a time, every declaration sees the values
+/// of the ones evaluated before it
+#[derive(Clone)]
+pub struct Evaluator {
+    lowering: Lowering,
+    env: Env,
+}
+
+impl Default for Evaluator {
+    fn default() -> Evaluator {
+        Evaluator::new()
+    }
+}
+
+impl Evaluator {
+    pub fn new() -> Evaluator {
+        Evaluator {
+            lowering: Lowering::new(),
+            env: initial_env(),
+        }
+    }
+
+    /// Binds the value of a value declaration, and returns it. Type
+    /// declarations replace earlier ones with the same name.
+    pub fn eval_decl<B: HasIdent + Clone>(
+        &mut self,
+        decl: Declaration<B>,
+    ) -> Result<Option<Term>, EvalError> {
+        match decl {
+            Declaration::Value(vd) => {
+                let value = self.eval_expr(vd.expr)?;
+                self.env.insert(vd.name, value.clone());
+                Ok(Some(value))
+            }
+            Declaration::Type(td) => {
+                self.lowering.types.retain(|ty| ty.name != td.name);
+                self.lowering.types.push(td);
+                Ok(None)
+            }
+            Declaration::Error(_) => Ok(None),
+        }
+    }
+
+    pub fn eval_expr<B: HasIdent + Clone>(&mut self, expr: Expr<B>) -> Result<Term, EvalError> {
+        let lowered = self.lowering.lower_expr(expr);
+        Term::eval(&self.env, lowered)
+    }
+}
+
 impl Term {
-    pub fn eval_prog<B: HasIdent>(prog: Vec<Declaration<B>>) -> Result<Term, EvalError> {
+    pub fn eval_prog<B: HasIdent + Clone>(prog: Vec<Declaration<B>>) -> Result<Term, EvalError> {
         let lowered = Lowering::new().lower_prog(prog);
         let mut env = initial_env();
-        let mut res = Term::Var("nuttin".to_string());
+        let mut res = Term::Var("nuttin".to_string(), Span::default());
         for (name, term) in lowered {
             res = Term::eval(&env, term)?;
             env.insert(name, res.clone());
@@ -191,48 +368,21 @@ impl Term {
         Ok(res)
     }
 
-    pub fn eval_expr<B: HasIdent>(expr: Expr<B>) -> Result<Term, EvalError> {
+    pub fn eval_expr<B: HasIdent + Clone>(expr: Expr<B>) -> Result<Term, EvalError> {
         let lowered = Lowering::new().lower_expr(expr);
         Term::eval(&initial_env(), lowered)
     }
 
     fn eval(env: &Env, term: Term) -> Result<Term, EvalError> {
         match term {
-            Term::Var(s) => match s.as_ref() {
-                "primadd" => match (env.get("x"), env.get("y")) {
-                    (
-                        Some(Term::Literal(Literal::Int(i1))),
-                        Some(Term::Literal(Literal::Int(i2))),
-                    ) => Ok(Term::Literal(Literal::Int(i1 + i2))),
-                    (Some(term1), Some(term2)) => {
-                        panic!("Attempting to add non-numbers: {:?} {:?}", term1, term2)
-                    }
-                    (_, _) => panic!("Bad implementation for add"),
-                },
-                "primfst" => match env.get("x") {
-                    Some(Term::Pack { values, .. }) => Ok(values[0].clone()),
-                    Some(term) => Err(EvalError::ProjectingFst(term.clone())),
-                    None => panic!("Bad implementation for fst"),
-                },
-                "primsnd" => match env.get("x") {
-                    Some(Term::Pack { values, .. }) => Ok(values[1].clone()),
-                    Some(term) => Err(EvalError::ProjectingFst(term.clone())),
-                    None => panic!("Bad implementation for fst"),
-                },
-                "primtuple" => match (env.get("x"), env.get("y")) {
-                    (Some(t1), Some(t2)) => Ok(Term::Pack {
-                        tag: 1,
-                        arity: 2,
-                        values: vec![t1.clone(), t2.clone()],
+            Term::Var(s, span) => match env.get(&s) {
+                Some(t) => Ok(t.clone()),
+                None => match primitives::lookup(&s) {
+                    Some(prim) => Ok(Term::Primitive {
+                        name: prim.name.to_string(),
+                        args: vec![],
                     }),
-                    _ => panic!("Bad implementation for fst"),
-                },
-                _ => match env.get(&s) {
-                    Some(t) => Ok(t.clone()),
-                    None => {
-                        // warn!("{:?}", env);
-                        Err(EvalError::UnknownVar(s))
-                    }
+                    None => Err(EvalError::UnknownVar(s).at(span)),
                 },
             },
             Term::Lambda { binder, body } => Ok(Term::Closure {
@@ -240,9 +390,36 @@ impl Term {
                 body,
                 env: env.clone(),
             }),
-            Term::Closure { .. } => Ok(term),
+            Term::LetRec { binder, expr, body } => {
+                let value = match Term::eval(env, *expr)? {
+                    // Rather than building a cyclic environment, the closure
+                    // re-binds itself whenever it's applied, by running its
+                    // body under the same `letrec`.
+                    Term::Closure {
+                        binder: arg,
+                        body: closure_body,
+                        env: closed_env,
+                    } => Term::Closure {
+                        binder: arg.clone(),
+                        body: Box::new(Term::LetRec {
+                            binder: binder.clone(),
+                            expr: Box::new(Term::Lambda {
+                                binder: arg,
+                                body: closure_body.clone(),
+                            }),
+                            body: closure_body,
+                        }),
+                        env: closed_env,
+                    },
+                    value => value,
+                };
+                let mut new_env = env.clone();
+                new_env.insert(binder, value);
+                Term::eval(&new_env, *body)
+            }
+            Term::Closure { .. } | Term::Primitive { .. } => Ok(term),
             Term::Literal(_) => Ok(term),
-            Term::App { func, arg } => match Term::eval(env, *func)? {
+            Term::App { func, arg, span } => match Term::eval(env, *func)? {
                 Term::Closure {
                     binder,
                     body,
@@ -254,7 +431,16 @@ impl Term {
                     new_env.insert(binder, evaled_arg);
                     Term::eval(&new_env, *body)
                 }
-                t => Err(EvalError::ApplyingNonLambda(t)),
+                Term::Primitive { name, mut args } => {
+                    args.push(Term::eval(env, *arg)?);
+                    let prim = primitives::lookup(&name).expect("Unknown primitive");
+                    if args.len() == prim.arity {
+                        (prim.eval)(args).map_err(|err| err.at(span))
+                    } else {
+                        Ok(Term::Primitive { name, args })
+                    }
+                }
+                t => Err(EvalError::ApplyingNonLambda(t).at(span)),
             },
             Term::Pack { tag, arity, values } => {
                 let mut evaled_values = vec![];
@@ -268,24 +454,42 @@ impl Term {
                     values: evaled_values,
                 })
             }
-            Term::Case { expr, cases } => {
+            Term::Case {
+                expr,
+                cases,
+                default,
+                span,
+            } => {
                 let evaled_expr = Term::eval(env, *expr)?;
-                match evaled_expr {
-                    Term::Pack { tag, .. } => {
-                        let matched_case = cases.into_iter().find_map(|case| {
-                            if case.tag == tag {
-                                Some(case.expr)
-                            } else {
-                                None
-                            }
-                        });
-
-                        match matched_case {
-                            None => Err(EvalError::FailedPatternMatch(evaled_expr)),
-                            Some(term) => Term::eval(env, term),
+                let mut new_env = env.clone();
+                let matched_case = cases
+                    .into_iter()
+                    .find_map(|case| match (case.pattern, &evaled_expr) {
+                        (
+                            TermPattern::Pack { tag, binders },
+                            Term::Pack {
+                                tag: actual,
+                                values,
+                                ..
+                            },
+                        ) if tag == *actual => {
+                            // Fields are bound to the binders positionally
+                            new_env.extend(binders.into_iter().zip(values.iter().cloned()));
+                            Some(case.expr)
+                        }
+                        (TermPattern::Literal(lit), Term::Literal(actual)) if lit == *actual => {
+                            Some(case.expr)
                         }
+                        _ => None,
+                    })
+                    .or_else(|| default.map(|default| *default));
+
+                match (matched_case, evaled_expr) {
+                    (Some(term), _) => Term::eval(&new_env, term),
+                    (None, t @ Term::Pack { .. }) | (None, t @ Term::Literal(_)) => {
+                        Err(EvalError::FailedPatternMatch(t).at(span))
                     }
-                    t => Err(EvalError::MatchOnNonPack(t)),
+                    (None, t) => Err(EvalError::MatchOnNonPack(t).at(span)),
                 }
             }
         }
@@ -297,11 +501,32 @@ impl Term {
 
     fn print_inner(&self, depth: u32) -> String {
         match self {
-            Term::Var(s) => s.clone(),
+            Term::Var(s, _) => s.clone(),
             Term::Literal(lit) => lit.print(),
             Term::Lambda { binder, body } => format!("(\\{}. {})", binder, body),
+            Term::LetRec { binder, expr, body } => parens_if(
+                depth > 0,
+                format!("letrec {} = {} in {}", binder, expr, body),
+            ),
             Term::Closure { binder, body, .. } => format!("(\\{}. {})", binder, body),
-            Term::App { func, arg } => parens_if(
+            Term::Primitive { name, args } => {
+                if args.is_empty() {
+                    name.clone()
+                } else {
+                    parens_if(
+                        depth > 0,
+                        format!(
+                            "{} {}",
+                            name,
+                            args.iter()
+                                .map(|arg| arg.print_inner(depth + 1))
+                                .collect::<Vec<String>>()
+                                .join(" ")
+                        ),
+                    )
+                }
+            }
+            Term::App { func, arg, .. } => parens_if(
                 depth > 0,
                 format!("{} {}", func.print_inner(depth), arg.print_inner(depth + 1)),
             ),
@@ -315,20 +540,28 @@ impl Term {
                     .collect::<Vec<String>>()
                     .join(", ")
             ),
-            Term::Case { expr, cases } => format!(
-                "match {} {{ {} }}",
+            Term::Case {
                 expr,
-                cases
+                cases,
+                default,
+                ..
+            } => {
+                let mut printed_cases: Vec<String> = cases
                     .iter()
-                    .map(|case| format!(
-                        "<{}>({}) => {}",
-                        case.tag,
-                        case.binders.join(", "),
-                        case.expr.print()
-                    ))
-                    .collect::<Vec<String>>()
-                    .join(", ")
-            ),
+                    .map(|case| match &case.pattern {
+                        TermPattern::Pack { tag, binders } => {
+                            format!("<{}>({}) => {}", tag, binders.join(", "), case.expr.print())
+                        }
+                        TermPattern::Literal(lit) => {
+                            format!("{} => {}", lit.print(), case.expr.print())
+                        }
+                    })
+                    .collect();
+                if let Some(default) = default {
+                    printed_cases.push(format!("_ => {}", default.print()))
+                }
+                format!("match {} {{ {} }}", expr, printed_cases.join(", "))
+            }
         }
     }
 }
@@ -1,5 +1,5 @@
-use std::iter::Peekable;
-use std::str::Chars;
+use crate::span::Position;
+use std::fmt;
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Token {
@@ -16,42 +16,199 @@ pub enum Token {
     DoubleColon,
     Semi,
     Comma,
+    Underscore,
+    At,
     Arrow,
     FatArrow,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    DoubleEquals,
+    NotEquals,
+    LessEquals,
+    GreaterEquals,
+    DoubleAmpersand,
+    DoublePipe,
+    ComposeLeft,
+    /// Also closes two levels of type arguments, as in `List<List<Int>>`
+    ComposeRight,
+    PipeRight,
     Forall,
     Match,
     Let,
     LetRec,
     In,
+    If,
+    Then,
+    Else,
     Type,
     Ident(String),
     UpperIdent(String),
     IntLiteral(i32),
     BooleanLiteral(bool),
+    /// A `--|` comment documenting the declaration that follows it
+    DocComment(String),
+    /// Stands in for input that failed to lex, so the parser can recover
+    /// from it like from any other syntax error. It doesn't match anything
+    /// in the grammar.
+    Error,
 }
 
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Lambda => write!(f, "\\"),
+            Token::Equals => write!(f, "="),
+            Token::Dot => write!(f, "."),
+            Token::LParen => write!(f, "("),
+            Token::RParen => write!(f, ")"),
+            Token::LBrace => write!(f, "{{"),
+            Token::RBrace => write!(f, "}}"),
+            Token::LAngle => write!(f, "<"),
+            Token::RAngle => write!(f, ">"),
+            Token::Colon => write!(f, ":"),
+            Token::DoubleColon => write!(f, "::"),
+            Token::Semi => write!(f, ";"),
+            Token::Comma => write!(f, ","),
+            Token::Underscore => write!(f, "_"),
+            Token::At => write!(f, "@"),
+            Token::Arrow => write!(f, "->"),
+            Token::FatArrow => write!(f, "=>"),
+            Token::Plus => write!(f, "+"),
+            Token::Minus => write!(f, "-"),
+            Token::Star => write!(f, "*"),
+            Token::Slash => write!(f, "/"),
+            Token::Percent => write!(f, "%"),
+            Token::DoubleEquals => write!(f, "=="),
+            Token::NotEquals => write!(f, "!="),
+            Token::LessEquals => write!(f, "<="),
+            Token::GreaterEquals => write!(f, ">="),
+            Token::DoubleAmpersand => write!(f, "&&"),
+            Token::DoublePipe => write!(f, "||"),
+            Token::ComposeLeft => write!(f, "<<"),
+            Token::ComposeRight => write!(f, ">>"),
+            Token::PipeRight => write!(f, "|>"),
+            Token::Forall => write!(f, "forall"),
+            Token::Match => write!(f, "match"),
+            Token::Let => write!(f, "let"),
+            Token::LetRec => write!(f, "letrec"),
+            Token::In => write!(f, "in"),
+            Token::If => write!(f, "if"),
+            Token::Then => write!(f, "then"),
+            Token::Else => write!(f, "else"),
+            Token::Type => write!(f, "type"),
+            Token::Ident(ident) => write!(f, "{}", ident),
+            Token::UpperIdent(ident) => write!(f, "{}", ident),
+            Token::IntLiteral(i) => write!(f, "{}", i),
+            Token::BooleanLiteral(b) => write!(f, "{}", b),
+            Token::DocComment(doc) => write!(f, "--| {}", doc),
+            Token::Error => write!(f, "<error>"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum LexErrorKind {
+    InvalidCharacter(char),
+    IntegerOutOfRange(String),
+    UnterminatedBlockComment,
+}
+
+/// An error the lexer ran into between `start` and `end`
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct LexError {
+    pub kind: LexErrorKind,
+    pub start: Position,
+    pub end: Position,
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            LexErrorKind::InvalidCharacter(c) => write!(f, "Invalid character `{}`", c),
+            LexErrorKind::IntegerOutOfRange(lit) => {
+                write!(f, "Integer literal {} is out of range", lit)
+            }
+            LexErrorKind::UnterminatedBlockComment => {
+                write!(f, "Block comment is missing its closing `-}}`")
+            }
+        }
+    }
+}
+
+/// A `--` or `{- -}` comment. The parser never sees these, the formatter
+/// collects them with `comments` so it can put them back.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Comment {
+    pub text: String,
+    pub start: Position,
+    pub end: Position,
+}
+
+impl Comment {
+    /// Line comments run until the end of the line, so whatever follows
+    /// them has to start on a new one
+    pub fn is_line_comment(&self) -> bool {
+        self.text.starts_with("--")
+    }
+}
+
+/// A token together with its start and end position, the triple protocol
+/// LALRPOP expects from an external lexer.
+pub type Spanned<Tok, Error> = Result<(Position, Tok, Position), Error>;
+
 pub struct Lexer<'input> {
-    input: Peekable<Chars<'input>>,
-    pos: u32,
+    input: &'input str,
+    pos: Position,
+    comments: Vec<Comment>,
+    /// How many braces we're inside of
+    depth: usize,
+    /// Whether the next token could start a declaration. Doc comments
+    /// anywhere else don't document anything, so they're skipped like any
+    /// other comment.
+    declaration_start: bool,
+    in_type_declaration: bool,
 }
 
 impl<'input> Lexer<'input> {
     pub fn new(input: &'input str) -> Lexer<'input> {
-        let mut lexer = Lexer {
-            input: input.chars().peekable(),
-            pos: 0,
-        };
-        lexer.consume_whitespace();
-        lexer
+        Lexer {
+            input,
+            pos: Position::default(),
+            comments: vec![],
+            depth: 0,
+            declaration_start: true,
+            in_type_declaration: false,
+        }
+    }
+
+    /// The input we haven't consumed yet
+    fn rest(&self) -> &'input str {
+        &self.input[self.pos.offset..]
     }
 
     fn next_char(&mut self) -> Option<char> {
-        self.pos += 1;
-        self.input.next()
+        let c = self.peek()?;
+        self.pos.offset += c.len_utf8();
+        if c == '\n' {
+            self.pos.line += 1;
+            self.pos.column = 0;
+        } else {
+            self.pos.column += 1;
+        }
+        Some(c)
     }
 
     fn peek(&mut self) -> Option<char> {
-        self.input.peek().cloned()
+        self.rest().chars().next()
+    }
+
+    fn skip(&mut self, n: usize) {
+        for _ in 0..n {
+            self.next_char();
+        }
     }
 
     fn consume_whitespace(&mut self) {
@@ -63,6 +220,85 @@ impl<'input> Lexer<'input> {
             }
         }
     }
+
+    /// Whether a doc comment at the current position documents a
+    /// declaration, rather than trailing at the end of the file
+    fn documents_declaration(&self) -> bool {
+        if !self.declaration_start {
+            return false;
+        }
+        // Skipping doc comments as well, the rest of the file has to
+        // contain more than comments
+        let mut rest = Lexer::new(self.rest());
+        rest.declaration_start = false;
+        rest.next().is_some()
+    }
+
+    /// Skips whitespace and comments. Doc comments are left alone if they
+    /// document a declaration, because they're handed to the parser as
+    /// tokens.
+    fn consume_trivia(&mut self) -> Result<(), LexError> {
+        loop {
+            self.consume_whitespace();
+            let rest = self.rest();
+            let start = self.pos;
+            if rest.starts_with("--|") && self.documents_declaration() {
+                return Ok(());
+            } else if rest.starts_with("--") {
+                self.consume_line();
+            } else if rest.starts_with("{-") {
+                self.consume_block_comment()?;
+            } else {
+                return Ok(());
+            }
+            self.comments.push(Comment {
+                text: self.input[start.offset..self.pos.offset]
+                    .trim_end()
+                    .to_string(),
+                start,
+                end: self.pos,
+            })
+        }
+    }
+
+    /// Consumes everything up to (but not including) the next line break
+    fn consume_line(&mut self) -> &'input str {
+        let rest = self.rest();
+        let line = &rest[..rest.find('\n').unwrap_or(rest.len())];
+        self.skip(line.chars().count());
+        line
+    }
+
+    /// Block comments nest, so `{- {- -} -}` is a single comment
+    fn consume_block_comment(&mut self) -> Result<(), LexError> {
+        let start = self.pos;
+        self.skip(2);
+        let end = self.pos;
+        let mut depth = 1;
+        while depth > 0 {
+            if self.rest().starts_with("{-") {
+                self.skip(2);
+                depth += 1;
+            } else if self.rest().starts_with("-}") {
+                self.skip(2);
+                depth -= 1;
+            } else if self.next_char().is_none() {
+                return Err(LexError {
+                    kind: LexErrorKind::UnterminatedBlockComment,
+                    start,
+                    end,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// All the comments in `input`, in order
+pub fn comments(input: &str) -> Vec<Comment> {
+    let mut lexer = Lexer::new(input);
+    while lexer.next().is_some() {}
+    lexer.comments
 }
 
 fn is_ident_start(c: char) -> bool {
@@ -83,44 +319,126 @@ fn is_ident_member(c: char) -> bool {
 }
 
 impl<'input> Iterator for Lexer<'input> {
-    type Item = Token;
+    type Item = Spanned<Token, LexError>;
 
-    fn next(&mut self) -> Option<Token> {
+    fn next(&mut self) -> Option<Self::Item> {
+        let token = self.lex();
+        if let Some(Ok((_, token, _))) = &token {
+            match token {
+                Token::LBrace => self.depth += 1,
+                Token::RBrace => self.depth = self.depth.saturating_sub(1),
+                _ => {}
+            }
+            let declaration_start = self.declaration_start;
+            self.declaration_start = match token {
+                Token::DocComment(_) => declaration_start,
+                Token::Semi => self.depth == 0,
+                Token::RBrace => self.depth == 0 && self.in_type_declaration,
+                _ => false,
+            };
+            if declaration_start {
+                self.in_type_declaration = *token == Token::Type;
+            }
+        }
+        token
+    }
+}
+
+impl<'input> Lexer<'input> {
+    fn lex(&mut self) -> Option<Spanned<Token, LexError>> {
+        if let Err(err) = self.consume_trivia() {
+            return Some(Err(err));
+        }
+        let start = self.pos;
+        if self.rest().starts_with("--|") {
+            self.skip(3);
+            let doc = self.consume_line().trim().to_string();
+            return Some(Ok((start, Token::DocComment(doc), self.pos)));
+        }
         let token = match self.next_char() {
-            Some('\\') => Some(Token::Lambda),
-            Some('.') => Some(Token::Dot),
-            Some('(') => Some(Token::LParen),
-            Some(')') => Some(Token::RParen),
-            Some('{') => Some(Token::LBrace),
-            Some('<') => Some(Token::LAngle),
-            Some('>') => Some(Token::RAngle),
-            Some('}') => Some(Token::RBrace),
+            None => return None,
+            Some('\\') => Ok(Token::Lambda),
+            Some('.') => Ok(Token::Dot),
+            Some('(') => Ok(Token::LParen),
+            Some(')') => Ok(Token::RParen),
+            Some('{') => Ok(Token::LBrace),
+            Some('<') => match self.peek() {
+                Some('=') => {
+                    self.next_char();
+                    Ok(Token::LessEquals)
+                }
+                Some('<') => {
+                    self.next_char();
+                    Ok(Token::ComposeLeft)
+                }
+                _ => Ok(Token::LAngle),
+            },
+            Some('>') => match self.peek() {
+                Some('=') => {
+                    self.next_char();
+                    Ok(Token::GreaterEquals)
+                }
+                Some('>') => {
+                    self.next_char();
+                    Ok(Token::ComposeRight)
+                }
+                _ => Ok(Token::RAngle),
+            },
+            Some('}') => Ok(Token::RBrace),
             Some(':') => {
                 if self.peek() == Some(':') {
-                    self.next();
-                    Some(Token::DoubleColon)
+                    self.next_char();
+                    Ok(Token::DoubleColon)
                 } else {
-                    Some(Token::Colon)
+                    Ok(Token::Colon)
                 }
             }
-            Some(';') => Some(Token::Semi),
-            Some(',') => Some(Token::Comma),
-            Some('=') => {
-                if self.peek() == Some('>') {
-                    self.next();
-                    Some(Token::FatArrow)
-                } else {
-                    Some(Token::Equals)
+            Some(';') => Ok(Token::Semi),
+            Some(',') => Ok(Token::Comma),
+            Some('_') => Ok(Token::Underscore),
+            Some('@') => Ok(Token::At),
+            Some('=') => match self.peek() {
+                Some('>') => {
+                    self.next_char();
+                    Ok(Token::FatArrow)
                 }
-            }
+                Some('=') => {
+                    self.next_char();
+                    Ok(Token::DoubleEquals)
+                }
+                _ => Ok(Token::Equals),
+            },
             Some('-') => {
                 if self.peek() == Some('>') {
-                    self.next();
-                    Some(Token::Arrow)
+                    self.next_char();
+                    Ok(Token::Arrow)
                 } else {
-                    panic!("Failed to parse an arrow.")
+                    Ok(Token::Minus)
                 }
             }
+            Some('+') => Ok(Token::Plus),
+            Some('*') => Ok(Token::Star),
+            Some('/') => Ok(Token::Slash),
+            Some('%') => Ok(Token::Percent),
+            Some('!') if self.peek() == Some('=') => {
+                self.next_char();
+                Ok(Token::NotEquals)
+            }
+            Some('&') if self.peek() == Some('&') => {
+                self.next_char();
+                Ok(Token::DoubleAmpersand)
+            }
+            Some('|') => match self.peek() {
+                Some('|') => {
+                    self.next_char();
+                    Ok(Token::DoublePipe)
+                }
+                Some('>') => {
+                    self.next_char();
+                    Ok(Token::PipeRight)
+                }
+                _ => Err(LexErrorKind::InvalidCharacter('|')),
+            },
             Some(c) if c.is_digit(10) => {
                 let mut res = c.to_string();
                 while let Some(c) = self.peek() {
@@ -130,7 +448,10 @@ impl<'input> Iterator for Lexer<'input> {
                         break;
                     }
                 }
-                Some(Token::IntLiteral(res.parse::<i32>().unwrap()))
+                match res.parse::<i32>() {
+                    Ok(i) => Ok(Token::IntLiteral(i)),
+                    Err(_) => Err(LexErrorKind::IntegerOutOfRange(res)),
+                }
             }
             Some(c) if is_ident_start(c) => {
                 let mut res = c.to_string();
@@ -142,27 +463,34 @@ impl<'input> Iterator for Lexer<'input> {
                     }
                 }
                 match res.as_str() {
-                    "true" => Some(Token::BooleanLiteral(true)),
-                    "false" => Some(Token::BooleanLiteral(false)),
-                    "forall" => Some(Token::Forall),
-                    "let" => Some(Token::Let),
-                    "letrec" => Some(Token::LetRec),
-                    "in" => Some(Token::In),
-                    "match" => Some(Token::Match),
-                    "type" => Some(Token::Type),
+                    "true" => Ok(Token::BooleanLiteral(true)),
+                    "false" => Ok(Token::BooleanLiteral(false)),
+                    "forall" => Ok(Token::Forall),
+                    "let" => Ok(Token::Let),
+                    "letrec" => Ok(Token::LetRec),
+                    "in" => Ok(Token::In),
+                    "if" => Ok(Token::If),
+                    "then" => Ok(Token::Then),
+                    "else" => Ok(Token::Else),
+                    "match" => Ok(Token::Match),
+                    "type" => Ok(Token::Type),
                     _ => {
                         if res.chars().next().unwrap().is_uppercase() {
-                            Some(Token::UpperIdent(res))
+                            Ok(Token::UpperIdent(res))
                         } else {
-                            Some(Token::Ident(res))
+                            Ok(Token::Ident(res))
                         }
                     }
                 }
             }
-            _ => None,
+            Some(c) => Err(LexErrorKind::InvalidCharacter(c)),
         };
-        self.consume_whitespace();
+        let end = self.pos;
         debug!("Token: {:?}", &token);
-        token
+        Some(
+            token
+                .map(|token| (start, token, end))
+                .map_err(|kind| LexError { kind, start, end }),
+        )
     }
 }
@@ -3,9 +3,12 @@
 use crate::bi_types;
 use crate::expr::{
     Case, DataConstructor, Declaration, Dtor, Expr, HasIdent, Literal, NewTypedExpr, NewVar,
-    TypeDeclaration, ValueDeclaration,
+    Pattern, TypeDeclaration, ValueDeclaration,
 };
+use crate::patterns::{self, Signatures};
 use crate::pretty::render_doc;
+use crate::primitives::{self, PRIMITIVES};
+use crate::span::Span;
 use pretty::{BoxDoc, Doc};
 use std::collections::{HashMap, HashSet};
 use std::fmt;
@@ -17,6 +20,7 @@ pub enum Type {
     Unknown(u32),
     Poly { vars: Vec<String>, ty: Box<Type> },
     Fun { arg: Box<Type>, result: Box<Type> },
+    Tuple(Box<Type>, Box<Type>),
 }
 
 impl fmt::Display for Type {
@@ -44,7 +48,9 @@ impl Type {
             bi_types::Type::Fun { arg, result } => {
                 Type::fun(Type::from_bi_type(*arg), Type::from_bi_type(*result))
             }
-            bi_types::Type::Tuple(_, _) => panic!("no tuples for you"),
+            bi_types::Type::Tuple(fst, snd) => {
+                Type::tuple(Type::from_bi_type(*fst), Type::from_bi_type(*snd))
+            }
         }
     }
 
@@ -68,6 +74,22 @@ impl Type {
         }
     }
 
+    fn tuple(fst: Type, snd: Type) -> Type {
+        Type::Tuple(Box::new(fst), Box::new(snd))
+    }
+
+    /// Quantifies over `vars`, if there are any
+    fn poly(vars: Vec<String>, ty: Type) -> Type {
+        if vars.is_empty() {
+            ty
+        } else {
+            Type::Poly {
+                vars,
+                ty: Box::new(ty),
+            }
+        }
+    }
+
     pub fn unknowns(&self) -> HashSet<u32> {
         let mut res = HashSet::new();
         match self {
@@ -82,6 +104,10 @@ impl Type {
             Type::Poly { ty, .. } => {
                 res.extend(ty.unknowns());
             }
+            Type::Tuple(fst, snd) => {
+                res.extend(fst.unknowns());
+                res.extend(snd.unknowns());
+            }
             Type::Constructor { arguments, .. } => {
                 for arg in arguments {
                     res.extend(arg.unknowns())
@@ -91,6 +117,34 @@ impl Type {
         res
     }
 
+    pub fn vars(&self) -> HashSet<String> {
+        let mut res = HashSet::new();
+        match self {
+            Type::Var(v) => {
+                res.insert(v.clone());
+            }
+            Type::Fun { arg, result } => {
+                res.extend(arg.vars());
+                res.extend(result.vars());
+            }
+            Type::Unknown(_) => {}
+            Type::Poly { vars, ty } => {
+                res.extend(vars.iter().cloned());
+                res.extend(ty.vars());
+            }
+            Type::Tuple(fst, snd) => {
+                res.extend(fst.vars());
+                res.extend(snd.vars());
+            }
+            Type::Constructor { arguments, .. } => {
+                for arg in arguments {
+                    res.extend(arg.vars())
+                }
+            }
+        }
+        res
+    }
+
     fn unfold_fun_inner(self) -> Vec<Self> {
         match self {
             Type::Fun { arg, result } => {
@@ -133,6 +187,10 @@ impl Type {
                 arg: Box::new(arg.subst(unknown, replacement)),
                 result: Box::new(result.subst(unknown, replacement)),
             },
+            Type::Tuple(fst, snd) => Type::tuple(
+                fst.subst(unknown, replacement),
+                snd.subst(unknown, replacement),
+            ),
         }
     }
 
@@ -156,6 +214,10 @@ impl Type {
                 arg.subst_mut(var, replacement);
                 result.subst_mut(var, replacement);
             }
+            Type::Tuple(fst, snd) => {
+                fst.subst_mut(var, replacement);
+                snd.subst_mut(var, replacement);
+            }
         }
     }
 
@@ -219,6 +281,13 @@ impl Type {
                     inner
                 }
             }
+            Type::Tuple(fst, snd) => Doc::text("(")
+                .append(fst.to_doc())
+                .append(Doc::text(","))
+                .append(Doc::space())
+                .append(snd.to_doc())
+                .append(Doc::text(")"))
+                .group(),
         }
     }
 }
@@ -234,6 +303,13 @@ pub enum TypeError {
     OccursCheck(u32, Type),
     Unification(Type, Type),
     CantInferMatch,
+    /// A pattern that binds the same variable twice
+    DuplicateBinder(String),
+    /// A match that doesn't cover the values described by these patterns
+    NonExhaustive(Vec<String>),
+    Located(Span, Box<TypeError>),
+    /// Explains an error by pointing at a second location that caused it
+    Because(Span, String, Box<TypeError>),
 }
 
 impl fmt::Display for TypeError {
@@ -263,6 +339,79 @@ impl TypeError {
             TypeError::CantInferMatch => {
                 "Can't infer type for a match, please provide an annotation".to_string()
             }
+            TypeError::DuplicateBinder(name) => {
+                format!("{} is bound more than once in the same pattern", name)
+            }
+            TypeError::NonExhaustive(missing) => format!(
+                "Non-exhaustive match, missing cases for: {}",
+                missing.join(", ")
+            ),
+            TypeError::Located(_, err) | TypeError::Because(_, _, err) => err.print(),
+        }
+    }
+
+    /// Attaches a source location to an error, unless it already has a more
+    /// precise one.
+    pub fn at(self, span: Span) -> TypeError {
+        match self {
+            TypeError::Located(..) => self,
+            TypeError::Because(reason_span, reason, err) => {
+                TypeError::Because(reason_span, reason, Box::new(err.at(span)))
+            }
+            err => TypeError::Located(span, Box::new(err)),
+        }
+    }
+
+    pub fn because(self, span: Span, reason: String) -> TypeError {
+        TypeError::Because(span, reason, Box::new(self))
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            TypeError::Located(span, _) => Some(*span),
+            TypeError::Because(_, _, err) => err.span(),
+            _ => None,
+        }
+    }
+
+    /// Secondary locations that help explain this error
+    pub fn reasons(&self) -> Vec<(Span, String)> {
+        match self {
+            TypeError::Located(_, err) => err.reasons(),
+            TypeError::Because(span, reason, err) => {
+                let mut res = vec![(*span, reason.clone())];
+                res.extend(err.reasons());
+                res
+            }
+            _ => vec![],
+        }
+    }
+}
+
+/// Problems that don't stop a program from type checking, but are most
+/// likely mistakes
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum TypeWarning {
+    /// A case that can never match, because earlier ones already match
+    /// everything it would
+    RedundantCase(Span),
+}
+
+impl fmt::Display for TypeWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TypeWarning::RedundantCase(_) => write!(
+                f,
+                "Unreachable case, earlier cases already match everything it would"
+            ),
+        }
+    }
+}
+
+impl TypeWarning {
+    pub fn span(&self) -> Span {
+        match self {
+            TypeWarning::RedundantCase(span) => *span,
         }
     }
 }
@@ -273,28 +422,55 @@ struct TypedValue {
     ty: Type,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 struct TypeInfo {
     type_arguments: Vec<String>,
     constructors: Vec<DataConstructor>,
 }
 
-#[derive(Debug, PartialEq, Default)]
+impl Signatures for HashMap<String, TypeInfo> {
+    fn constructors(&self, ty: &str) -> Option<&[DataConstructor]> {
+        self.get(ty)
+            .map(|type_info| type_info.constructors.as_slice())
+    }
+}
+
+/// A typechecked pattern, together with the variables it binds
+type TypedPattern = (Pattern<NewVar>, Vec<(String, Type)>);
+
+#[derive(Debug, PartialEq, Default, Clone)]
 pub struct CheckState {
     unknown_supply: u32,
     types: HashMap<String, TypeInfo>,
     subst: HashMap<u32, Type>,
     context: HashMap<String, Type>,
+    warnings: Vec<TypeWarning>,
 }
 
-#[derive(Debug, Default)]
+/// Checks declarations one after the other, every declaration can refer to
+/// the ones checked before it
+#[derive(Debug, Default, Clone)]
 pub struct TypeChecker {
     state: CheckState,
 }
 
 impl TypeChecker {
     pub fn new() -> TypeChecker {
-        Default::default()
+        let mut type_checker: TypeChecker = Default::default();
+        for prim in PRIMITIVES {
+            let ty = Type::from_bi_type(prim.ty());
+            type_checker
+                .state
+                .context
+                .insert(primitives::qualified(prim.name), ty.clone());
+            type_checker.state.context.insert(prim.name.to_string(), ty);
+        }
+        type_checker
+    }
+
+    /// Hands out the warnings we've collected so far
+    pub fn take_warnings(&mut self) -> Vec<TypeWarning> {
+        std::mem::take(&mut self.state.warnings)
     }
 
     fn add_type_declaration(&mut self, ty_decl: TypeDeclaration) {
@@ -328,6 +504,7 @@ impl TypeChecker {
                 None => ty,
                 Some(ty) => self.zonk_type(ty.clone()),
             },
+            Type::Tuple(fst, snd) => Type::tuple(self.zonk_type(*fst), self.zonk_type(*snd)),
         }
     }
 
@@ -447,6 +624,10 @@ impl TypeChecker {
                     self.unify(arg1, arg2)?
                 }
             }
+            (Type::Tuple(fst1, snd1), Type::Tuple(fst2, snd2)) => {
+                self.unify(*fst1, *fst2)?;
+                self.unify(*snd1, *snd2)?;
+            }
             (Type::Unknown(u), ty2) => self.solve_type(u, ty2)?,
             (ty1, Type::Unknown(u)) => self.solve_type(u, ty1)?,
             (ty1, ty2) => return Err(TypeError::Unification(ty1, ty2)),
@@ -467,6 +648,51 @@ impl TypeChecker {
         }
     }
 
+    /// Quantifies over the unknowns in `ty` that don't occur anywhere in the
+    /// context. Those can't be solved by anything outside of the expression
+    /// `ty` was inferred for, so we're free to pick them at every use.
+    fn generalize(&mut self, ty: Type) -> Type {
+        let (mut vars, ty) = match self.zonk_type(ty) {
+            Type::Poly { vars, ty } => (vars, *ty),
+            ty => (vec![], ty),
+        };
+        let context_unknowns: HashSet<u32> = self
+            .state
+            .context
+            .values()
+            .flat_map(|ty| self.zonk_type(ty.clone()).unknowns())
+            .collect();
+        let mut unknowns: Vec<u32> = ty
+            .unknowns()
+            .difference(&context_unknowns)
+            .cloned()
+            .collect();
+        if unknowns.is_empty() {
+            return Type::poly(vars, ty);
+        }
+        unknowns.sort();
+
+        let mut taken = ty.vars();
+        taken.extend(vars.iter().cloned());
+        let mut names = (0..)
+            .map(|n: u32| {
+                let letter = ((b'a' + (n % 26) as u8) as char).to_string();
+                if n < 26 {
+                    letter
+                } else {
+                    format!("{}{}", letter, n / 26)
+                }
+            })
+            .filter(|name| !taken.contains(name));
+        let mut ty = ty;
+        for unknown in unknowns {
+            let name = names.next().unwrap();
+            ty = ty.subst(unknown, &Type::Var(name.clone()));
+            vars.push(name);
+        }
+        Type::poly(vars, ty)
+    }
+
     // TODO According to @olle I should be returning a coercion function here.
     fn subsumes(&mut self, ty1: Type, ty2: Type) -> Result<(), TypeError> {
         match (ty1, ty2) {
@@ -481,8 +707,10 @@ impl TypeChecker {
 
     fn check<B: HasIdent>(&mut self, expr: Expr<B>, ty: Type) -> Result<TypedValue, TypeError> {
         // TODO Lame for now
+        let span = expr.span();
         let typed_expr = self.infer(expr)?;
-        self.subsumes(typed_expr.ty, ty.clone())?;
+        self.subsumes(typed_expr.ty, ty.clone())
+            .map_err(|err| err.at(span))?;
         Ok(TypedValue {
             expr: typed_expr.expr,
             ty,
@@ -523,12 +751,17 @@ impl TypeChecker {
     }
 
     fn infer<B: HasIdent>(&mut self, expr: Expr<B>) -> Result<TypedValue, TypeError> {
+        let span = expr.span();
+        self.infer_inner(expr).map_err(|err| err.at(span))
+    }
+
+    fn infer_inner<B: HasIdent>(&mut self, expr: Expr<B>) -> Result<TypedValue, TypeError> {
         match expr {
-            Expr::App { func, arg } => {
+            Expr::App { func, arg, .. } => {
                 let typed_fun = self.infer(*func)?;
                 self.check_application(typed_fun, *arg)
             }
-            Expr::Lambda { binder, body } => {
+            Expr::Lambda { binder, body, span } => {
                 let ty_binder = self.fresh_unknown();
                 let typed_body =
                     self.bind_name(binder.ident(), ty_binder.clone(), |tc| tc.infer(*body))?;
@@ -539,66 +772,84 @@ impl TypeChecker {
                             ty: ty_binder.clone(),
                         },
                         body: Box::new(typed_body.expr),
+                        span,
                     },
                     ty: Type::fun(ty_binder, typed_body.ty),
                 })
             }
-            Expr::Let { binder, expr, body } => {
+            Expr::Let {
+                binder,
+                expr,
+                body,
+                span,
+            } => {
                 let typed_expr = self.infer(*expr)?;
+                let ty_binder = self.generalize(typed_expr.ty);
                 let typed_body =
-                    self.bind_name(binder.ident(), typed_expr.ty.clone(), |tc| tc.infer(*body))?;
+                    self.bind_name(binder.ident(), ty_binder.clone(), |tc| tc.infer(*body))?;
                 Ok(TypedValue {
                     expr: Expr::Let {
                         binder: NewVar {
                             name: binder.ident(),
-                            ty: typed_expr.ty,
+                            ty: ty_binder,
                         },
                         expr: Box::new(typed_expr.expr),
                         body: Box::new(typed_body.expr),
+                        span,
                     },
                     ty: typed_body.ty,
                 })
             }
-            Expr::LetRec { binder, expr, body } => {
+            Expr::LetRec {
+                binder,
+                expr,
+                body,
+                span,
+            } => {
                 let fresh_binder = self.fresh_unknown();
                 let typed_expr =
                     self.bind_name(binder.ident(), fresh_binder.clone(), |tc| tc.infer(*expr))?;
                 self.unify(fresh_binder.clone(), typed_expr.ty.clone())?;
+                let ty_binder = self.generalize(typed_expr.ty);
                 let typed_body =
-                    self.bind_name(binder.ident(), typed_expr.ty.clone(), |tc| tc.infer(*body))?;
+                    self.bind_name(binder.ident(), ty_binder.clone(), |tc| tc.infer(*body))?;
                 Ok(TypedValue {
                     expr: Expr::LetRec {
                         binder: NewVar {
                             name: binder.ident(),
-                            ty: typed_expr.ty,
+                            ty: ty_binder,
                         },
                         expr: Box::new(typed_expr.expr),
                         body: Box::new(typed_body.expr),
+                        span,
                     },
                     ty: typed_body.ty,
                 })
             }
-            Expr::Var(v) => {
+            Expr::Var(v, span) => {
                 let var = v.ident();
                 let ty_var = self.lookup_name(&var)?;
                 let ty_var = self.instantiate(ty_var);
                 Ok(TypedValue {
-                    expr: Expr::Var(NewVar {
-                        name: var,
-                        ty: ty_var.clone(),
-                    }),
+                    expr: Expr::Var(
+                        NewVar {
+                            name: var,
+                            ty: ty_var.clone(),
+                        },
+                        span,
+                    ),
                     ty: ty_var,
                 })
             }
-            Expr::Literal(Literal::Int(i)) => Ok(TypedValue {
-                expr: Expr::Literal(Literal::Int(i)),
+            Expr::Literal(Literal::Int(i), span) => Ok(TypedValue {
+                expr: Expr::int(i, span),
                 ty: Type::int(),
             }),
-            Expr::Literal(Literal::Bool(b)) => Ok(TypedValue {
-                expr: Expr::Literal(Literal::Bool(b)),
+            Expr::Literal(Literal::Bool(b), span) => Ok(TypedValue {
+                expr: Expr::bool(b, span),
                 ty: Type::bool(),
             }),
-            Expr::Construction { dtor, args } => {
+            Expr::Construction { dtor, args, span } => {
                 let (data_constructor, type_arguments) = self.lookup_dataconstructor(&dtor)?;
                 let fresh_vars: Vec<(String, Type)> = type_arguments
                     .into_iter()
@@ -623,6 +874,7 @@ impl TypeChecker {
                     expr: Expr::Construction {
                         dtor: dtor.clone(),
                         args: typed_args,
+                        span,
                     },
                     ty: Type::Constructor {
                         name: dtor.ty,
@@ -630,127 +882,284 @@ impl TypeChecker {
                     },
                 })
             }
-            Expr::Match { expr, cases } => {
+            Expr::Match { expr, cases, span } => {
                 let typed_expr = self.infer(*expr)?;
                 let ty_res = self.fresh_unknown();
                 let mut typed_cases = vec![];
                 for case in cases {
-                    let binders = self.infer_pattern(
-                        &case.data_constructor,
-                        &case.binders,
-                        typed_expr.ty.clone(),
-                    )?;
+                    let case_span = case.span;
+                    let (pattern, binders) =
+                        self.infer_pattern(case.pattern, typed_expr.ty.clone())?;
                     let body = case.expr;
-                    let typed_case = self.bind_names(binders.clone(), |tc| tc.infer(body))?;
-                    self.unify(ty_res.clone(), typed_case.ty)?;
+                    let typed_case = self.bind_names(binders, |tc| tc.infer(body))?;
+                    self.unify(ty_res.clone(), typed_case.ty)
+                        .map_err(|err| err.at(case_span))?;
                     typed_cases.push(Case {
-                        data_constructor: case.data_constructor,
-                        binders: binders
-                            .into_iter()
-                            .map(|(name, ty)| NewVar { name, ty })
-                            .collect(),
+                        pattern,
                         expr: typed_case.expr,
+                        span: case_span,
                     });
                 }
+                self.check_coverage(&typed_cases)?;
                 Ok(TypedValue {
                     expr: Expr::Match {
                         expr: Box::new(typed_expr.expr),
                         cases: typed_cases,
+                        span,
                     },
                     ty: ty_res,
                 })
             }
-            Expr::Ann { expr, ty } => {
+            Expr::If {
+                condition,
+                then_branch,
+                else_branch,
+                span,
+            } => {
+                let typed_condition = self.check(*condition, Type::bool())?;
+                let typed_then = self.infer(*then_branch)?;
+                let else_span = else_branch.span();
+                let typed_else = self.infer(*else_branch)?;
+                self.unify(typed_then.ty.clone(), typed_else.ty)
+                    .map_err(|err| err.at(else_span))?;
+                Ok(TypedValue {
+                    expr: Expr::If {
+                        condition: Box::new(typed_condition.expr),
+                        then_branch: Box::new(typed_then.expr),
+                        else_branch: Box::new(typed_else.expr),
+                        span,
+                    },
+                    ty: typed_then.ty,
+                })
+            }
+            Expr::Ann {
+                expr, ty, ty_span, ..
+            } => {
                 // TODO Reconstruct `Ann` once bi_types is gone
-                self.check(*expr, Type::from_bi_type(ty))
+                let ty = Type::from_bi_type(ty);
+                let span = expr.span();
+                let typed_expr = self.infer(*expr)?;
+                self.subsumes(typed_expr.ty, ty.clone()).map_err(|err| {
+                    let reason = format!("expected `{}` because of this annotation", ty);
+                    err.at(span).because(ty_span, reason)
+                })?;
+                Ok(TypedValue {
+                    expr: typed_expr.expr,
+                    ty,
+                })
+            }
+            Expr::Tuple(fst, snd, span) => {
+                let typed_fst = self.infer(*fst)?;
+                let typed_snd = self.infer(*snd)?;
+                Ok(TypedValue {
+                    expr: Expr::tuple(typed_fst.expr, typed_snd.expr, span),
+                    ty: Type::tuple(typed_fst.ty, typed_snd.ty),
+                })
             }
-            Expr::Tuple(_, _) => Err(TypeError::CantInferMatch),
         }
     }
 
-    pub fn infer_pattern<B>(
+    /// Makes sure the cases of a match cover every possible value, and warns
+    /// about cases that can never be reached.
+    fn check_coverage<B>(&mut self, cases: &[Case<B>]) -> Result<(), TypeError> {
+        let coverage =
+            patterns::check_coverage(&self.state.types, cases.iter().map(|case| &case.pattern));
+        for ix in coverage.redundant {
+            self.state
+                .warnings
+                .push(TypeWarning::RedundantCase(cases[ix].span))
+        }
+        if coverage.missing.is_empty() {
+            Ok(())
+        } else {
+            Err(TypeError::NonExhaustive(coverage.missing))
+        }
+    }
+
+    /// Checks a pattern against the type of the values it matches. Returns
+    /// the typed pattern and the variables it binds.
+    pub fn infer_pattern<B: HasIdent>(
         &mut self,
-        dtor: &Dtor,
-        binders: &[B],
+        pattern: Pattern<B>,
         ty: Type,
-    ) -> Result<Vec<(String, Type)>, TypeError>
-    where
-        B: HasIdent,
-    {
-        let (data_constructor, type_arguments) = self.lookup_dataconstructor(dtor)?;
-        let fresh_vars: Vec<(String, Type)> = type_arguments
-            .into_iter()
-            .map(|arg| (arg, self.fresh_unknown()))
-            .collect();
-        if binders.len() != data_constructor.fields.len() {
-            return Err(TypeError::WrongConstructorArity(
-                dtor.clone(),
-                binders.len(),
-                data_constructor.fields.len(),
-            ));
-        }
-        self.unify(
-            ty,
-            Type::Constructor {
-                name: dtor.ty.clone(),
-                arguments: fresh_vars.iter().map(|(_, fresh)| fresh.clone()).collect(),
-            },
-        )?;
-
-        Ok(binders
-            .into_iter()
-            .zip(data_constructor.fields)
-            .map(|(binder, ty)| {
-                (
-                    binder.ident(),
-                    Type::from_bi_type(ty).subst_many(&fresh_vars),
-                )
-            })
-            .collect())
+    ) -> Result<TypedPattern, TypeError> {
+        let mut seen = HashSet::new();
+        for (binder, span) in pattern.binder_spans() {
+            let name = binder.ident();
+            if !seen.insert(name.clone()) {
+                return Err(TypeError::DuplicateBinder(name).at(span));
+            }
+        }
+        self.infer_pattern_located(pattern, ty)
+    }
+
+    fn infer_pattern_located<B: HasIdent>(
+        &mut self,
+        pattern: Pattern<B>,
+        ty: Type,
+    ) -> Result<TypedPattern, TypeError> {
+        let span = pattern.span();
+        self.infer_pattern_inner(pattern, ty)
+            .map_err(|err| err.at(span))
+    }
+
+    fn infer_pattern_inner<B: HasIdent>(
+        &mut self,
+        pattern: Pattern<B>,
+        ty: Type,
+    ) -> Result<TypedPattern, TypeError> {
+        match pattern {
+            Pattern::Wildcard(span) => Ok((Pattern::Wildcard(span), vec![])),
+            Pattern::Var(binder, span) => {
+                let name = binder.ident();
+                Ok((
+                    Pattern::Var(
+                        NewVar {
+                            name: name.clone(),
+                            ty: ty.clone(),
+                        },
+                        span,
+                    ),
+                    vec![(name, ty)],
+                ))
+            }
+            Pattern::Literal(lit, span) => {
+                let ty_lit = match lit {
+                    Literal::Int(_) => Type::int(),
+                    Literal::Bool(_) => Type::bool(),
+                };
+                self.unify(ty, ty_lit)?;
+                Ok((Pattern::Literal(lit, span), vec![]))
+            }
+            Pattern::Constructor { dtor, fields, span } => {
+                let (data_constructor, type_arguments) = self.lookup_dataconstructor(&dtor)?;
+                let fresh_vars: Vec<(String, Type)> = type_arguments
+                    .into_iter()
+                    .map(|arg| (arg, self.fresh_unknown()))
+                    .collect();
+                if fields.len() != data_constructor.fields.len() {
+                    return Err(TypeError::WrongConstructorArity(
+                        dtor,
+                        fields.len(),
+                        data_constructor.fields.len(),
+                    ));
+                }
+                self.unify(
+                    ty,
+                    Type::Constructor {
+                        name: dtor.ty.clone(),
+                        arguments: fresh_vars.iter().map(|(_, fresh)| fresh.clone()).collect(),
+                    },
+                )?;
+
+                let mut typed_fields = vec![];
+                let mut binders = vec![];
+                for (field, ty_field) in fields.into_iter().zip(data_constructor.fields) {
+                    let ty_field = Type::from_bi_type(ty_field).subst_many(&fresh_vars);
+                    let (typed_field, field_binders) =
+                        self.infer_pattern_located(field, ty_field)?;
+                    typed_fields.push(typed_field);
+                    binders.extend(field_binders);
+                }
+                Ok((
+                    Pattern::Constructor {
+                        dtor,
+                        fields: typed_fields,
+                        span,
+                    },
+                    binders,
+                ))
+            }
+            Pattern::Tuple(fst, snd, span) => {
+                let ty_fst = self.fresh_unknown();
+                let ty_snd = self.fresh_unknown();
+                self.unify(ty, Type::tuple(ty_fst.clone(), ty_snd.clone()))?;
+                let (typed_fst, mut binders) = self.infer_pattern_located(*fst, ty_fst)?;
+                let (typed_snd, snd_binders) = self.infer_pattern_located(*snd, ty_snd)?;
+                binders.extend(snd_binders);
+                Ok((
+                    Pattern::Tuple(Box::new(typed_fst), Box::new(typed_snd), span),
+                    binders,
+                ))
+            }
+            Pattern::As {
+                binder,
+                pattern,
+                span,
+            } => {
+                let name = binder.ident();
+                let (typed_pattern, mut binders) =
+                    self.infer_pattern_located(*pattern, ty.clone())?;
+                binders.insert(0, (name.clone(), ty.clone()));
+                Ok((
+                    Pattern::As {
+                        binder: NewVar { name, ty },
+                        pattern: Box::new(typed_pattern),
+                        span,
+                    },
+                    binders,
+                ))
+            }
+        }
     }
 
     pub fn infer_prog<B: HasIdent>(
         &mut self,
         prog: Vec<Declaration<B>>,
     ) -> Result<Vec<(Declaration<NewVar>, Type)>, TypeError> {
-        // TODO setup initial context
-        self.state.context.insert(
-            "primadd".to_string(),
-            Type::Poly {
-                vars: vec![],
-                ty: Box::new(Type::int()),
-            },
-        );
-
         let mut result = vec![];
-
         for decl in prog {
-            match decl {
-                Declaration::Type(type_decl) => {
-                    self.add_type_declaration(type_decl.clone());
-                    result.push((Declaration::Type(type_decl), Type::int()));
-                }
-                Declaration::Value(ValueDeclaration { name, expr }) => {
-                    debug!(
-                        "Inferring declaration {}: \n=============================",
-                        name
-                    );
-                    let typed_expr = self.infer(expr)?;
-                    // TODO generalize
-                    self.state
-                        .context
-                        .insert(name.clone(), typed_expr.ty.clone());
-                    result.push((
-                        Declaration::Value(ValueDeclaration {
-                            name: name,
-                            expr: typed_expr.expr,
-                        }),
-                        typed_expr.ty,
-                    ));
-                }
+            result.extend(self.infer_decl(decl)?)
+        }
+        Ok(result)
+    }
+
+    /// Checks a declaration and adds what it declares to the context. Type
+    /// declarations come back with a dummy type, `Declaration::Error`s are
+    /// skipped.
+    pub fn infer_decl<B: HasIdent>(
+        &mut self,
+        decl: Declaration<B>,
+    ) -> Result<Option<(Declaration<NewVar>, Type)>, TypeError> {
+        match decl {
+            Declaration::Type(type_decl) => {
+                self.add_type_declaration(type_decl.clone());
+                Ok(Some((Declaration::Type(type_decl), Type::int())))
+            }
+            Declaration::Error(_) => Ok(None),
+            Declaration::Value(ValueDeclaration {
+                name,
+                expr,
+                span,
+                doc,
+            }) => {
+                debug!(
+                    "Inferring declaration {}: \n=============================",
+                    name
+                );
+                let (expr, ty) = self.infer_expr(expr)?;
+                self.state.context.insert(name.clone(), ty.clone());
+                Ok(Some((
+                    Declaration::Value(ValueDeclaration {
+                        name,
+                        expr,
+                        span,
+                        doc,
+                    }),
+                    ty,
+                )))
             }
         }
+    }
 
-        Ok(result)
+    /// Infers the generalized type of an expression, in the context of the
+    /// declarations checked so far
+    pub fn infer_expr<B: HasIdent>(
+        &mut self,
+        expr: Expr<B>,
+    ) -> Result<(NewTypedExpr, Type), TypeError> {
+        let typed_expr = self.infer(expr)?;
+        let ty = self.generalize(typed_expr.ty);
+        Ok((self.zonk_expr(typed_expr.expr), ty))
     }
 }
@@ -1,8 +1,15 @@
 extern crate wabt;
 extern crate wasmi;
+use crate::pretty::render_doc;
+use pretty::{BoxDoc, Doc};
 use std::fs;
 use std::path::Path;
-use wasmi::{ImportsBuilder, ModuleInstance, NopExternals};
+use wasmi::{ImportsBuilder, ModuleInstance, NopExternals, StackRecycler};
+
+/// Recursive programs nest calls deeply, as every application goes through
+/// `$apply`. These are 16 times wasmi's default limits.
+const VALUE_STACK_LIMIT: usize = 16 * 1024 * 1024;
+const CALL_STACK_LIMIT: usize = 1024 * 1024;
 
 pub fn run_wasm(prog: String) -> Result<Option<wasmi::RuntimeValue>, wasmi::Error> {
     // Parse WAT (WebAssembly Text format) into wasm bytecode.
@@ -16,34 +23,223 @@ pub fn run_wasm(prog: String) -> Result<Option<wasmi::RuntimeValue>, wasmi::Erro
     let instance = ModuleInstance::new(&module, &ImportsBuilder::default())?;
 
     // Assert that there is no `start` function.
-    instance
-        .assert_no_start()
-        .invoke_export("main", &[], &mut NopExternals)
+    instance.assert_no_start().invoke_export_with_stack(
+        "main",
+        &[],
+        &mut NopExternals,
+        &mut StackRecycler::with_limits(VALUE_STACK_LIMIT, CALL_STACK_LIMIT),
+    )
 }
 
-pub fn output_wasm(prog: String, path: &Path) {
+pub fn output_wasm(prog: String, path: &Path) -> Result<(), String> {
     let wasm_binary = wabt::Wat2Wasm::new()
         .canonicalize_lebs(false)
         .write_debug_names(true)
         .convert(prog)
-        .unwrap();
-    fs::write(path, wasm_binary.as_ref()).unwrap()
+        .map_err(|err| format!("{}", err))?;
+    fs::write(path, wasm_binary.as_ref())
+        .map_err(|err| format!("Failed to write {}: {}", path.display(), err))
 }
 
+/// Indents WAT, like the code generator emits it, for humans to read
 pub fn pretty_wat(input: &str) -> String {
-    use std::process::{Command, Stdio};
-    fs::write("tmp.wat", input.as_bytes()).unwrap();
-    let child = Command::new("wat-desugar.exe")
-        .arg("tmp.wat")
-        .stdout(Stdio::piped())
-        .spawn()
-        .expect("Failed to start wat desugar process");
+    let sexps = parse_sexps(input);
+    let rendered = render_doc(Doc::intersperse(
+        sexps.iter().map(|sexp| sexp.to_doc()),
+        Doc::newline(),
+    ));
+    // Empty lines still get indented
+    rendered
+        .lines()
+        .map(str::trim_end)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// WAT is made up of s-expressions, which is all we need to know about it
+/// to lay it out
+#[derive(Debug, PartialEq, Eq)]
+enum Sexp<'a> {
+    Atom(&'a str),
+    /// A `;;` or `(; ;)` comment
+    Comment(&'a str),
+    List(Vec<Sexp<'a>>),
+}
 
-    let output = child.wait_with_output().unwrap();
+/// Parses `input` into s-expressions. Unbalanced parentheses are tolerated,
+/// so we can still look at broken output.
+fn parse_sexps(input: &str) -> Vec<Sexp<'_>> {
+    let mut stack: Vec<Vec<Sexp>> = vec![vec![]];
+    let mut rest = input;
+    loop {
+        rest = rest.trim_start();
+        let len = if rest.is_empty() {
+            break;
+        } else if rest.starts_with(";;") {
+            rest.find('\n').unwrap_or(rest.len())
+        } else if rest.starts_with("(;") {
+            rest.find(";)").map_or(rest.len(), |end| end + 2)
+        } else if rest.starts_with('(') {
+            stack.push(vec![]);
+            1
+        } else if rest.starts_with(')') {
+            if stack.len() > 1 {
+                let list = stack.pop().unwrap();
+                stack.last_mut().unwrap().push(Sexp::List(list));
+            }
+            1
+        } else if rest.starts_with('"') {
+            string_len(rest)
+        } else {
+            rest.find(|c: char| c.is_whitespace() || c == '(' || c == ')' || c == '"')
+                .unwrap_or(rest.len())
+        };
+        let (token, tail) = rest.split_at(len);
+        if token.starts_with(";;") || token.starts_with("(;") {
+            stack
+                .last_mut()
+                .unwrap()
+                .push(Sexp::Comment(token.trim_end()))
+        } else if token != "(" && token != ")" {
+            stack.last_mut().unwrap().push(Sexp::Atom(token))
+        }
+        rest = tail;
+    }
+    while stack.len() > 1 {
+        let list = stack.pop().unwrap();
+        stack.last_mut().unwrap().push(Sexp::List(list));
+    }
+    stack.pop().unwrap()
+}
 
-    fs::remove_file("tmp.wat").unwrap();
+/// The length of the string literal at the start of `input`, including its
+/// quotes
+fn string_len(input: &str) -> usize {
+    let mut escaped = false;
+    for (ix, c) in input.char_indices().skip(1) {
+        match c {
+            '\\' => escaped = !escaped,
+            '"' if !escaped => return ix + 1,
+            _ => escaped = false,
+        }
+    }
+    input.len()
+}
+
+impl<'a> Sexp<'a> {
+    /// Whether this is an immediate argument, that belongs on the same line
+    /// as the instruction before it, like the `$f` in `call $f`
+    fn is_immediate(&self) -> bool {
+        match self {
+            Sexp::Atom(atom) => {
+                atom.starts_with(|c: char| c == '$' || c == '"' || c == '-' || c.is_ascii_digit())
+                    || atom.starts_with("offset=")
+                    || atom.starts_with("align=")
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether this is part of a function's or block's signature, which
+    /// belongs on the line that starts it
+    fn is_signature(&self) -> bool {
+        match self {
+            Sexp::List(items) => match items.first() {
+                Some(Sexp::Atom(head)) => {
+                    ["param", "result", "type", "export", "import"].contains(head)
+                }
+                _ => false,
+            },
+            _ => false,
+        }
+    }
 
-    format!("{}", std::str::from_utf8(&output.stdout).unwrap())
+    fn is_func(&self) -> bool {
+        match self {
+            Sexp::List(items) => items.first() == Some(&Sexp::Atom("func")),
+            _ => false,
+        }
+    }
+
+    fn has_comments(&self) -> bool {
+        match self {
+            Sexp::Atom(_) => false,
+            Sexp::Comment(_) => true,
+            Sexp::List(items) => items.iter().any(Sexp::has_comments),
+        }
+    }
+
+    fn to_doc(&self) -> Doc<BoxDoc<()>> {
+        match self {
+            Sexp::Atom(atom) | Sexp::Comment(atom) => Doc::text(*atom),
+            Sexp::List(items) => list_to_doc(items),
+        }
+    }
+}
+
+/// Splits the elements of a list into the pieces that go on a line of
+/// their own, instructions stay together with their immediates. The first
+/// line also gets the signature.
+fn lines<'s, 'a>(items: &'s [Sexp<'a>]) -> Vec<&'s [Sexp<'a>]> {
+    let mut lines = vec![];
+    let mut start = 0;
+    for ix in 1..=items.len() {
+        let continues = match items.get(ix) {
+            None => false,
+            Some(item) => match items[ix - 1] {
+                Sexp::Comment(_) => false,
+                Sexp::Atom(_) => item.is_immediate() || (start == 0 && item.is_signature()),
+                Sexp::List(_) => start == 0 && item.is_signature(),
+            },
+        };
+        if !continues {
+            lines.push(&items[start..ix]);
+            start = ix;
+        }
+    }
+    lines
+}
+
+fn list_to_doc<'a>(items: &'a [Sexp<'a>]) -> Doc<'a, BoxDoc<'a, ()>> {
+    let line_doc = |line: &'a [Sexp<'a>]| {
+        Doc::intersperse(line.iter().map(Sexp::to_doc), Doc::text(" ")).group()
+    };
+    let lines = lines(items);
+    let (head, body) = match lines.split_first() {
+        None => return Doc::text("()"),
+        Some(split) => split,
+    };
+    // Comments run until the end of the line, so lists containing them
+    // can't be laid out on a single line
+    let is_module = items.first() == Some(&Sexp::Atom("module"));
+    let break_lines = is_module || items.iter().any(Sexp::has_comments);
+    let mut doc = Doc::text("(").append(line_doc(head));
+    let mut previous = *head;
+    for line in body.iter() {
+        let separator = if break_lines {
+            // Leave some room between the functions in a module, and the
+            // comments describing them
+            let spaced = match (&previous[0], &line[0]) {
+                (Sexp::Comment(_), _) => false,
+                (_, next) if next.is_func() => true,
+                (prev, Sexp::Comment(_)) => prev.is_func(),
+                _ => false,
+            };
+            if is_module && spaced {
+                Doc::newline().append(Doc::newline())
+            } else {
+                Doc::newline()
+            }
+        } else {
+            Doc::space()
+        };
+        doc = doc.append(separator.append(line_doc(line)).nest(2));
+        previous = line;
+    }
+    if let Some(Sexp::Comment(_)) = items.last() {
+        doc = doc.append(Doc::newline())
+    }
+    doc.append(Doc::text(")")).group()
 }
 
 pub fn pretty_result(res: Result<Option<wasmi::RuntimeValue>, wasmi::Error>) -> String {
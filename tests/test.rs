@@ -1,5 +1,9 @@
 extern crate siml;
-use siml::pipeline::{run_program, Backend};
+use siml::expr::Declaration;
+use siml::formatter::format_program;
+use siml::pipeline::{compile, dump, parse, run_program, typecheck, Backend, Phase};
+use siml::span::FileId;
+use siml::wasm::{pretty_wat, run_wasm};
 use std::fs;
 use std::path::PathBuf;
 
@@ -23,8 +27,15 @@ fn test_passing() {
         let path = entry.unwrap().path();
         if path.is_file() {
             let backend = backend_from_path(&path);
-            let res = run_program(&fs::read_to_string(path.clone()).unwrap(), backend).unwrap();
+            let is_term = backend == Backend::Term;
+            let source = fs::read_to_string(path.clone()).unwrap();
+            let res = run_program(&source, backend).unwrap();
             println!("Running: {} => {}", path.display(), res);
+            // The term interpreter has to be able to run the wasm tests as well
+            if !is_term {
+                let res = run_program(&source, Backend::Term).unwrap();
+                println!("Running: {} (term) => {}", path.display(), res);
+            }
         }
     }
 }
@@ -42,3 +53,462 @@ fn test_failing() {
         }
     }
 }
+
+#[test]
+fn test_error_span() {
+    let err = run_program("let main : Int =\n  true;", Backend::Term).unwrap_err();
+    let span = err
+        .span()
+        .expect("Type errors should point into the source");
+    assert_eq!((span.start.line, span.start.column), (1, 2));
+    assert_eq!((span.end.line, span.end.column), (1, 6));
+}
+
+#[test]
+fn test_lex_error_span() {
+    let err = run_program("let main : Int =\n  1 $ 2;", Backend::Term).unwrap_err();
+    let span = err.span().expect("Lex errors should point into the source");
+    assert_eq!((span.start.line, span.start.column), (1, 4));
+    assert_eq!((span.end.line, span.end.column), (1, 5));
+}
+
+#[test]
+fn test_doc_comments() {
+    let source = "--| The answer\n--| to everything\nlet main : Int = 42;";
+    let (prog, errors) = parse(FileId::default(), source);
+    assert!(errors.is_empty());
+    match &prog[0] {
+        Declaration::Value(vd) => {
+            assert_eq!(vd.doc, Some("The answer\nto everything".to_string()))
+        }
+        _ => panic!("Expected a value declaration"),
+    }
+
+    // Anywhere else they're just comments
+    let source = "let main : Int =\n  --| the answer\n  match 42 { x => x --| trailing\n } --| before the end\n;\n--| at the end";
+    let (prog, errors) = parse(FileId::default(), source);
+    assert!(errors.is_empty());
+    match &prog[..] {
+        [Declaration::Value(vd)] => assert_eq!(vd.doc, None),
+        _ => panic!("Expected a single value declaration"),
+    }
+}
+
+#[test]
+fn test_parse_error_recovery() {
+    let source = "let x : Int = ;\ntype T { A( }\nlet y : Int = 1;\nlet z : Int = in;";
+    let (prog, errors) = parse(FileId::default(), source);
+    let lines: Vec<u32> = errors
+        .iter()
+        .map(|err| err.span().unwrap().start.line)
+        .collect();
+    assert_eq!(lines, vec![0, 1, 3]);
+    assert_eq!(prog.len(), 4);
+    match &prog[2] {
+        Declaration::Value(vd) => assert_eq!(vd.name, "y"),
+        _ => panic!("Expected y to parse"),
+    }
+
+    // A `}` closing a match doesn't end the declaration, and lex errors
+    // don't stop the parser either
+    let source = "let x : Int = match 1 { _ => } + 1;\nlet y : Int = 2 $ 3;\nlet z : Int = 1;\nlet w : Int = in;";
+    let (prog, errors) = parse(FileId::default(), source);
+    let lines: Vec<u32> = errors
+        .iter()
+        .map(|err| err.span().unwrap().start.line)
+        .collect();
+    assert_eq!(lines, vec![0, 1, 3]);
+    assert_eq!(prog.len(), 4);
+}
+
+#[test]
+fn test_match_coverage() {
+    let err = run_program(
+        "type T { A(), B(), C() }\nlet main : Int = match T::A() { T::A() => 1 };",
+        Backend::Term,
+    )
+    .unwrap_err();
+    assert_eq!(
+        format!("{}", err),
+        "Non-exhaustive match, missing cases for: T::B(), T::C()"
+    );
+
+    let err = run_program(
+        "type L { Cons(Int, L), Nil() }\nlet main : Int = match L::Nil() { L::Cons(1, _) => 1, L::Nil() => 0 };",
+        Backend::Term,
+    )
+    .unwrap_err();
+    assert_eq!(
+        format!("{}", err),
+        "Non-exhaustive match, missing cases for: L::Cons(_, _)"
+    );
+
+    let err = run_program(
+        "let main : Int = match (true, 1) { (true, n) => n };",
+        Backend::Term,
+    )
+    .unwrap_err();
+    assert_eq!(
+        format!("{}", err),
+        "Non-exhaustive match, missing cases for: (false, _)"
+    );
+
+    let (_, warnings) = typecheck(
+        FileId::default(),
+        "type T { A(), B() }\nlet main : Int = match T::A() { T::A() => 1, T::B() => 2, T::A() => 3 };",
+    )
+    .unwrap();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0].span().start.column, 58);
+}
+
+#[test]
+fn test_dump_phases() {
+    let source = "let id : forall a. a -> a = \\x. x;\nlet main : Int = id (1 + 2);";
+    let dump = |phase| dump(FileId::default(), source, phase).unwrap();
+    assert_eq!(
+        dump(Phase::Ast),
+        "let id = ((\\x. x) : ∀ a. a → a);\n\nlet main = (id (prim:add 1 2) : Int);"
+    );
+    assert!(dump(Phase::Typed).contains("main : Int\nlet main = id (prim:add 1 2);"));
+    assert_eq!(
+        dump(Phase::Ir),
+        "id(x) =\n  x\n\nmain() =\n  id(prim:add(1, 2))\n\nentry main"
+    );
+    assert!(dump(Phase::Wat).starts_with("(module"));
+}
+
+// The examples in prog.siml, tuples included, compile to wasm and agree
+// with the term interpreter
+#[test]
+fn test_prog_example() {
+    let source = fs::read_to_string("prog.siml").unwrap();
+    assert_eq!(run_program(&source, Backend::WasmRun).unwrap(), "21");
+    assert_eq!(run_program(&source, Backend::Term).unwrap(), "21");
+}
+
+// Dumps are only useful for diffing if compiling twice gives the same module
+#[test]
+fn test_compile_deterministic() {
+    let source = fs::read_to_string("tests/passing/closures_wasm.siml").unwrap();
+    let wat = compile(FileId::default(), &source).unwrap();
+    for _ in 0..3 {
+        assert_eq!(compile(FileId::default(), &source).unwrap(), wat);
+    }
+}
+
+#[test]
+fn test_pretty_wat() {
+    let wat = "(module (func $f (param $x i32) (result i32)\n;; Doubles x\n(local.get $x) i32.const 2 i32.mul) (export \"f\" (func $f)))";
+    assert_eq!(
+        pretty_wat(wat),
+        "(module
+
+  (func $f (param $x i32) (result i32)
+    ;; Doubles x
+    (local.get $x)
+    i32.const 2
+    i32.mul)
+  (export \"f\" (func $f)))"
+    );
+}
+
+#[test]
+fn test_pretty_wat_round_trip() {
+    for entry in fs::read_dir("tests/passing").expect("Failed to read passing dir") {
+        let path = entry.unwrap().path();
+        if path.is_file() && backend_from_path(&path) == Backend::WasmRun {
+            let source = fs::read_to_string(&path).unwrap();
+            let wat = compile(FileId::default(), &source).unwrap();
+            assert_eq!(
+                run_wasm(pretty_wat(&wat)).unwrap(),
+                run_wasm(wat).unwrap(),
+                "{}",
+                path.display()
+            );
+        }
+    }
+}
+
+// Allocates far more than the initial 64KiB heap, so this only works if the
+// wasm runtime collects garbage and grows its memory. The term interpreter
+// is too slow for lists this long.
+#[test]
+fn test_garbage_collection() {
+    let source = r"
+type List<a> { Nil(), Cons(a, List<a>) }
+
+let range : Int -> List<Int> =
+  letrec go = \n. if n == 0 then List::Nil() else List::Cons(n, go (n - 1)) in
+  go;
+
+let foldl : forall a b. (b -> a -> b) -> b -> List<a> -> b = \f.
+  letrec go = \acc. \xs. match xs {
+    List::Nil() => acc,
+    List::Cons(x, rest) => go (f acc x) rest,
+  } in
+  go;
+
+let map : forall a b. (a -> b) -> List<a> -> List<b> = \f.
+  letrec go = \xs. match xs {
+    List::Nil() => List::Nil(),
+    List::Cons(x, rest) => List::Cons(f x, go rest),
+  } in
+  go;
+
+let main : Int =
+  let xs = range 10000 in
+  let sum = foldl (\acc. \x. acc + x) 0 in
+  sum (map (\x. x % 7) xs) + sum (map (\x. x * 2) xs) - sum xs;
+";
+    let res = run_program(source, Backend::WasmRun).unwrap();
+    assert_eq!(res, "50034998");
+}
+
+// `fst` returns a closure that gets applied to an argument which allocates
+// enough to collect garbage several times, so the closure has to survive
+// being moved while its argument is evaluated.
+#[test]
+fn test_garbage_collection_in_primitive_application() {
+    let source = r"
+type List<a> { Nil(), Cons(a, List<a>) }
+
+let range : Int -> List<Int> =
+  letrec go = \n. if n == 0 then List::Nil() else List::Cons(n, go (n - 1)) in
+  go;
+
+let length : forall a. List<a> -> Int =
+  letrec go = \xs. match xs {
+    List::Nil() => 0,
+    List::Cons(x, rest) => 1 + go rest,
+  } in
+  go;
+
+let main : Int =
+  let k = 3 in
+  fst (\x. x + k, true) (length (range 20000) + length (range 20000));
+";
+    let res = run_program(source, Backend::WasmRun).unwrap();
+    assert_eq!(res, "40003");
+}
+
+fn siml(args: &[&str]) -> std::process::Output {
+    std::process::Command::new(env!("CARGO_BIN_EXE_siml"))
+        .args(args)
+        .output()
+        .expect("Failed to start siml")
+}
+
+#[test]
+fn test_cli_run() {
+    let out = siml(&["run", "tests/passing/if_wasm.siml", "--backend", "wasm"]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "163\n");
+
+    // Both backends print values and warnings the same way
+    let path = std::env::temp_dir().join(format!("siml_run_{}.siml", std::process::id()));
+    let path_str = path.to_str().unwrap();
+    fs::write(
+        &path,
+        "let main : Bool = match 1 {\n  _ => true,\n  1 => false,\n};",
+    )
+    .unwrap();
+    for backend in &["term", "wasm"] {
+        let out = siml(&["run", path_str, "--backend", backend]);
+        assert!(out.status.success());
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "true\n");
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(stderr.starts_with("warning: Unreachable case"));
+        assert!(stderr.contains(&format!("--> {}:3:3", path_str)));
+    }
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_cli_check_failure() {
+    let out = siml(&["check", "tests/failing/if_non_bool_condition.siml"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(out.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.starts_with("error: "));
+    assert!(stderr.contains("--> tests/failing/if_non_bool_condition.siml:"));
+}
+
+#[test]
+fn test_cli_json_errors() {
+    let out = siml(&[
+        "check",
+        "tests/failing/if_non_bool_condition.siml",
+        "--error-format",
+        "json",
+    ]);
+    assert_eq!(out.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    let diagnostics: Vec<serde_json::Value> = stderr
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0]["severity"], "error");
+    assert_eq!(
+        diagnostics[0]["labels"][0]["file"],
+        "tests/failing/if_non_bool_condition.siml"
+    );
+    assert_eq!(diagnostics[0]["labels"][0]["primary"], true);
+}
+
+#[test]
+fn test_cli_dump_to_files() {
+    let dir = std::env::temp_dir().join(format!("siml_cli_dump_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let source = dir.join("prog.siml");
+    fs::copy("tests/passing/minimal_wasm.siml", &source).unwrap();
+    let out = siml(&[
+        "check",
+        source.to_str().unwrap(),
+        "--dump-ir",
+        "--dump-wat",
+        "--dump-to-files",
+    ]);
+    assert!(out.status.success());
+    assert!(out.stdout.is_empty());
+    assert!(fs::read_to_string(dir.join("prog.ir"))
+        .unwrap()
+        .contains("entry main"));
+    assert!(fs::read_to_string(dir.join("prog.wat"))
+        .unwrap()
+        .starts_with("(module"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_cli_usage_error() {
+    assert_eq!(siml(&["run"]).status.code(), Some(2));
+    assert_eq!(siml(&["frobnicate", "prog.siml"]).status.code(), Some(2));
+    assert_eq!(
+        siml(&["run", "prog.siml", "-b", "jvm"]).status.code(),
+        Some(2)
+    );
+}
+
+#[test]
+fn test_cli_lsp() {
+    use siml::lsp::{read_message, write_message};
+    use std::io::{BufReader, Write};
+    use std::process::{Command, Stdio};
+
+    let uri = "file:///prog.siml";
+    let messages = [
+        serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} }),
+        serde_json::json!({ "jsonrpc": "2.0", "method": "initialized", "params": {} }),
+        serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": uri, "languageId": "siml", "version": 1, "text": "let main : Int = true;" } },
+        }),
+        serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 2,
+            "method": "textDocument/hover",
+            "params": { "textDocument": { "uri": uri }, "position": { "line": 0, "character": 5 } },
+        }),
+        serde_json::json!({ "jsonrpc": "2.0", "id": 3, "method": "shutdown" }),
+        serde_json::json!({ "jsonrpc": "2.0", "method": "exit" }),
+    ];
+    let mut input = vec![];
+    for message in messages.iter() {
+        write_message(&mut input, message).unwrap();
+    }
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_siml"))
+        .arg("lsp")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to start siml");
+    child.stdin.take().unwrap().write_all(&input).unwrap();
+    let out = child.wait_with_output().unwrap();
+    assert!(out.status.success());
+
+    let mut output = BufReader::new(out.stdout.as_slice());
+    let mut responses = vec![];
+    while let Some(message) = read_message(&mut output).unwrap() {
+        responses.push(message)
+    }
+    assert_eq!(responses.len(), 4);
+    assert_eq!(
+        responses[0]["result"]["capabilities"]["hoverProvider"],
+        true
+    );
+    assert_eq!(responses[1]["method"], "textDocument/publishDiagnostics");
+    assert_eq!(
+        responses[1]["params"]["diagnostics"][0]["message"],
+        "Failed to unify Bool with Int"
+    );
+    // `main` didn't check, so there's nothing to show
+    assert_eq!(responses[2]["id"], 2);
+    assert_eq!(responses[2]["result"], serde_json::Value::Null);
+    assert_eq!(responses[3]["id"], 3);
+}
+
+/// The program's AST, without any spans. Formatting moves code around, but
+/// should leave everything else alone.
+fn ast_without_spans(input: &str) -> String {
+    let prog = parse(FileId::default(), input);
+    assert!(prog.1.is_empty(), "{:?}", prog.1);
+    let spans = regex::Regex::new(
+        r"Span \{ file: FileId\(\d+\), start: Position \{[^}]*\}, end: Position \{[^}]*\} \}",
+    )
+    .unwrap();
+    spans
+        .replace_all(&format!("{:?}", prog.0), "Span")
+        .to_string()
+}
+
+#[test]
+fn test_format_round_trip() {
+    for entry in fs::read_dir("tests/passing").unwrap() {
+        let path = entry.unwrap().path();
+        let input = fs::read_to_string(&path).unwrap();
+        // Narrow widths make sure the broken layouts parse too
+        for width in [10, 40, 80, 120] {
+            let formatted = format_program(FileId::default(), &input, width).unwrap();
+            assert_eq!(
+                ast_without_spans(&formatted),
+                ast_without_spans(&input),
+                "Formatting {} at width {} changed the program:\n{}",
+                path.display(),
+                width,
+                formatted
+            );
+            let again = format_program(FileId::default(), &formatted, width).unwrap();
+            assert_eq!(
+                again,
+                formatted,
+                "Formatting {} isn't stable",
+                path.display()
+            );
+            assert_eq!(
+                siml::token::comments(&formatted).len(),
+                siml::token::comments(&input).len(),
+                "Formatting {} lost comments",
+                path.display()
+            );
+        }
+    }
+}
+
+#[test]
+fn test_cli_fmt() {
+    let path = std::env::temp_dir().join(format!("siml_fmt_{}.siml", std::process::id()));
+    let path_str = path.to_str().unwrap();
+    fs::write(&path, "let main:Int=add 1 2;").unwrap();
+    assert_eq!(siml(&["fmt", path_str, "--check"]).status.code(), Some(1));
+    assert!(siml(&["fmt", path_str]).status.success());
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        "let main : Int = add 1 2;\n"
+    );
+    assert!(siml(&["fmt", path_str, "--check"]).status.success());
+    fs::remove_file(&path).unwrap();
+}